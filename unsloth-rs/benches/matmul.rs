@@ -0,0 +1,68 @@
+// Compares `Tensor::matmul` (on the default backend: tiled and, with the `rayon`
+// feature, multithreaded) with a plain single-threaded ndarray `dot`, which is
+// what it used to call.
+//
+//     cargo bench --bench matmul
+//     RAYON_NUM_THREADS=1 cargo bench --bench matmul   # single-threaded baseline
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use ndarray::{Array, Array2, Array3, Axis, IxDyn};
+use std::hint::black_box;
+use unsloth_rs::core::Tensor;
+
+fn values(n: usize) -> Vec<f32> {
+    (0..n).map(|i| (i * 7919 % 1000) as f32 / 500.0 - 1.0).collect()
+}
+
+fn tensor(shape: &[usize]) -> Tensor {
+    let n = shape.iter().product();
+    Tensor::new(Array::from_shape_vec(IxDyn(shape), values(n)).unwrap())
+}
+
+fn matmul_2d(c: &mut Criterion) {
+    let mut group = c.benchmark_group("matmul_2d");
+    group.sample_size(10);
+    // [tokens, hidden] x [hidden, out], as in the linear layers.
+    for &(m, k, n) in &[(16, 1024, 4096), (256, 1024, 1024), (512, 2048, 2048)] {
+        let (a, b) = (tensor(&[m, k]), tensor(&[k, n]));
+        let id = format!("{m}x{k}x{n}");
+        group.bench_with_input(BenchmarkId::new("tensor", &id), &(), |bench, _| {
+            bench.iter(|| black_box(a.matmul(&b)))
+        });
+
+        let a = Array2::from_shape_vec((m, k), values(m * k)).unwrap();
+        let b = Array2::from_shape_vec((k, n), values(k * n)).unwrap();
+        group.bench_with_input(BenchmarkId::new("ndarray_dot", &id), &(), |bench, _| {
+            bench.iter(|| black_box(a.dot(&b)))
+        });
+    }
+    group.finish();
+}
+
+fn attention_scores(c: &mut Criterion) {
+    let mut group = c.benchmark_group("attention_scores");
+    group.sample_size(10);
+    // Q K^T per head: [heads, seq, head_dim] x [heads, head_dim, seq].
+    for &(heads, seq, head_dim) in &[(8, 128, 64), (32, 512, 128)] {
+        let (q, k_t) = (tensor(&[heads, seq, head_dim]), tensor(&[heads, head_dim, seq]));
+        let id = format!("{heads}x{seq}x{head_dim}");
+        group.bench_with_input(BenchmarkId::new("tensor", &id), &(), |bench, _| {
+            bench.iter(|| black_box(q.matmul(&k_t)))
+        });
+
+        let q = Array3::from_shape_vec((heads, seq, head_dim), values(heads * seq * head_dim)).unwrap();
+        let k_t = Array3::from_shape_vec((heads, head_dim, seq), values(heads * seq * head_dim)).unwrap();
+        group.bench_with_input(BenchmarkId::new("ndarray_dot", &id), &(), |bench, _| {
+            bench.iter(|| {
+                let mut out = Array3::<f32>::zeros((heads, seq, seq));
+                for (h, mut out) in out.axis_iter_mut(Axis(0)).enumerate() {
+                    out.assign(&q.index_axis(Axis(0), h).dot(&k_t.index_axis(Axis(0), h)));
+                }
+                black_box(out)
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, matmul_2d, attention_scores);
+criterion_main!(benches);
@@ -0,0 +1,158 @@
+use ndarray::{ArrayD, Axis};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+// Maps the gradient of an op's output to the gradients of its inputs, in the
+// same order as the parents recorded on the node. `None` means "no gradient
+// flows to this input".
+pub(crate) type BackwardFn = Box<dyn Fn(&ArrayD<f32>) -> Vec<Option<ArrayD<f32>>>>;
+
+pub(crate) struct Node {
+    parents: Vec<Option<Rc<Node>>>,
+    backward: Option<BackwardFn>,
+    grad: RefCell<Option<ArrayD<f32>>>,
+}
+
+impl Node {
+    pub(crate) fn leaf() -> Rc<Node> {
+        Rc::new(Node {
+            parents: Vec::new(),
+            backward: None,
+            grad: RefCell::new(None),
+        })
+    }
+
+    pub(crate) fn op(parents: Vec<Option<Rc<Node>>>, backward: BackwardFn) -> Rc<Node> {
+        Rc::new(Node {
+            parents,
+            backward: Some(backward),
+            grad: RefCell::new(None),
+        })
+    }
+
+    pub(crate) fn is_leaf(&self) -> bool {
+        self.backward.is_none()
+    }
+
+    pub(crate) fn grad(&self) -> Option<ArrayD<f32>> {
+        self.grad.borrow().clone()
+    }
+
+    pub(crate) fn zero_grad(&self) {
+        *self.grad.borrow_mut() = None;
+    }
+
+    fn accumulate(&self, grad: ArrayD<f32>) {
+        let mut slot = self.grad.borrow_mut();
+        match slot.as_mut() {
+            Some(existing) => *existing += &grad,
+            None => *slot = Some(grad),
+        }
+    }
+}
+
+thread_local! {
+    static GRAD_ENABLED: Cell<bool> = const { Cell::new(true) };
+}
+
+pub fn is_grad_enabled() -> bool {
+    GRAD_ENABLED.with(|enabled| enabled.get())
+}
+
+/// Runs `f` without recording any ops on the gradient tape.
+pub fn no_grad<R>(f: impl FnOnce() -> R) -> R {
+    with_grad_enabled(false, f)
+}
+
+/// Runs `f` with ops recorded again, e.g. to recompute a checkpointed
+/// segment from inside a backward pass.
+pub fn enable_grad<R>(f: impl FnOnce() -> R) -> R {
+    with_grad_enabled(true, f)
+}
+
+fn with_grad_enabled<R>(enabled: bool, f: impl FnOnce() -> R) -> R {
+    struct Restore(bool);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            GRAD_ENABLED.with(|enabled| enabled.set(self.0));
+        }
+    }
+
+    let _restore = Restore(GRAD_ENABLED.with(|slot| slot.replace(enabled)));
+    f()
+}
+
+// Nodes ordered so that every node comes after all of its parents.
+fn topo_order(root: &Rc<Node>) -> Vec<Rc<Node>> {
+    let mut order = Vec::new();
+    let mut visited = HashSet::new();
+    let mut stack = vec![(root.clone(), false)];
+
+    while let Some((node, expanded)) = stack.pop() {
+        if expanded {
+            order.push(node);
+            continue;
+        }
+        if !visited.insert(Rc::as_ptr(&node)) {
+            continue;
+        }
+        stack.push((node.clone(), true));
+        for parent in node.parents.iter().flatten() {
+            if !visited.contains(&Rc::as_ptr(parent)) {
+                stack.push((parent.clone(), false));
+            }
+        }
+    }
+
+    order
+}
+
+pub(crate) fn run_backward(root: &Rc<Node>, seed: ArrayD<f32>) {
+    let mut pending: HashMap<*const Node, ArrayD<f32>> = HashMap::new();
+    pending.insert(Rc::as_ptr(root), seed);
+
+    // Backward closures may call ops themselves; never record those.
+    no_grad(|| {
+        for node in topo_order(root).iter().rev() {
+            let grad = match pending.remove(&Rc::as_ptr(node)) {
+                Some(grad) => grad,
+                None => continue,
+            };
+
+            let backward = match &node.backward {
+                Some(backward) => backward,
+                None => {
+                    node.accumulate(grad);
+                    continue;
+                }
+            };
+
+            let parent_grads = backward(&grad);
+            for (parent, parent_grad) in node.parents.iter().zip(parent_grads) {
+                if let (Some(parent), Some(parent_grad)) = (parent, parent_grad) {
+                    match pending.get_mut(&Rc::as_ptr(parent)) {
+                        Some(existing) => *existing += &parent_grad,
+                        None => {
+                            pending.insert(Rc::as_ptr(parent), parent_grad);
+                        }
+                    }
+                }
+            }
+        }
+    });
+}
+
+// Sums a broadcast gradient back down to the shape of the input it came from.
+pub(crate) fn reduce_to_shape(grad: ArrayD<f32>, shape: &[usize]) -> ArrayD<f32> {
+    let mut grad = grad;
+    while grad.ndim() > shape.len() {
+        grad = grad.sum_axis(Axis(0));
+    }
+    for (axis, &dim) in shape.iter().enumerate() {
+        if dim == 1 && grad.shape()[axis] != 1 {
+            grad = grad.sum_axis(Axis(axis)).insert_axis(Axis(axis));
+        }
+    }
+    grad
+}
@@ -0,0 +1,173 @@
+// The reference backend: single-threaded ndarray code. The per-lane and
+// per-token kernels are shared with `ParallelCpuBackend`.
+use super::Backend;
+use crate::core::rope::RopeLayout;
+use ndarray::linalg::general_mat_mul;
+use ndarray::{
+    Array2, ArrayD, ArrayView1, ArrayView2, ArrayViewD, ArrayViewMut1, ArrayViewMut2, ArrayViewMut3, ArrayViewMutD,
+    Axis, Zip,
+};
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CpuBackend;
+
+impl Backend for CpuBackend {
+    fn name(&self) -> &'static str {
+        "cpu"
+    }
+
+    fn gemm_into(&self, a: &ArrayView2<f32>, b: &ArrayView2<f32>, mut c: ArrayViewMut2<f32>) {
+        general_mat_mul(1.0, a, b, 0.0, &mut c);
+    }
+
+    fn batched_gemm_into(&self, pairs: &[(ArrayView2<f32>, ArrayView2<f32>)], mut out: ArrayViewMut3<f32>) {
+        for ((a, b), c) in pairs.iter().zip(out.outer_iter_mut()) {
+            self.gemm_into(a, b, c);
+        }
+    }
+
+    fn add(&self, a: &ArrayViewD<f32>, b: &ArrayViewD<f32>) -> ArrayD<f32> {
+        a + b
+    }
+
+    fn mul(&self, a: &ArrayViewD<f32>, b: &ArrayViewD<f32>) -> ArrayD<f32> {
+        a * b
+    }
+
+    fn scale(&self, x: &ArrayViewD<f32>, factor: f32) -> ArrayD<f32> {
+        x.mapv(|x| x * factor)
+    }
+
+    fn silu(&self, x: &ArrayViewD<f32>) -> ArrayD<f32> {
+        x.mapv(silu)
+    }
+
+    fn silu_backward(&self, x: &ArrayViewD<f32>, grad: &ArrayViewD<f32>) -> ArrayD<f32> {
+        Zip::from(x).and(grad).map_collect(|&x, &g| g * dsilu(x))
+    }
+
+    fn sum(&self, x: &ArrayViewD<f32>) -> f32 {
+        x.sum()
+    }
+
+    fn rms_normalize(&self, x: &ArrayViewD<f32>, epsilon: f32) -> (ArrayD<f32>, ArrayD<f32>) {
+        let last = Axis(x.ndim() - 1);
+        let mut normalized = x.to_owned();
+        let mut rrms = ArrayD::zeros(rrms_shape(x));
+        Zip::from(normalized.lanes_mut(last))
+            .and(rrms.lanes_mut(last))
+            .for_each(|lane, r| rms_normalize_lane(lane, r, epsilon));
+        (normalized, rrms)
+    }
+
+    fn logsumexp(&self, x: &ArrayViewD<f32>, axis: usize) -> ArrayD<f32> {
+        x.map_axis(Axis(axis), logsumexp_lane)
+    }
+
+    fn rope(&self, x: &mut ArrayD<f32>, cos: &Array2<f32>, sin: &Array2<f32>, layout: RopeLayout) {
+        for (row, token) in x.outer_iter_mut().enumerate() {
+            rotate_token(token, cos.row(row), sin.row(row), layout);
+        }
+    }
+
+    fn softmax(&self, x: &mut ArrayD<f32>, axis: usize) {
+        x.lanes_mut(Axis(axis)).into_iter().for_each(softmax_lane);
+    }
+
+    fn log_softmax(&self, x: &mut ArrayD<f32>, axis: usize) {
+        x.lanes_mut(Axis(axis)).into_iter().for_each(log_softmax_lane);
+    }
+
+    fn softmax_backward(&self, grad: &ArrayD<f32>, y: &ArrayD<f32>, axis: usize) -> ArrayD<f32> {
+        let mut grad_x = grad.clone();
+        Zip::from(grad_x.lanes_mut(Axis(axis)))
+            .and(y.lanes(Axis(axis)))
+            .for_each(softmax_backward_lane);
+        grad_x
+    }
+
+    fn log_softmax_backward(&self, grad: &ArrayD<f32>, y: &ArrayD<f32>, axis: usize) -> ArrayD<f32> {
+        let mut grad_x = grad.clone();
+        Zip::from(grad_x.lanes_mut(Axis(axis)))
+            .and(y.lanes(Axis(axis)))
+            .for_each(log_softmax_backward_lane);
+        grad_x
+    }
+}
+
+pub(super) fn silu(x: f32) -> f32 {
+    x / (1.0 + (-x).exp())
+}
+
+pub(super) fn dsilu(x: f32) -> f32 {
+    let sigmoid = 1.0 / (1.0 + (-x).exp());
+    sigmoid * (1.0 + x * (1.0 - sigmoid))
+}
+
+// `x`'s shape with the last axis reduced to length 1.
+pub(super) fn rrms_shape(x: &ArrayViewD<f32>) -> Vec<usize> {
+    let mut shape = x.shape().to_vec();
+    *shape.last_mut().unwrap() = 1;
+    shape
+}
+
+pub(super) fn rms_normalize_lane(mut lane: ArrayViewMut1<f32>, mut rrms: ArrayViewMut1<f32>, epsilon: f32) {
+    let mean_square = lane.iter().map(|x| x * x).sum::<f32>() / lane.len() as f32;
+    let r = 1.0 / (mean_square + epsilon).sqrt();
+    lane.mapv_inplace(|x| x * r);
+    rrms[0] = r;
+}
+
+pub(super) fn logsumexp_lane(lane: ArrayView1<f32>) -> f32 {
+    let max = lane.fold(f32::NEG_INFINITY, |a, &b| a.max(b));
+    if max == f32::NEG_INFINITY {
+        return max;
+    }
+    max + lane.iter().map(|&x| (x - max).exp()).sum::<f32>().ln()
+}
+
+pub(super) fn softmax_lane(mut lane: ArrayViewMut1<f32>) {
+    let max = lane.fold(f32::NEG_INFINITY, |a, &b| a.max(b));
+    if max == f32::NEG_INFINITY {
+        lane.fill(0.0);
+        return;
+    }
+    lane.mapv_inplace(|x| (x - max).exp());
+    let sum = lane.sum();
+    lane.mapv_inplace(|x| x / sum);
+}
+
+pub(super) fn log_softmax_lane(mut lane: ArrayViewMut1<f32>) {
+    let lse = logsumexp_lane(lane.view());
+    if lse != f32::NEG_INFINITY {
+        lane.mapv_inplace(|x| x - lse);
+    }
+}
+
+pub(super) fn softmax_backward_lane(mut grad: ArrayViewMut1<f32>, y: ArrayView1<f32>) {
+    let dot = grad.iter().zip(&y).map(|(g, y)| g * y).sum::<f32>();
+    grad.zip_mut_with(&y, |g, &y| *g = y * (*g - dot));
+}
+
+pub(super) fn log_softmax_backward_lane(mut grad: ArrayViewMut1<f32>, y: ArrayView1<f32>) {
+    let sum = grad.sum();
+    grad.zip_mut_with(&y, |g, &y| *g -= y.exp() * sum);
+}
+
+// Rotates the pairs of every row of `token` along its last axis.
+pub(super) fn rotate_token(mut token: ArrayViewMutD<f32>, cos: ArrayView1<f32>, sin: ArrayView1<f32>, layout: RopeLayout) {
+    let half = cos.len();
+    let last = Axis(token.ndim() - 1);
+    for mut lane in token.lanes_mut(last) {
+        for i in 0..half {
+            let (a, b) = match layout {
+                RopeLayout::Interleaved => (2 * i, 2 * i + 1),
+                RopeLayout::HalfSplit => (i, i + half),
+            };
+            let (c, s) = (cos[i], sin[i]);
+            let (x1, x2) = (lane[a], lane[b]);
+            lane[a] = x1 * c - x2 * s;
+            lane[b] = x2 * c + x1 * s;
+        }
+    }
+}
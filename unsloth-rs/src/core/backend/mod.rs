@@ -0,0 +1,108 @@
+// Compute backends. Tensors keep their data in host ndarray arrays and the
+// gradient tape stays in `Tensor`; a backend only decides how the arithmetic
+// of each primitive op runs. Every op dispatches to the backend selected for
+// the current thread, so code written against `Tensor` (the models, the LoRA
+// kernels) runs unchanged on any backend.
+mod cpu;
+#[cfg(feature = "rayon")]
+mod parallel;
+
+use super::rope::RopeLayout;
+use ndarray::{Array2, ArrayD, ArrayView2, ArrayViewD, ArrayViewMut2, ArrayViewMut3};
+use std::cell::RefCell;
+use std::fmt;
+use std::sync::{Arc, OnceLock};
+
+pub use cpu::CpuBackend;
+#[cfg(feature = "rayon")]
+pub use parallel::ParallelCpuBackend;
+
+// The primitive f32 kernels behind the `Tensor` ops.
+pub trait Backend: fmt::Debug + Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // Overwrites `c` with `a x b`.
+    fn gemm_into(&self, a: &ArrayView2<f32>, b: &ArrayView2<f32>, c: ArrayViewMut2<f32>);
+
+    // Overwrites every matrix `out[i]` of a stacked [batch, m, n] output with
+    // `pairs[i].0 x pairs[i].1`.
+    fn batched_gemm_into(&self, pairs: &[(ArrayView2<f32>, ArrayView2<f32>)], out: ArrayViewMut3<f32>);
+
+    fn gemm(&self, a: &ArrayView2<f32>, b: &ArrayView2<f32>) -> Array2<f32> {
+        let mut c = Array2::zeros((a.nrows(), b.ncols()));
+        self.gemm_into(a, b, c.view_mut());
+        c
+    }
+
+    // Elementwise ops; binary inputs are already broadcast to the same shape.
+    fn add(&self, a: &ArrayViewD<f32>, b: &ArrayViewD<f32>) -> ArrayD<f32>;
+    fn mul(&self, a: &ArrayViewD<f32>, b: &ArrayViewD<f32>) -> ArrayD<f32>;
+    fn scale(&self, x: &ArrayViewD<f32>, factor: f32) -> ArrayD<f32>;
+    fn silu(&self, x: &ArrayViewD<f32>) -> ArrayD<f32>;
+    // `grad * silu'(x)`.
+    fn silu_backward(&self, x: &ArrayViewD<f32>, grad: &ArrayViewD<f32>) -> ArrayD<f32>;
+
+    // Reductions.
+    fn sum(&self, x: &ArrayViewD<f32>) -> f32;
+    // `(x * r, r)` with r = 1 / sqrt(mean(x^2) + eps) over the last axis, which
+    // is kept in `r` with length 1.
+    fn rms_normalize(&self, x: &ArrayViewD<f32>, epsilon: f32) -> (ArrayD<f32>, ArrayD<f32>);
+    // log(sum(exp(lane))) of every lane along `axis`, which is removed; -inf
+    // for fully `-inf` lanes.
+    fn logsumexp(&self, x: &ArrayViewD<f32>, axis: usize) -> ArrayD<f32>;
+
+    // Rotates the pairs of every token (axis 0) of `x` in place, token `r` by
+    // the angles in row `r` of the [tokens, pairs] `cos` / `sin` tables.
+    fn rope(&self, x: &mut ArrayD<f32>, cos: &Array2<f32>, sin: &Array2<f32>, layout: RopeLayout);
+
+    // In place along `axis`. Fully `-inf` lanes become zeros under `softmax`
+    // and stay `-inf` under `log_softmax`.
+    fn softmax(&self, x: &mut ArrayD<f32>, axis: usize);
+    fn log_softmax(&self, x: &mut ArrayD<f32>, axis: usize);
+    // y * (g - sum(g * y)) along each lane, for a softmax with output `y`.
+    fn softmax_backward(&self, grad: &ArrayD<f32>, y: &ArrayD<f32>, axis: usize) -> ArrayD<f32>;
+    // g - exp(y) * sum(g) along each lane, for a log-softmax with output `y`.
+    fn log_softmax_backward(&self, grad: &ArrayD<f32>, y: &ArrayD<f32>, axis: usize) -> ArrayD<f32>;
+}
+
+thread_local! {
+    static BACKEND: RefCell<Option<Arc<dyn Backend>>> = const { RefCell::new(None) };
+}
+
+// `ParallelCpuBackend` with the `rayon` feature, `CpuBackend` otherwise.
+pub fn default_backend() -> Arc<dyn Backend> {
+    static DEFAULT: OnceLock<Arc<dyn Backend>> = OnceLock::new();
+    DEFAULT
+        .get_or_init(|| {
+            #[cfg(feature = "rayon")]
+            let backend: Arc<dyn Backend> = Arc::new(ParallelCpuBackend);
+            #[cfg(not(feature = "rayon"))]
+            let backend: Arc<dyn Backend> = Arc::new(CpuBackend);
+            backend
+        })
+        .clone()
+}
+
+// The backend ops on this thread dispatch to. Backward passes use the backend
+// that is current when `backward` runs.
+pub fn current_backend() -> Arc<dyn Backend> {
+    BACKEND.with(|backend| backend.borrow().clone()).unwrap_or_else(default_backend)
+}
+
+// Selects the backend for every later op on this thread.
+pub fn set_backend(backend: Arc<dyn Backend>) {
+    BACKEND.with(|slot| *slot.borrow_mut() = Some(backend));
+}
+
+/// Runs `f` with every op on this thread dispatched to `backend`.
+pub fn with_backend<R>(backend: Arc<dyn Backend>, f: impl FnOnce() -> R) -> R {
+    struct Restore(Option<Arc<dyn Backend>>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            BACKEND.with(|slot| *slot.borrow_mut() = self.0.take());
+        }
+    }
+
+    let _restore = Restore(BACKEND.with(|slot| slot.borrow_mut().replace(backend)));
+    f()
+}
@@ -0,0 +1,171 @@
+// Multithreaded CPU backend. Each tile of a GEMM output is computed by
+// ndarray's cache-blocked, SIMD `general_mat_mul`, and tiles, the matrices of a
+// batched product, elementwise ops and independent lanes are spread across the
+// rayon thread pool. Inputs too small to be worth splitting run on the
+// reference `CpuBackend`.
+use super::cpu::{self, CpuBackend};
+use super::Backend;
+use crate::core::rope::RopeLayout;
+use ndarray::linalg::general_mat_mul;
+use ndarray::parallel::prelude::*;
+use ndarray::{Array2, ArrayD, ArrayView2, ArrayViewD, ArrayViewMut2, ArrayViewMut3, Axis, Zip};
+
+// Products smaller than this many multiply-adds are not worth splitting.
+const GEMM_THRESHOLD: usize = 1 << 18;
+// Rows (or columns) of a GEMM output computed by one task.
+const TILE: usize = 64;
+// Elementwise and lane-wise ops over fewer elements than this stay serial.
+const ELEMENTWISE_THRESHOLD: usize = 1 << 15;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ParallelCpuBackend;
+
+fn serial(len: usize) -> bool {
+    len < ELEMENTWISE_THRESHOLD || rayon::current_num_threads() == 1
+}
+
+impl Backend for ParallelCpuBackend {
+    fn name(&self) -> &'static str {
+        "parallel_cpu"
+    }
+
+    fn gemm_into(&self, a: &ArrayView2<f32>, b: &ArrayView2<f32>, mut c: ArrayViewMut2<f32>) {
+        if a.nrows() * a.ncols() * b.ncols() < GEMM_THRESHOLD || rayon::current_num_threads() == 1 {
+            return CpuBackend.gemm_into(a, b, c);
+        }
+        // Split the larger output dimension, so short (few-token) inputs
+        // against wide weights still use every thread.
+        if a.nrows() >= b.ncols() {
+            c.axis_chunks_iter_mut(Axis(0), TILE)
+                .into_par_iter()
+                .zip(a.axis_chunks_iter(Axis(0), TILE))
+                .for_each(|(mut c, a)| general_mat_mul(1.0, &a, b, 0.0, &mut c));
+        } else {
+            c.axis_chunks_iter_mut(Axis(1), TILE)
+                .into_par_iter()
+                .zip(b.axis_chunks_iter(Axis(1), TILE))
+                .for_each(|(mut c, b)| general_mat_mul(1.0, a, &b, 0.0, &mut c));
+        }
+    }
+
+    fn batched_gemm_into(&self, pairs: &[(ArrayView2<f32>, ArrayView2<f32>)], mut out: ArrayViewMut3<f32>) {
+        if pairs.len() == 1 || rayon::current_num_threads() == 1 {
+            for ((a, b), c) in pairs.iter().zip(out.outer_iter_mut()) {
+                self.gemm_into(a, b, c);
+            }
+            return;
+        }
+        out.outer_iter_mut()
+            .into_par_iter()
+            .zip(pairs)
+            .for_each(|(c, (a, b))| self.gemm_into(a, b, c));
+    }
+
+    fn add(&self, a: &ArrayViewD<f32>, b: &ArrayViewD<f32>) -> ArrayD<f32> {
+        if serial(a.len()) {
+            return CpuBackend.add(a, b);
+        }
+        Zip::from(a).and(b).par_map_collect(|&a, &b| a + b)
+    }
+
+    fn mul(&self, a: &ArrayViewD<f32>, b: &ArrayViewD<f32>) -> ArrayD<f32> {
+        if serial(a.len()) {
+            return CpuBackend.mul(a, b);
+        }
+        Zip::from(a).and(b).par_map_collect(|&a, &b| a * b)
+    }
+
+    fn scale(&self, x: &ArrayViewD<f32>, factor: f32) -> ArrayD<f32> {
+        if serial(x.len()) {
+            return CpuBackend.scale(x, factor);
+        }
+        Zip::from(x).par_map_collect(|&x| x * factor)
+    }
+
+    fn silu(&self, x: &ArrayViewD<f32>) -> ArrayD<f32> {
+        if serial(x.len()) {
+            return CpuBackend.silu(x);
+        }
+        Zip::from(x).par_map_collect(|&x| cpu::silu(x))
+    }
+
+    fn silu_backward(&self, x: &ArrayViewD<f32>, grad: &ArrayViewD<f32>) -> ArrayD<f32> {
+        if serial(x.len()) {
+            return CpuBackend.silu_backward(x, grad);
+        }
+        Zip::from(x).and(grad).par_map_collect(|&x, &g| g * cpu::dsilu(x))
+    }
+
+    fn sum(&self, x: &ArrayViewD<f32>) -> f32 {
+        if serial(x.len()) {
+            return CpuBackend.sum(x);
+        }
+        Zip::from(x).par_fold(|| 0.0, |acc, &x| acc + x, |a, b| a + b)
+    }
+
+    fn rms_normalize(&self, x: &ArrayViewD<f32>, epsilon: f32) -> (ArrayD<f32>, ArrayD<f32>) {
+        if serial(x.len()) {
+            return CpuBackend.rms_normalize(x, epsilon);
+        }
+        let last = Axis(x.ndim() - 1);
+        let mut normalized = x.to_owned();
+        let mut rrms = ArrayD::zeros(cpu::rrms_shape(x));
+        Zip::from(normalized.lanes_mut(last))
+            .and(rrms.lanes_mut(last))
+            .par_for_each(|lane, r| cpu::rms_normalize_lane(lane, r, epsilon));
+        (normalized, rrms)
+    }
+
+    fn logsumexp(&self, x: &ArrayViewD<f32>, axis: usize) -> ArrayD<f32> {
+        if serial(x.len()) {
+            return CpuBackend.logsumexp(x, axis);
+        }
+        Zip::from(x.lanes(Axis(axis))).par_map_collect(cpu::logsumexp_lane)
+    }
+
+    fn rope(&self, x: &mut ArrayD<f32>, cos: &Array2<f32>, sin: &Array2<f32>, layout: RopeLayout) {
+        if serial(x.len()) {
+            return CpuBackend.rope(x, cos, sin, layout);
+        }
+        x.outer_iter_mut()
+            .into_par_iter()
+            .enumerate()
+            .for_each(|(row, token)| cpu::rotate_token(token, cos.row(row), sin.row(row), layout));
+    }
+
+    fn softmax(&self, x: &mut ArrayD<f32>, axis: usize) {
+        if serial(x.len()) {
+            return CpuBackend.softmax(x, axis);
+        }
+        Zip::from(x.lanes_mut(Axis(axis))).par_for_each(cpu::softmax_lane);
+    }
+
+    fn log_softmax(&self, x: &mut ArrayD<f32>, axis: usize) {
+        if serial(x.len()) {
+            return CpuBackend.log_softmax(x, axis);
+        }
+        Zip::from(x.lanes_mut(Axis(axis))).par_for_each(cpu::log_softmax_lane);
+    }
+
+    fn softmax_backward(&self, grad: &ArrayD<f32>, y: &ArrayD<f32>, axis: usize) -> ArrayD<f32> {
+        if serial(grad.len()) {
+            return CpuBackend.softmax_backward(grad, y, axis);
+        }
+        let mut grad_x = grad.clone();
+        Zip::from(grad_x.lanes_mut(Axis(axis)))
+            .and(y.lanes(Axis(axis)))
+            .par_for_each(cpu::softmax_backward_lane);
+        grad_x
+    }
+
+    fn log_softmax_backward(&self, grad: &ArrayD<f32>, y: &ArrayD<f32>, axis: usize) -> ArrayD<f32> {
+        if serial(grad.len()) {
+            return CpuBackend.log_softmax_backward(grad, y, axis);
+        }
+        let mut grad_x = grad.clone();
+        Zip::from(grad_x.lanes_mut(Axis(axis)))
+            .and(y.lanes(Axis(axis)))
+            .par_for_each(cpu::log_softmax_backward_lane);
+        grad_x
+    }
+}
@@ -0,0 +1,177 @@
+use super::autograd::{enable_grad, no_grad, run_backward};
+use super::{with_rng, TapeInput, Tensor};
+use memmap2::MmapMut;
+use ndarray::{ArcArray, ArrayD, IxDyn};
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Gradient (activation) checkpointing: what a checkpointed segment keeps for
+// its backward pass.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CheckpointMode {
+    // Every intermediate activation stays on the tape.
+    #[default]
+    None,
+    // Only the input of the segment is kept; the activations are recomputed
+    // during backward.
+    Full,
+    // As `Full`, with the input moved to a memory-mapped scratch file in the
+    // system temp directory (or the one given to `with_scratch_dir`) until
+    // backward needs it.
+    Offload,
+}
+
+impl fmt::Display for CheckpointMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckpointMode::None => f.write_str("none"),
+            CheckpointMode::Full => f.write_str("full"),
+            CheckpointMode::Offload => f.write_str("offload"),
+        }
+    }
+}
+
+impl FromStr for CheckpointMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(CheckpointMode::None),
+            "full" => Ok(CheckpointMode::Full),
+            "offload" => Ok(CheckpointMode::Offload),
+            _ => Err(format!("unknown checkpoint mode {s:?} (expected none, full or offload)")),
+        }
+    }
+}
+
+thread_local! {
+    static SCRATCH_DIR: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+}
+
+// Runs `f` with `CheckpointMode::Offload` writing its scratch files to `dir`
+// on this thread, instead of the system temp directory.
+pub fn with_scratch_dir<R>(dir: impl Into<PathBuf>, f: impl FnOnce() -> R) -> R {
+    struct Restore(Option<PathBuf>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            SCRATCH_DIR.with(|slot| *slot.borrow_mut() = self.0.take());
+        }
+    }
+
+    let _restore = Restore(SCRATCH_DIR.with(|slot| slot.borrow_mut().replace(dir.into())));
+    f()
+}
+
+// A checkpointed input, in memory or offloaded to disk.
+enum Saved {
+    Memory(ArcArray<f32, IxDyn>),
+    Disk(ScratchFile),
+}
+
+impl Saved {
+    fn load(&self) -> ArrayD<f32> {
+        match self {
+            Saved::Memory(data) => data.to_owned(),
+            Saved::Disk(file) => file.load(),
+        }
+    }
+}
+
+// f32 values in a memory-mapped file that is deleted on drop.
+struct ScratchFile {
+    shape: Vec<usize>,
+    map: Option<MmapMut>,
+    path: PathBuf,
+}
+
+impl ScratchFile {
+    fn create(data: &ArcArray<f32, IxDyn>) -> io::Result<Self> {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let dir = SCRATCH_DIR.with(|slot| slot.borrow().clone()).unwrap_or_else(std::env::temp_dir);
+        let path = dir.join(format!("unsloth-rs-checkpoint-{}-{id}.bin", std::process::id()));
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+        let mut scratch = ScratchFile {
+            shape: data.shape().to_vec(),
+            map: None,
+            path,
+        };
+        file.set_len((data.len() * 4) as u64)?;
+        // SAFETY: the file was just created under a unique name and is only
+        // accessed through this mapping.
+        let mut map = unsafe { MmapMut::map_mut(&file)? };
+        for (bytes, x) in map.chunks_exact_mut(4).zip(data.iter()) {
+            bytes.copy_from_slice(&x.to_le_bytes());
+        }
+        scratch.map = Some(map);
+        Ok(scratch)
+    }
+
+    fn load(&self) -> ArrayD<f32> {
+        let map = self.map.as_ref().unwrap();
+        let values = map.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect();
+        ArrayD::from_shape_vec(self.shape.clone(), values).unwrap()
+    }
+}
+
+impl Drop for ScratchFile {
+    fn drop(&mut self) {
+        // Unmap first, so the file can be removed on every platform.
+        self.map.take();
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl Tensor {
+    // Runs `f(self)` without recording its intermediate activations. On
+    // backward, `f` is run again from the saved input and backpropagated
+    // through; gradients of the tensors in `params` (the leaves `f` closes
+    // over) accumulate from that inner pass. The recomputation replays this
+    // thread's generator from where the forward pass drew from it, so `f` sees
+    // the same dropout masks both times. An empty input is never offloaded,
+    // since it cannot be mapped.
+    pub(crate) fn checkpoint(
+        &self,
+        params: &[&dyn TapeInput],
+        mode: CheckpointMode,
+        f: impl Fn(&Tensor) -> Tensor + 'static,
+    ) -> io::Result<Tensor> {
+        let tracked = self.is_tracked() || params.iter().any(|p| p.tape_node().is_some());
+        if mode == CheckpointMode::None || !tracked || !super::is_grad_enabled() {
+            return Ok(f(self));
+        }
+
+        let rng_state = with_rng(|rng| rng.clone());
+        let output = no_grad(|| f(self));
+        let saved = if mode == CheckpointMode::Offload && !self.data.is_empty() {
+            Saved::Disk(ScratchFile::create(&self.data)?)
+        } else {
+            Saved::Memory(self.data.clone())
+        };
+
+        let x_tracked = self.is_tracked();
+        let n_params = params.len();
+        let mut parents: Vec<&dyn TapeInput> = vec![self];
+        parents.extend_from_slice(params);
+        Ok(Tensor::from_op(output.data.into_owned(), &parents, move |grad| {
+            let mut x = Tensor::new(saved.load());
+            x.set_requires_grad(x_tracked);
+            let current = with_rng(|rng| std::mem::replace(rng, rng_state.clone()));
+            let y = enable_grad(|| f(&x));
+            with_rng(|rng| *rng = current);
+            if let Some(node) = &y.node {
+                run_backward(node, grad.clone());
+            }
+            let grad_x = x_tracked.then(|| x.grad().unwrap_or_else(|| ArrayD::zeros(x.data.raw_dim())));
+            let mut grads = vec![grad_x];
+            // The parameters already received their gradients above.
+            grads.extend((0..n_params).map(|_| None));
+            grads
+        }))
+    }
+}
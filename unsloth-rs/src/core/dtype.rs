@@ -0,0 +1,115 @@
+use ndarray::{ArrayViewD, CowArray, IxDyn};
+use std::fmt;
+
+pub use half::{bf16, f16};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DType {
+    F32,
+    F16,
+    BF16,
+}
+
+impl DType {
+    pub fn size_in_bytes(self) -> usize {
+        match self {
+            DType::F32 => 4,
+            DType::F16 | DType::BF16 => 2,
+        }
+    }
+}
+
+impl fmt::Display for DType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            DType::F32 => "f32",
+            DType::F16 => "f16",
+            DType::BF16 => "bf16",
+        };
+        f.write_str(name)
+    }
+}
+
+// A storage type for `Tensor`. Compute always happens in f32: values are
+// widened with `to_f32` when read and narrowed with `from_f32` when stored.
+pub trait Element: Copy + Default + fmt::Debug + Send + Sync + 'static {
+    const DTYPE: DType;
+
+    fn to_f32(self) -> f32;
+
+    fn from_f32(value: f32) -> Self;
+
+    // Little-endian bytes, as used by the safetensors format.
+    fn extend_le_bytes(self, out: &mut Vec<u8>);
+
+    fn from_le_bytes(bytes: &[u8]) -> Self;
+
+    // Widens a view to f32, borrowing instead of copying when it already is.
+    fn upcast(view: ArrayViewD<'_, Self>) -> CowArray<'_, f32, IxDyn> {
+        CowArray::from(view.mapv(Self::to_f32))
+    }
+}
+
+impl Element for f32 {
+    const DTYPE: DType = DType::F32;
+
+    fn to_f32(self) -> f32 {
+        self
+    }
+
+    fn from_f32(value: f32) -> Self {
+        value
+    }
+
+    fn extend_le_bytes(self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&f32::to_le_bytes(self));
+    }
+
+    fn from_le_bytes(bytes: &[u8]) -> Self {
+        f32::from_le_bytes(bytes.try_into().unwrap())
+    }
+
+    fn upcast(view: ArrayViewD<'_, Self>) -> CowArray<'_, f32, IxDyn> {
+        CowArray::from(view)
+    }
+}
+
+impl Element for f16 {
+    const DTYPE: DType = DType::F16;
+
+    fn to_f32(self) -> f32 {
+        f16::to_f32(self)
+    }
+
+    fn from_f32(value: f32) -> Self {
+        f16::from_f32(value)
+    }
+
+    fn extend_le_bytes(self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&f16::to_le_bytes(self));
+    }
+
+    fn from_le_bytes(bytes: &[u8]) -> Self {
+        f16::from_le_bytes(bytes.try_into().unwrap())
+    }
+}
+
+impl Element for bf16 {
+    const DTYPE: DType = DType::BF16;
+
+    fn to_f32(self) -> f32 {
+        bf16::to_f32(self)
+    }
+
+    fn from_f32(value: f32) -> Self {
+        bf16::from_f32(value)
+    }
+
+    fn extend_le_bytes(self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&bf16::to_le_bytes(self));
+    }
+
+    fn from_le_bytes(bytes: &[u8]) -> Self {
+        bf16::from_le_bytes(bytes.try_into().unwrap())
+    }
+}
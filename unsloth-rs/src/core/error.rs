@@ -0,0 +1,45 @@
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TensorError {
+    // An input does not have the shape the op requires.
+    ShapeMismatch {
+        op: &'static str,
+        expected: Vec<usize>,
+        actual: Vec<usize>,
+    },
+    // Two inputs cannot be combined (broadcast or contracted) with each other.
+    IncompatibleShapes {
+        op: &'static str,
+        lhs: Vec<usize>,
+        rhs: Vec<usize>,
+    },
+    InvalidAxis {
+        op: &'static str,
+        axis: usize,
+        ndim: usize,
+    },
+    InvalidArgument {
+        op: &'static str,
+        message: String,
+    },
+}
+
+impl fmt::Display for TensorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TensorError::ShapeMismatch { op, expected, actual } => {
+                write!(f, "{op}: expected shape {expected:?}, got {actual:?}")
+            }
+            TensorError::IncompatibleShapes { op, lhs, rhs } => {
+                write!(f, "{op}: incompatible shapes {lhs:?} and {rhs:?}")
+            }
+            TensorError::InvalidAxis { op, axis, ndim } => {
+                write!(f, "{op}: axis {axis} is out of range for a {ndim}D tensor")
+            }
+            TensorError::InvalidArgument { op, message } => write!(f, "{op}: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for TensorError {}
@@ -0,0 +1,75 @@
+use super::rng::Rng;
+use super::{Element, Tensor};
+use ndarray::{Array, IxDyn};
+
+// Weight initializers, following `torch.nn.init`. Weights are laid out for
+// `x.matmul(w)`, so a 2D weight is [fan_in, fan_out] (the transpose of a
+// PyTorch `Linear` weight).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Init {
+    // LoRA B, so that a fresh adapter leaves the base model unchanged.
+    Zeros,
+    // Norm weights.
+    Ones,
+    // N(0, std^2); HF models use std = `initializer_range` (0.02).
+    Normal { std: f32 },
+    // U(-bound, bound).
+    Uniform { bound: f32 },
+    // He initialization for a leaky ReLU with negative slope `a` (0 for ReLU):
+    // std = gain / sqrt(fan_in) with gain = sqrt(2 / (1 + a^2)).
+    KaimingNormal { a: f32 },
+    // The uniform variant, bound = gain * sqrt(3 / fan_in). `a = sqrt(5)`
+    // gives `nn.Linear`'s default, which PEFT also uses for LoRA A.
+    KaimingUniform { a: f32 },
+    // Glorot initialization: std = sqrt(2 / (fan_in + fan_out)).
+    XavierNormal,
+    // bound = sqrt(6 / (fan_in + fan_out)).
+    XavierUniform,
+}
+
+// (fan_in, fan_out) of a weight: the last axis is the output, every other axis
+// feeds the input. A vector counts as both.
+pub fn fans(shape: &[usize]) -> (usize, usize) {
+    match shape {
+        [] => (1, 1),
+        [n] => (*n, *n),
+        [.., fan_out] => (shape[..shape.len() - 1].iter().product(), *fan_out),
+    }
+}
+
+impl Init {
+    pub fn tensor(&self, shape: &[usize], rng: &mut Rng) -> Tensor {
+        self.tensor_with_dtype(shape, rng)
+    }
+
+    // Values are drawn in f32 (in row-major order) and then cast to `T`.
+    pub fn tensor_with_dtype<T: Element>(&self, shape: &[usize], rng: &mut Rng) -> Tensor<T> {
+        let (fan_in, fan_out) = fans(shape);
+        let gain = |a: f32| (2.0 / (1.0 + a * a)).sqrt();
+        let n: usize = shape.iter().product();
+        let values: Vec<f32> = match *self {
+            Init::Zeros => vec![0.0; n],
+            Init::Ones => vec![1.0; n],
+            Init::Normal { std } => (0..n).map(|_| std * rng.normal()).collect(),
+            Init::Uniform { bound } => (0..n).map(|_| rng.uniform_range(-bound, bound)).collect(),
+            Init::KaimingNormal { a } => {
+                let std = gain(a) / (fan_in.max(1) as f32).sqrt();
+                (0..n).map(|_| std * rng.normal()).collect()
+            }
+            Init::KaimingUniform { a } => {
+                let bound = gain(a) * (3.0 / fan_in.max(1) as f32).sqrt();
+                (0..n).map(|_| rng.uniform_range(-bound, bound)).collect()
+            }
+            Init::XavierNormal => {
+                let std = (2.0 / (fan_in + fan_out).max(1) as f32).sqrt();
+                (0..n).map(|_| std * rng.normal()).collect()
+            }
+            Init::XavierUniform => {
+                let bound = (6.0 / (fan_in + fan_out).max(1) as f32).sqrt();
+                (0..n).map(|_| rng.uniform_range(-bound, bound)).collect()
+            }
+        };
+        let values = values.into_iter().map(T::from_f32).collect();
+        Tensor::from_array(Array::from_shape_vec(IxDyn(shape), values).unwrap())
+    }
+}
@@ -0,0 +1,153 @@
+use super::{current_backend, Rng};
+use ndarray::{s, Array1, Array2, ArrayView2, Axis};
+
+// Sweeps over every pair of columns before `svd` gives up on convergence;
+// Jacobi usually needs fewer than ten.
+const MAX_SWEEPS: usize = 60;
+
+// Columns `svd_lowrank` samples beyond the rank it returns, which makes the
+// top directions much more accurate for little extra work.
+const OVERSAMPLING: usize = 8;
+
+// A thin singular value decomposition `a = u diag(s) vt` of an [m, n] matrix,
+// with k singular values in decreasing order: u is [m, k], s is [k] and vt is
+// [k, n]. `svd` gives all k = min(m, n) of them, `svd_lowrank` the top ones.
+#[derive(Debug, Clone)]
+pub struct Svd {
+    pub u: Array2<f32>,
+    pub s: Array1<f32>,
+    pub vt: Array2<f32>,
+}
+
+impl Svd {
+    // The best rank-`rank` approximation of the matrix (Eckart-Young), split
+    // evenly into `u_r sqrt(s_r)` [m, rank] and `sqrt(s_r) vt_r` [rank, n]. A
+    // rank above k is padded with zeros.
+    pub fn low_rank(&self, rank: usize) -> (Array2<f32>, Array2<f32>) {
+        let (m, k, n) = (self.u.nrows(), self.s.len(), self.vt.ncols());
+        let kept = rank.min(k);
+        let root = self.s.slice(s![..kept]).mapv(f32::sqrt);
+        let mut left = Array2::zeros((m, rank));
+        let mut right = Array2::zeros((rank, n));
+        left.slice_mut(s![.., ..kept]).assign(&(&self.u.slice(s![.., ..kept]) * &root));
+        right.slice_mut(s![..kept, ..]).assign(&(&self.vt.slice(s![..kept, ..]) * &root.insert_axis(Axis(1))));
+        (left, right)
+    }
+}
+
+// One-sided Jacobi (Hestenes) in f64: rotates pairs of columns until they are
+// all orthogonal, accumulating the rotations in v, so that the column norms
+// are the singular values. Accurate, but each sweep is cubic in the matrix
+// size, too slow for whole weights; `svd_lowrank` only runs it on a small
+// projection of them.
+pub fn svd(a: &ArrayView2<f32>) -> Svd {
+    let (m, n) = a.dim();
+    if m < n {
+        let Svd { u, s, vt } = svd(&a.t());
+        return Svd {
+            u: vt.reversed_axes(),
+            s,
+            vt: u.reversed_axes(),
+        };
+    }
+
+    let mut u = a.mapv(f64::from);
+    let mut v = Array2::<f64>::eye(n);
+    for _ in 0..MAX_SWEEPS {
+        let mut rotated = false;
+        for p in 0..n {
+            for q in p + 1..n {
+                let (cp, cq) = (u.column(p), u.column(q));
+                let (alpha, beta, gamma) = (cp.dot(&cp), cq.dot(&cq), cp.dot(&cq));
+                if gamma.abs() <= f64::EPSILON * (alpha * beta).sqrt() {
+                    continue;
+                }
+                rotated = true;
+                // The rotation that zeroes the (p, q) entry of u^T u.
+                let zeta = (beta - alpha) / (2.0 * gamma);
+                let t = zeta.signum() / (zeta.abs() + (1.0 + zeta * zeta).sqrt());
+                let c = 1.0 / (1.0 + t * t).sqrt();
+                rotate(&mut u, p, q, c, c * t);
+                rotate(&mut v, p, q, c, c * t);
+            }
+        }
+        if !rotated {
+            break;
+        }
+    }
+
+    let norms: Vec<f64> = u.columns().into_iter().map(|column| column.dot(&column).sqrt()).collect();
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by(|&i, &j| norms[j].total_cmp(&norms[i]));
+    let mut svd = Svd {
+        u: Array2::zeros((m, n)),
+        s: Array1::zeros(n),
+        vt: Array2::zeros((n, n)),
+    };
+    for (k, &j) in order.iter().enumerate() {
+        svd.s[k] = norms[j] as f32;
+        // The left vector of a zero singular value is left at zero.
+        if norms[j] > 0.0 {
+            svd.u.column_mut(k).assign(&u.column(j).mapv(|x| (x / norms[j]) as f32));
+        }
+        svd.vt.row_mut(k).assign(&v.column(j).mapv(|x| x as f32));
+    }
+    svd
+}
+
+fn rotate(a: &mut Array2<f64>, p: usize, q: usize, c: f64, s: f64) {
+    for mut row in a.rows_mut() {
+        let (x, y) = (row[p], row[q]);
+        row[p] = c * x - s * y;
+        row[q] = s * x + c * y;
+    }
+}
+
+// The top `rank` singular triplets by randomized subspace iteration (Halko et
+// al., as `torch.svd_lowrank`): the range of `a` is sampled with a Gaussian
+// sketch of `rank + OVERSAMPLING` columns, sharpened by `iterations` power
+// steps, and `a` projected onto it is decomposed with `svd`. That costs
+// O(m n l) for l sampled columns instead of cubic sweeps over `a`, and is exact
+// once l reaches min(m, n). The sketch has a fixed seed, so that the result
+// only depends on `a`.
+pub fn svd_lowrank(a: &ArrayView2<f32>, rank: usize, iterations: usize) -> Svd {
+    let (m, n) = a.dim();
+    let width = (rank + OVERSAMPLING).min(m.min(n));
+    let backend = current_backend();
+    let mut rng = Rng::new(0);
+    let sketch = Array2::from_shape_simple_fn((n, width), || rng.normal());
+    let mut q = orthonormalize(backend.gemm(a, &sketch.view()));
+    for _ in 0..iterations {
+        let z = orthonormalize(backend.gemm(&a.t(), &q.view()));
+        q = orthonormalize(backend.gemm(a, &z.view()));
+    }
+
+    let Svd { u, s, vt } = svd(&backend.gemm(&q.t(), a).view());
+    let kept = rank.min(s.len());
+    Svd {
+        u: backend.gemm(&q.view(), &u.slice(s![.., ..kept])),
+        s: s.slice(s![..kept]).to_owned(),
+        vt: vt.slice(s![..kept, ..]).to_owned(),
+    }
+}
+
+// Orthonormal columns spanning those of `a`, by Gram-Schmidt in f64 (run twice
+// over each column, which keeps them orthogonal to working precision). A
+// column that depends on the earlier ones is left at zero.
+fn orthonormalize(a: Array2<f32>) -> Array2<f32> {
+    let mut q = a.mapv(f64::from);
+    for j in 0..q.ncols() {
+        let norm = q.column(j).dot(&q.column(j)).sqrt();
+        for _ in 0..2 {
+            for i in 0..j {
+                let (previous, column) = (q.column(i).to_owned(), q.column(j));
+                let projection = previous.dot(&column);
+                q.column_mut(j).scaled_add(-projection, &previous);
+            }
+        }
+        let remaining = q.column(j).dot(&q.column(j)).sqrt();
+        let scale = if remaining > norm * f64::from(f32::EPSILON) { remaining.recip() } else { 0.0 };
+        q.column_mut(j).mapv_inplace(|x| x * scale);
+    }
+    q.mapv(|x| x as f32)
+}
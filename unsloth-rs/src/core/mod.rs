@@ -0,0 +1,683 @@
+pub mod autograd;
+pub mod backend;
+pub mod checkpoint;
+pub mod dtype;
+pub mod error;
+pub mod init;
+pub mod linalg;
+pub mod quant;
+pub mod rng;
+pub mod rope;
+pub mod softmax;
+
+use autograd::{reduce_to_shape, Node};
+use ndarray::{s, ArcArray, Array, Array2, ArrayD, ArrayView2, ArrayViewD, Axis, Dimension, Ix2, IxDyn};
+use std::fmt;
+use std::rc::Rc;
+
+pub use autograd::{enable_grad, is_grad_enabled, no_grad};
+#[cfg(feature = "rayon")]
+pub use backend::ParallelCpuBackend;
+pub use backend::{current_backend, set_backend, with_backend, Backend, CpuBackend};
+pub use checkpoint::{with_scratch_dir, CheckpointMode};
+pub use dtype::{bf16, f16, DType, Element};
+pub use error::TensorError;
+pub use init::Init;
+pub use linalg::{svd, svd_lowrank, Svd};
+pub use quant::{QuantType, QuantizedTensor, Weight};
+pub use rng::{manual_seed, with_rng, Rng};
+pub use rope::{RopeConfig, RopeLayout, RopeScaling};
+pub use softmax::SoftmaxMask;
+
+// Tensors store their elements as `T` (f32 by default). Ops compute and
+// return f32; only f32 tensors can be recorded on the gradient tape.
+#[derive(Clone)]
+pub struct Tensor<T: Element = f32> {
+    pub data: ArcArray<T, IxDyn>,
+    node: Option<Rc<Node>>,
+}
+
+impl<T: Element> fmt::Debug for Tensor<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tensor")
+            .field("data", &self.data)
+            .field("dtype", &T::DTYPE)
+            .field("requires_grad", &self.requires_grad())
+            .finish()
+    }
+}
+
+// Anything whose tape node can be recorded as the parent of an op.
+pub(crate) trait TapeInput {
+    fn tape_node(&self) -> Option<Rc<Node>>;
+}
+
+impl<T: Element> TapeInput for Tensor<T> {
+    fn tape_node(&self) -> Option<Rc<Node>> {
+        self.node.clone()
+    }
+}
+
+impl<T: Element> Tensor<T> {
+    pub fn from_array(data: Array<T, IxDyn>) -> Self {
+        Self::from_shared(data.into_shared())
+    }
+
+    // Shares `data`, e.g. a slice of a longer buffer, instead of copying it.
+    pub(crate) fn from_shared(data: ArcArray<T, IxDyn>) -> Self {
+        Tensor { data, node: None }
+    }
+
+    pub fn dtype(&self) -> DType {
+        T::DTYPE
+    }
+
+    // Explicit element-type conversion. Casts are not recorded on the tape.
+    pub fn to_dtype<U: Element>(&self) -> Tensor<U> {
+        Tensor::from_array(self.data.mapv(|x| U::from_f32(x.to_f32())))
+    }
+
+    pub fn to_f32(&self) -> Tensor {
+        self.to_dtype()
+    }
+
+    pub(crate) fn is_tracked(&self) -> bool {
+        self.node.is_some()
+    }
+
+    pub fn requires_grad(&self) -> bool {
+        self.node.is_some()
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.node.as_ref().is_none_or(|node| node.is_leaf())
+    }
+}
+
+impl Tensor {
+    pub fn new(data: Array<f32, IxDyn>) -> Self {
+        Tensor::from_array(data)
+    }
+
+    // Wraps the result of an op, recording it on the tape when gradients are
+    // enabled and at least one input is tracked.
+    pub(crate) fn from_op(
+        data: ArrayD<f32>,
+        parents: &[&dyn TapeInput],
+        backward: impl Fn(&ArrayD<f32>) -> Vec<Option<ArrayD<f32>>> + 'static,
+    ) -> Tensor {
+        let parents: Vec<_> = parents.iter().map(|p| p.tape_node()).collect();
+        let tracked = is_grad_enabled() && parents.iter().any(Option::is_some);
+        let node = tracked.then(|| Node::op(parents, Box::new(backward)));
+        Tensor {
+            data: data.into_shared(),
+            node,
+        }
+    }
+
+    // Turns this tensor into a leaf whose gradient is accumulated by `backward`.
+    pub fn set_requires_grad(&mut self, requires_grad: bool) {
+        self.node = requires_grad.then(Node::leaf);
+    }
+
+    pub fn grad(&self) -> Option<ArrayD<f32>> {
+        self.node.as_ref().and_then(|node| node.grad())
+    }
+
+    pub fn zero_grad(&self) {
+        if let Some(node) = &self.node {
+            node.zero_grad();
+        }
+    }
+
+    pub fn detach(&self) -> Tensor {
+        Tensor {
+            data: self.data.clone(),
+            node: None,
+        }
+    }
+
+    // Backpropagates from this tensor, seeding it with ones (so a non-scalar
+    // output behaves as if it were summed first). Gradients accumulate into
+    // `.grad()` of every leaf that requires grad.
+    pub fn backward(&self) {
+        if let Some(node) = &self.node {
+            autograd::run_backward(node, ArrayD::ones(self.data.raw_dim()));
+        }
+    }
+
+    // Matrix product with numpy-style batching: leading dimensions broadcast,
+    // and a 1D operand is treated as a row (lhs) or column (rhs) vector.
+    pub fn matmul<R: MatmulRhs + ?Sized>(&self, other: &R) -> Tensor {
+        unwrap_or_panic(self.try_matmul(other))
+    }
+
+    pub fn try_matmul<R: MatmulRhs + ?Sized>(&self, other: &R) -> Result<Tensor, TensorError> {
+        other.try_rmatmul(self)
+    }
+
+    pub fn reshape(&self, shape: &[usize]) -> Tensor {
+        unwrap_or_panic(self.try_reshape(shape))
+    }
+
+    pub fn try_reshape(&self, shape: &[usize]) -> Result<Tensor, TensorError> {
+        if shape.iter().product::<usize>() != self.data.len() {
+            return Err(TensorError::ShapeMismatch {
+                op: "reshape",
+                expected: shape.to_vec(),
+                actual: self.data.shape().to_vec(),
+            });
+        }
+        let result = self.data.as_standard_layout().into_owned().into_shape(shape).unwrap();
+
+        let input_shape = self.data.shape().to_vec();
+        Ok(Tensor::from_op(result, &[self], move |grad| {
+            vec![Some(grad.as_standard_layout().into_owned().into_shape(input_shape.clone()).unwrap())]
+        }))
+    }
+
+    pub fn permute(&self, axes: &[usize]) -> Tensor {
+        unwrap_or_panic(self.try_permute(axes))
+    }
+
+    pub fn try_permute(&self, axes: &[usize]) -> Result<Tensor, TensorError> {
+        let ndim = self.data.ndim();
+        let mut seen = vec![false; ndim];
+        for &axis in axes {
+            if axis >= ndim {
+                return Err(TensorError::InvalidAxis { op: "permute", axis, ndim });
+            }
+            seen[axis] = true;
+        }
+        if axes.len() != ndim || seen.contains(&false) {
+            return Err(TensorError::InvalidArgument {
+                op: "permute",
+                message: format!("{axes:?} is not a permutation of the axes of a {ndim}D tensor"),
+            });
+        }
+        let result = self.data.view().permuted_axes(axes).as_standard_layout().into_owned();
+
+        let mut inverse = vec![0; axes.len()];
+        for (i, &axis) in axes.iter().enumerate() {
+            inverse[axis] = i;
+        }
+        Ok(Tensor::from_op(result, &[self], move |grad| {
+            vec![Some(grad.view().permuted_axes(&inverse[..]).as_standard_layout().into_owned())]
+        }))
+    }
+
+    pub fn transpose(&self, axis_a: usize, axis_b: usize) -> Tensor {
+        unwrap_or_panic(self.try_transpose(axis_a, axis_b))
+    }
+
+    pub fn try_transpose(&self, axis_a: usize, axis_b: usize) -> Result<Tensor, TensorError> {
+        let ndim = self.data.ndim();
+        for axis in [axis_a, axis_b] {
+            if axis >= ndim {
+                return Err(TensorError::InvalidAxis { op: "transpose", axis, ndim });
+            }
+        }
+        let mut axes: Vec<usize> = (0..ndim).collect();
+        axes.swap(axis_a, axis_b);
+        self.try_permute(&axes)
+    }
+
+    pub fn scale(&self, factor: f32) -> Tensor {
+        let result = current_backend().scale(&self.data.view(), factor);
+
+        Tensor::from_op(result, &[self], move |grad| {
+            vec![Some(current_backend().scale(&grad.view(), factor))]
+        })
+    }
+
+    pub fn add(&self, other: &Tensor) -> Tensor {
+        unwrap_or_panic(self.try_add(other))
+    }
+
+    pub fn try_add(&self, other: &Tensor) -> Result<Tensor, TensorError> {
+        let (a, b) = broadcast_views("add", self, other)?;
+        let result = current_backend().add(&a, &b);
+
+        let (a_shape, b_shape) = (self.data.shape().to_vec(), other.data.shape().to_vec());
+        Ok(Tensor::from_op(result, &[self, other], move |grad| {
+            vec![
+                Some(reduce_to_shape(grad.clone(), &a_shape)),
+                Some(reduce_to_shape(grad.clone(), &b_shape)),
+            ]
+        }))
+    }
+
+    pub fn mul(&self, other: &Tensor) -> Tensor {
+        unwrap_or_panic(self.try_mul(other))
+    }
+
+    pub fn try_mul(&self, other: &Tensor) -> Result<Tensor, TensorError> {
+        let (a_view, b_view) = broadcast_views("mul", self, other)?;
+        let result = current_backend().mul(&a_view, &b_view);
+
+        let (a, b) = (self.data.clone(), other.data.clone());
+        let (a_tracked, b_tracked) = (self.is_tracked(), other.is_tracked());
+        Ok(Tensor::from_op(result, &[self, other], move |grad| {
+            let backend = current_backend();
+            let grad_times = |x: &ArcArray<f32, IxDyn>| backend.mul(&grad.view(), &x.broadcast(grad.shape()).unwrap());
+            vec![
+                a_tracked.then(|| reduce_to_shape(grad_times(&b), a.shape())),
+                b_tracked.then(|| reduce_to_shape(grad_times(&a), b.shape())),
+            ]
+        }))
+    }
+
+    pub fn silu(&self) -> Tensor {
+        let result = current_backend().silu(&self.data.view());
+
+        let x = self.data.clone();
+        Tensor::from_op(result, &[self], move |grad| {
+            vec![Some(current_backend().silu_backward(&x.view(), &grad.view()))]
+        })
+    }
+
+    pub fn dropout(&self, p: f32) -> Tensor {
+        unwrap_or_panic(self.try_dropout(p))
+    }
+
+    // Inverted dropout: zeroes each element with probability `p` and scales the
+    // rest by 1 / (1 - p), drawing the mask from this thread's generator (see
+    // `manual_seed`). Callers skip it outside of training.
+    pub fn try_dropout(&self, p: f32) -> Result<Tensor, TensorError> {
+        if !(0.0..1.0).contains(&p) {
+            return Err(TensorError::InvalidArgument {
+                op: "dropout",
+                message: format!("probability {p} is not in [0, 1)"),
+            });
+        }
+        let scale = 1.0 / (1.0 - p);
+        let mask = with_rng(|rng| {
+            ArrayD::from_shape_simple_fn(self.data.raw_dim(), || if rng.uniform() < p { 0.0 } else { scale })
+        });
+        let result = current_backend().mul(&self.data.view(), &mask.view());
+
+        Ok(Tensor::from_op(result, &[self], move |grad| {
+            vec![Some(current_backend().mul(&grad.view(), &mask.view()))]
+        }))
+    }
+
+    pub fn sum(&self) -> Tensor {
+        let result = ArrayD::from_elem(IxDyn(&[]), current_backend().sum(&self.data.view()));
+
+        let shape = self.data.raw_dim();
+        Tensor::from_op(result, &[self], move |grad| {
+            vec![Some(ArrayD::from_elem(shape.clone(), grad.sum()))]
+        })
+    }
+
+    pub fn concat(tensors: &[&Tensor], axis: usize) -> Tensor {
+        unwrap_or_panic(Tensor::try_concat(tensors, axis))
+    }
+
+    // Joins the tensors along `axis`; every other axis must match.
+    pub fn try_concat(tensors: &[&Tensor], axis: usize) -> Result<Tensor, TensorError> {
+        let Some(first) = tensors.first() else {
+            return Err(TensorError::InvalidArgument {
+                op: "concat",
+                message: "there are no tensors to concatenate".to_string(),
+            });
+        };
+        let ndim = first.data.ndim();
+        if axis >= ndim {
+            return Err(TensorError::InvalidAxis { op: "concat", axis, ndim });
+        }
+        for tensor in tensors {
+            let (mut expected, mut actual) = (first.data.shape().to_vec(), tensor.data.shape().to_vec());
+            if actual.len() == ndim {
+                expected[axis] = 0;
+                actual[axis] = 0;
+            }
+            if expected != actual {
+                return Err(TensorError::ShapeMismatch {
+                    op: "concat",
+                    expected: first.data.shape().to_vec(),
+                    actual: tensor.data.shape().to_vec(),
+                });
+            }
+        }
+        let views: Vec<_> = tensors.iter().map(|tensor| tensor.data.view()).collect();
+        let result = ndarray::concatenate(Axis(axis), &views).unwrap();
+
+        let sizes: Vec<usize> = tensors.iter().map(|tensor| tensor.data.shape()[axis]).collect();
+        let parents: Vec<&dyn TapeInput> = tensors.iter().map(|&tensor| tensor as &dyn TapeInput).collect();
+        Ok(Tensor::from_op(result, &parents, move |grad| {
+            let mut start = 0;
+            sizes
+                .iter()
+                .map(|&size| {
+                    start += size;
+                    Some(grad.slice_axis(Axis(axis), (start - size..start).into()).to_owned())
+                })
+                .collect()
+        }))
+    }
+
+    pub fn narrow(&self, axis: usize, start: usize, len: usize) -> Tensor {
+        unwrap_or_panic(self.try_narrow(axis, start, len))
+    }
+
+    // The `len` entries of `axis` from `start` on, as torch's `narrow`.
+    pub fn try_narrow(&self, axis: usize, start: usize, len: usize) -> Result<Tensor, TensorError> {
+        let ndim = self.data.ndim();
+        if axis >= ndim {
+            return Err(TensorError::InvalidAxis { op: "narrow", axis, ndim });
+        }
+        let size = self.data.shape()[axis];
+        if start + len > size {
+            return Err(TensorError::InvalidArgument {
+                op: "narrow",
+                message: format!("{start}..{} is out of bounds for axis {axis} of size {size}", start + len),
+            });
+        }
+        let result = self.data.slice_axis(Axis(axis), (start..start + len).into()).to_owned();
+
+        let shape = self.data.raw_dim();
+        Ok(Tensor::from_op(result, &[self], move |grad| {
+            let mut full = ArrayD::zeros(shape.clone());
+            full.slice_axis_mut(Axis(axis), (start..start + len).into()).assign(grad);
+            vec![Some(full)]
+        }))
+    }
+
+    pub fn rmsnorm<U: Element>(&self, weight: &Tensor<U>, epsilon: f32) -> Tensor {
+        unwrap_or_panic(self.try_rmsnorm(weight, epsilon))
+    }
+
+    pub fn try_rmsnorm<U: Element>(&self, weight: &Tensor<U>, epsilon: f32) -> Result<Tensor, TensorError> {
+        if self.data.ndim() == 0 {
+            return Err(TensorError::InvalidArgument {
+                op: "rmsnorm",
+                message: "input must be at least 1D".to_string(),
+            });
+        }
+        let last_dim = self.data.ndim() - 1;
+        let hidden = self.data.shape()[last_dim];
+        // The weight is a vector over the hidden dimension (or a single
+        // element broadcast over it).
+        if weight.data.len() != hidden && weight.data.len() != 1 {
+            return Err(TensorError::ShapeMismatch {
+                op: "rmsnorm",
+                expected: vec![hidden],
+                actual: weight.data.shape().to_vec(),
+            });
+        }
+        let backend = current_backend();
+        let (normalized_x, rrms_reshaped) = backend.rms_normalize(&self.data.view(), epsilon);
+
+        let weight_f32 = U::upcast(weight.data.view());
+        let reshaped_weight = weight_f32.to_shape((1, weight.data.len())).unwrap();
+
+        let shape = broadcast_shapes(normalized_x.shape(), reshaped_weight.shape()).unwrap();
+        let result = backend.mul(
+            &normalized_x.broadcast(shape.clone()).unwrap(),
+            &reshaped_weight.broadcast(shape).unwrap(),
+        );
+
+        let x = self.data.clone();
+        let w = reshaped_weight.to_owned().into_dyn();
+        let w_shape = weight.data.shape().to_vec();
+        let (x_tracked, w_tracked) = (self.is_tracked(), weight.is_tracked());
+        Ok(Tensor::from_op(result, &[self, weight], move |grad| {
+            let axis = Axis(last_dim);
+            let grad_x = x_tracked.then(|| {
+                // d/dx (x * r) with r = 1 / sqrt(mean(x^2) + eps):
+                // r * dn - x * r^3 * mean(dn * x)
+                let dn = grad * &w;
+                let dot = (&dn * &x).mean_axis(axis).unwrap().insert_axis(axis);
+                let r3 = rrms_reshaped.mapv(|r| r * r * r);
+                &dn * &rrms_reshaped - &(&x * &(&r3 * &dot))
+            });
+            let grad_w = w_tracked.then(|| {
+                let grad_w = reduce_to_shape(grad * &normalized_x, w.shape());
+                grad_w.into_shape(w_shape.clone()).unwrap()
+            });
+            vec![grad_x, grad_w]
+        }))
+    }
+
+    pub fn rope(&self, pos: usize, rotary_dim: usize, max_seq_len: usize, theta: f32) -> Tensor {
+        unwrap_or_panic(self.try_rope(pos, rotary_dim, max_seq_len, theta))
+    }
+
+    // Rotates every row by the same position, with interleaved pairs; see
+    // `rope_at` for per-token positions and other layouts.
+    pub fn try_rope(
+        &self,
+        pos: usize,
+        rotary_dim: usize,
+        _max_seq_len: usize,
+        theta: f32,
+    ) -> Result<Tensor, TensorError> {
+        let rows = self.data.shape().first().copied().unwrap_or(0);
+        let config = RopeConfig::new(rotary_dim, theta).with_layout(RopeLayout::Interleaved);
+        self.try_rope_at(&vec![pos; rows], &config)
+    }
+
+    pub fn softmax(&self, axis: usize) -> Tensor {
+        unwrap_or_panic(self.try_softmax(axis))
+    }
+
+    pub fn try_softmax(&self, axis: usize) -> Result<Tensor, TensorError> {
+        let mut new_data = self.data.to_owned();
+
+        // Special case for 1D arrays - apply softmax to entire array
+        let axis = if new_data.ndim() == 1 { 0 } else { axis };
+        if axis >= new_data.ndim() {
+            return Err(TensorError::InvalidAxis {
+                op: "softmax",
+                axis,
+                ndim: new_data.ndim(),
+            });
+        }
+        current_backend().softmax(&mut new_data, axis);
+
+        let y = new_data.clone();
+        Ok(Tensor::from_op(new_data, &[self], move |grad| {
+            vec![Some(current_backend().softmax_backward(grad, &y, axis))]
+        }))
+    }
+}
+
+// The right-hand side of `Tensor::matmul`: a tensor of any dtype, or a frozen
+// (possibly quantized) weight.
+pub trait MatmulRhs {
+    // Computes `lhs x self`.
+    fn try_rmatmul(&self, lhs: &Tensor) -> Result<Tensor, TensorError>;
+}
+
+impl<U: Element> MatmulRhs for Tensor<U> {
+    fn try_rmatmul(&self, lhs: &Tensor) -> Result<Tensor, TensorError> {
+        let lhs_shape = lhs.data.shape();
+        let rhs_shape = self.data.shape();
+
+        if lhs_shape.is_empty() || rhs_shape.is_empty() {
+            return Err(TensorError::InvalidArgument {
+                op: "matmul",
+                message: "inputs must be at least 1D".to_string(),
+            });
+        }
+
+        let a = promote_lhs(lhs.data.view());
+        let b = promote_rhs(self.data.view());
+        let (a_ndim, b_ndim) = (a.ndim(), b.ndim());
+        let batch = broadcast_shapes(&a.shape()[..a_ndim - 2], &b.shape()[..b_ndim - 2]);
+        if a.shape()[a_ndim - 1] != b.shape()[b_ndim - 2] || batch.is_none() {
+            return Err(TensorError::IncompatibleShapes {
+                op: "matmul",
+                lhs: lhs_shape.to_vec(),
+                rhs: rhs_shape.to_vec(),
+            });
+        }
+
+        let result = mixed_matmul(&a, &b);
+        let out_shape = result.shape().to_vec();
+        let result = result.into_shape(matmul_output_shape(lhs_shape, rhs_shape, &out_shape)).unwrap();
+
+        let (a, b) = (lhs.data.clone(), self.data.clone());
+        let (a_tracked, b_tracked) = (lhs.is_tracked(), self.is_tracked());
+        Ok(Tensor::from_op(result, &[lhs, self], move |grad| {
+            let grad = grad.view().into_shape(out_shape.clone()).unwrap();
+            let a_2d = promote_lhs(a.view());
+            let b_2d = promote_rhs(b.view());
+
+            let grad_a = a_tracked.then(|| {
+                let grad_a = mixed_matmul(&grad, &transpose_last(b_2d.clone()));
+                reduce_to_shape(grad_a, a_2d.shape()).into_shape(a.shape()).unwrap()
+            });
+            let grad_b = b_tracked.then(|| {
+                let grad_b = if b_2d.ndim() == 2 {
+                    // Fold every batch dimension into the contraction.
+                    let k = a_2d.shape()[a_2d.ndim() - 1];
+                    let n = grad.shape()[grad.ndim() - 1];
+                    let a_flat = a_2d.to_shape((a_2d.len() / k.max(1), k)).unwrap();
+                    let grad_flat = grad.to_shape((grad.len() / n.max(1), n)).unwrap();
+                    current_backend().gemm(&a_flat.t(), &grad_flat.view()).into_dyn()
+                } else {
+                    let grad_b = batched_matmul(&transpose_last(a_2d.clone()), &grad);
+                    reduce_to_shape(grad_b, b_2d.shape())
+                };
+                grad_b.into_shape(b.shape()).unwrap()
+            });
+            vec![grad_a, grad_b]
+        }))
+    }
+}
+
+fn unwrap_or_panic<T>(result: Result<T, TensorError>) -> T {
+    result.unwrap_or_else(|err| panic!("{err}"))
+}
+
+// Both operands broadcast to their common shape.
+fn broadcast_views<'a>(
+    op: &'static str,
+    a: &'a Tensor,
+    b: &'a Tensor,
+) -> Result<(ArrayViewD<'a, f32>, ArrayViewD<'a, f32>), TensorError> {
+    match broadcast_shapes(a.data.shape(), b.data.shape()) {
+        Some(shape) => Ok((a.data.broadcast(shape.clone()).unwrap(), b.data.broadcast(shape).unwrap())),
+        None => Err(TensorError::IncompatibleShapes {
+            op,
+            lhs: a.data.shape().to_vec(),
+            rhs: b.data.shape().to_vec(),
+        }),
+    }
+}
+
+fn promote_lhs<A>(a: ArrayViewD<A>) -> ArrayViewD<A> {
+    if a.ndim() == 1 {
+        a.insert_axis(Axis(0))
+    } else {
+        a
+    }
+}
+
+fn promote_rhs<A>(b: ArrayViewD<A>) -> ArrayViewD<A> {
+    if b.ndim() == 1 {
+        b.insert_axis(Axis(1))
+    } else {
+        b
+    }
+}
+
+fn transpose_last<A>(mut a: ArrayViewD<A>) -> ArrayViewD<A> {
+    let ndim = a.ndim();
+    a.swap_axes(ndim - 2, ndim - 1);
+    a
+}
+
+// Drops the unit axes that `promote_lhs` / `promote_rhs` introduced.
+fn matmul_output_shape(a_shape: &[usize], b_shape: &[usize], out_shape: &[usize]) -> Vec<usize> {
+    let mut shape = out_shape.to_vec();
+    if b_shape.len() == 1 {
+        shape.pop();
+    }
+    if a_shape.len() == 1 {
+        shape.remove(shape.len() - if b_shape.len() == 1 { 1 } else { 2 });
+    }
+    shape
+}
+
+pub(crate) fn broadcast_shapes(a: &[usize], b: &[usize]) -> Option<Vec<usize>> {
+    let ndim = a.len().max(b.len());
+    let mut shape = vec![0; ndim];
+    for i in 0..ndim {
+        let da = if i < ndim - a.len() { 1 } else { a[i - (ndim - a.len())] };
+        let db = if i < ndim - b.len() { 1 } else { b[i - (ndim - b.len())] };
+        shape[i] = match (da, db) {
+            (da, db) if da == db => da,
+            (1, db) => db,
+            (da, 1) => da,
+            _ => return None,
+        };
+    }
+    Some(shape)
+}
+
+// Columns of a narrower rhs widened to f32 at a time, so a half-precision
+// weight is never materialized in f32 all at once.
+const UPCAST_BLOCK: usize = 256;
+
+// `batched_matmul` for an rhs stored as any element type, accumulating in f32.
+fn mixed_matmul<U: Element>(a: &ArrayViewD<f32>, b: &ArrayViewD<U>) -> ArrayD<f32> {
+    if U::DTYPE == DType::F32 || b.ndim() != 2 {
+        return batched_matmul(a, &U::upcast(b.view()).view());
+    }
+
+    let (k, n) = (b.shape()[0], b.shape()[1]);
+    let a_2d = a.to_shape((a.len() / k.max(1), k)).unwrap();
+    let b_2d = b.view().into_dimensionality::<Ix2>().unwrap();
+    let mut result = Array2::zeros((a_2d.nrows(), n));
+    let backend = current_backend();
+    for start in (0..n).step_by(UPCAST_BLOCK) {
+        let end = (start + UPCAST_BLOCK).min(n);
+        let block = b_2d.slice(s![.., start..end]).mapv(U::to_f32);
+        backend.gemm_into(&a_2d.view(), &block.view(), result.slice_mut(s![.., start..end]));
+    }
+
+    let mut shape = a.shape()[..a.ndim() - 1].to_vec();
+    shape.push(n);
+    result.into_dyn().into_shape(shape).unwrap()
+}
+
+// Matrix product over the last two axes of `a` [.., m, k] and `b` [.., k, n],
+// broadcasting the leading axes.
+fn batched_matmul(a: &ArrayViewD<f32>, b: &ArrayViewD<f32>) -> ArrayD<f32> {
+    let (a_ndim, b_ndim) = (a.ndim(), b.ndim());
+    let (m, k) = (a.shape()[a_ndim - 2], a.shape()[a_ndim - 1]);
+    let n = b.shape()[b_ndim - 1];
+    debug_assert_eq!(k, b.shape()[b_ndim - 2]);
+
+    if b_ndim == 2 {
+        // Fold every leading dimension of `a` into the rows of a single GEMM.
+        let b_2d = b.view().into_dimensionality::<Ix2>().unwrap();
+        let a_2d = a.to_shape((a.len() / k.max(1), k)).unwrap();
+        let mut shape = a.shape()[..a_ndim - 1].to_vec();
+        shape.push(n);
+        return current_backend().gemm(&a_2d.view(), &b_2d).into_dyn().into_shape(shape).unwrap();
+    }
+
+    let batch = broadcast_shapes(&a.shape()[..a_ndim - 2], &b.shape()[..b_ndim - 2])
+        .expect("Matmul batch dimensions are incompatible");
+    let a = a.broadcast([&batch[..], &[m, k]].concat()).unwrap();
+    let b = b.broadcast([&batch[..], &[k, n]].concat()).unwrap();
+    let pairs: Vec<(ArrayView2<f32>, ArrayView2<f32>)> = ndarray::indices(&batch[..])
+        .into_iter()
+        .map(|index| {
+            let (mut a_mat, mut b_mat) = (a.view(), b.view());
+            for &j in index.slice() {
+                a_mat = a_mat.index_axis_move(Axis(0), j);
+                b_mat = b_mat.index_axis_move(Axis(0), j);
+            }
+            (a_mat.into_dimensionality().unwrap(), b_mat.into_dimensionality().unwrap())
+        })
+        .collect();
+    let mut result = Array::zeros((pairs.len(), m, n));
+    current_backend().batched_gemm_into(&pairs, result.view_mut());
+
+    result.into_shape([&batch[..], &[m, n]].concat()).unwrap()
+}
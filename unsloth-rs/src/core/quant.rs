@@ -0,0 +1,497 @@
+use super::autograd::Node;
+use super::{current_backend, unwrap_or_panic, Element, MatmulRhs, TapeInput, Tensor, TensorError};
+use ndarray::{s, Array, Array2, ArrayView2, IxDyn};
+use std::borrow::Cow;
+use std::fmt;
+use std::rc::Rc;
+use std::sync::Arc;
+
+// The 16 NormalFloat4 levels from the QLoRA paper (as used by bitsandbytes):
+// quantiles of N(0, 1), rescaled to [-1, 1], with an exact zero.
+const NF4_LEVELS: [f32; 16] = [
+    -1.0,
+    -0.696_192_8,
+    -0.525_073_05,
+    -0.394_917_5,
+    -0.284_441_38,
+    -0.184_773_43,
+    -0.091_050_036,
+    0.0,
+    0.079_580_3,
+    0.160_930_2,
+    0.246_112_3,
+    0.337_915_24,
+    0.440_709_83,
+    0.562_617,
+    0.722_956_84,
+    1.0,
+];
+
+pub const DEFAULT_BLOCK_SIZE: usize = 64;
+// Number of absmax values that share one scale when they are double-quantized.
+const ABSMAX_GROUP_SIZE: usize = 256;
+// Rows of the weight dequantized at a time by `matmul`.
+const DEQUANT_ROWS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum QuantType {
+    // 4-bit NormalFloat, two codes per byte, with double-quantized absmax.
+    Nf4,
+    // Symmetric 8-bit integers, one code per byte.
+    Int8,
+}
+
+impl fmt::Display for QuantType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuantType::Nf4 => f.write_str("nf4"),
+            QuantType::Int8 => f.write_str("int8"),
+        }
+    }
+}
+
+// Per-block absolute maxima, either kept in f32 or themselves quantized to
+// 8 bits around their mean ("double quantization").
+#[derive(Debug, Clone, PartialEq)]
+enum Absmax {
+    Full(Vec<f32>),
+    DoubleQuantized {
+        codes: Vec<i8>,
+        scales: Vec<f32>,
+        offset: f32,
+    },
+}
+
+impl Absmax {
+    fn double_quantize(absmax: &[f32]) -> Absmax {
+        let offset = absmax.iter().sum::<f32>() / absmax.len().max(1) as f32;
+        let mut codes = Vec::with_capacity(absmax.len());
+        let mut scales = Vec::with_capacity(absmax.len().div_ceil(ABSMAX_GROUP_SIZE));
+        for group in absmax.chunks(ABSMAX_GROUP_SIZE) {
+            let scale = group.iter().fold(0.0f32, |m, &a| m.max((a - offset).abs()));
+            scales.push(scale);
+            for &a in group {
+                let code = if scale > 0.0 { ((a - offset) / scale * 127.0).round() } else { 0.0 };
+                codes.push(code as i8);
+            }
+        }
+        Absmax::DoubleQuantized { codes, scales, offset }
+    }
+
+    // The absmax of block `i`.
+    fn get(&self, i: usize) -> f32 {
+        match self {
+            Absmax::Full(values) => values[i],
+            Absmax::DoubleQuantized { codes, scales, offset } => {
+                codes[i] as f32 / 127.0 * scales[i / ABSMAX_GROUP_SIZE] + offset
+            }
+        }
+    }
+}
+
+// A blockwise-quantized tensor. Elements are grouped, in row-major order,
+// into blocks of `block_size` values that share one absmax scale. The codes
+// and scales are shared, so clones are cheap.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuantizedTensor {
+    qtype: QuantType,
+    shape: Vec<usize>,
+    block_size: usize,
+    codes: Arc<[u8]>,
+    absmax: Arc<Absmax>,
+}
+
+impl QuantizedTensor {
+    pub fn quantize<T: Element>(tensor: &Tensor<T>, qtype: QuantType) -> Self {
+        unwrap_or_panic(Self::try_quantize(tensor, qtype, DEFAULT_BLOCK_SIZE))
+    }
+
+    pub fn try_quantize<T: Element>(
+        tensor: &Tensor<T>,
+        qtype: QuantType,
+        block_size: usize,
+    ) -> Result<Self, TensorError> {
+        if block_size == 0 || (qtype == QuantType::Nf4 && !block_size.is_multiple_of(2)) {
+            return Err(TensorError::InvalidArgument {
+                op: "quantize",
+                message: format!("invalid block size {block_size} for {qtype}"),
+            });
+        }
+
+        let values: Vec<f32> = tensor.data.iter().map(|x| x.to_f32()).collect();
+        let mut absmax = Vec::with_capacity(values.len().div_ceil(block_size));
+        let mut codes = Vec::with_capacity(match qtype {
+            QuantType::Nf4 => values.len().div_ceil(2),
+            QuantType::Int8 => values.len(),
+        });
+
+        for block in values.chunks(block_size) {
+            let max = block.iter().fold(0.0f32, |m, &x| m.max(x.abs()));
+            absmax.push(max);
+            let inv = if max > 0.0 { 1.0 / max } else { 0.0 };
+            match qtype {
+                // The first element of each pair goes in the high nibble.
+                QuantType::Nf4 => {
+                    for pair in block.chunks(2) {
+                        let hi = nearest_nf4(pair[0] * inv);
+                        let lo = pair.get(1).map_or(0, |&x| nearest_nf4(x * inv));
+                        codes.push((hi << 4) | lo);
+                    }
+                }
+                QuantType::Int8 => {
+                    codes.extend(block.iter().map(|&x| (x * inv * 127.0).round() as i8 as u8));
+                }
+            }
+        }
+
+        let absmax = match qtype {
+            QuantType::Nf4 => Absmax::double_quantize(&absmax),
+            QuantType::Int8 => Absmax::Full(absmax),
+        };
+
+        Ok(QuantizedTensor {
+            qtype,
+            shape: tensor.data.shape().to_vec(),
+            block_size,
+            codes: codes.into(),
+            absmax: Arc::new(absmax),
+        })
+    }
+
+    pub fn qtype(&self) -> QuantType {
+        self.qtype
+    }
+
+    pub fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn len(&self) -> usize {
+        self.shape.iter().product()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Bytes used by the codes and the block scales.
+    pub fn size_in_bytes(&self) -> usize {
+        let absmax = match &*self.absmax {
+            Absmax::Full(values) => values.len() * 4,
+            Absmax::DoubleQuantized { codes, scales, .. } => codes.len() + scales.len() * 4 + 4,
+        };
+        self.codes.len() + absmax
+    }
+
+    pub fn dequantize(&self) -> Tensor {
+        let mut values = vec![0.0; self.len()];
+        self.dequantize_into(0, &mut values);
+        Tensor::new(Array::from_shape_vec(IxDyn(&self.shape), values).unwrap())
+    }
+
+    // Dequantizes the flat (row-major) elements from `start` on into `out`.
+    fn dequantize_into(&self, start: usize, out: &mut [f32]) {
+        for (i, value) in (start..).zip(out) {
+            let scale = self.absmax.get(i / self.block_size);
+            *value = match self.qtype {
+                QuantType::Nf4 => {
+                    // Blocks have even length, so pairs never straddle them.
+                    let byte = self.codes[i / 2];
+                    let code = if i.is_multiple_of(2) { byte >> 4 } else { byte & 0x0f };
+                    NF4_LEVELS[code as usize] * scale
+                }
+                QuantType::Int8 => self.codes[i] as i8 as f32 / 127.0 * scale,
+            };
+        }
+    }
+
+    // Flat little-endian encoding, stored as a u8 tensor in safetensors files:
+    // qtype, block size, shape, codes, then the absmax (plain or double-quantized).
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.size_in_bytes() + 64);
+        out.push(match self.qtype {
+            QuantType::Nf4 => 0,
+            QuantType::Int8 => 1,
+        });
+        push_u64(&mut out, self.block_size as u64);
+        push_u64(&mut out, self.shape.len() as u64);
+        for &dim in &self.shape {
+            push_u64(&mut out, dim as u64);
+        }
+        push_u64(&mut out, self.codes.len() as u64);
+        out.extend_from_slice(&self.codes);
+        match &*self.absmax {
+            Absmax::Full(values) => {
+                out.push(0);
+                push_f32s(&mut out, values);
+            }
+            Absmax::DoubleQuantized { codes, scales, offset } => {
+                out.push(1);
+                out.extend_from_slice(&offset.to_le_bytes());
+                push_u64(&mut out, codes.len() as u64);
+                out.extend(codes.iter().map(|&c| c as u8));
+                push_f32s(&mut out, scales);
+            }
+        }
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, TensorError> {
+        let mut reader = ByteReader { bytes };
+        let qtype = match reader.u8()? {
+            0 => QuantType::Nf4,
+            1 => QuantType::Int8,
+            tag => return Err(corrupt(format!("unknown quantization type {tag}"))),
+        };
+        let block_size = reader.usize()?;
+        // The block sizes `try_quantize` accepts.
+        if block_size == 0 || (qtype == QuantType::Nf4 && !block_size.is_multiple_of(2)) {
+            return Err(corrupt(format!("invalid block size {block_size} for {qtype}")));
+        }
+        let ndim = reader.usize()?;
+        let shape = (0..ndim).map(|_| reader.usize()).collect::<Result<Vec<_>, _>>()?;
+        let n_codes = reader.usize()?;
+        let codes = reader.take(n_codes)?.to_vec();
+        let absmax = match reader.u8()? {
+            0 => Absmax::Full(reader.f32s()?),
+            1 => {
+                let offset = f32::from_le_bytes(reader.take(4)?.try_into().unwrap());
+                let n = reader.usize()?;
+                let codes = reader.take(n)?.iter().map(|&c| c as i8).collect();
+                let scales = reader.f32s()?;
+                Absmax::DoubleQuantized { codes, scales, offset }
+            }
+            tag => return Err(corrupt(format!("unknown absmax encoding {tag}"))),
+        };
+
+        let tensor = QuantizedTensor {
+            qtype,
+            shape,
+            block_size,
+            codes: codes.into(),
+            absmax: Arc::new(absmax),
+        };
+        let n_blocks = tensor.len().div_ceil(block_size);
+        let expected_codes = match qtype {
+            QuantType::Nf4 => tensor.len().div_ceil(2),
+            QuantType::Int8 => tensor.len(),
+        };
+        let absmax_ok = match &*tensor.absmax {
+            Absmax::Full(values) => values.len() == n_blocks,
+            Absmax::DoubleQuantized { codes, scales, .. } => {
+                codes.len() == n_blocks && scales.len() == n_blocks.div_ceil(ABSMAX_GROUP_SIZE)
+            }
+        };
+        if tensor.codes.len() != expected_codes || !absmax_ok || !reader.bytes.is_empty() {
+            return Err(corrupt("inconsistent sizes".to_string()));
+        }
+        Ok(tensor)
+    }
+}
+
+fn push_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_f32s(out: &mut Vec<u8>, values: &[f32]) {
+    push_u64(out, values.len() as u64);
+    for value in values {
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+}
+
+fn corrupt(message: String) -> TensorError {
+    TensorError::InvalidArgument {
+        op: "QuantizedTensor::from_bytes",
+        message,
+    }
+}
+
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> ByteReader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], TensorError> {
+        if n > self.bytes.len() {
+            return Err(corrupt("unexpected end of data".to_string()));
+        }
+        let (head, tail) = self.bytes.split_at(n);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, TensorError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn usize(&mut self) -> Result<usize, TensorError> {
+        let value = u64::from_le_bytes(self.take(8)?.try_into().unwrap());
+        usize::try_from(value).map_err(|_| corrupt(format!("size {value} does not fit in usize")))
+    }
+
+    fn f32s(&mut self) -> Result<Vec<f32>, TensorError> {
+        let n = self.usize()?;
+        let bytes = self.take(n.checked_mul(4).ok_or_else(|| corrupt("size overflow".to_string()))?)?;
+        Ok(bytes.chunks_exact(4).map(|b| f32::from_le_bytes(b.try_into().unwrap())).collect())
+    }
+}
+
+fn nearest_nf4(x: f32) -> u8 {
+    let mut best = 0;
+    for (i, &level) in NF4_LEVELS.iter().enumerate() {
+        if (x - level).abs() < (x - NF4_LEVELS[best]).abs() {
+            best = i;
+        }
+    }
+    best as u8
+}
+
+// `lhs [.., k] x self [k, n]`, dequantizing `DEQUANT_ROWS` rows of the weight at
+// a time. The weight is frozen, so only `lhs` receives a gradient.
+impl MatmulRhs for QuantizedTensor {
+    fn try_rmatmul(&self, lhs: &Tensor) -> Result<Tensor, TensorError> {
+        let lhs_shape = lhs.data.shape().to_vec();
+        if self.shape.len() != 2 || lhs_shape.is_empty() || lhs_shape[lhs_shape.len() - 1] != self.shape[0] {
+            return Err(TensorError::IncompatibleShapes {
+                op: "matmul",
+                lhs: lhs_shape,
+                rhs: self.shape.clone(),
+            });
+        }
+
+        let (k, n) = (self.shape[0], self.shape[1]);
+        let x = lhs.data.to_shape((lhs.data.len() / k.max(1), k)).unwrap();
+        let result = self.matmul(&x.view());
+
+        let mut out_shape = lhs_shape[..lhs_shape.len() - 1].to_vec();
+        out_shape.push(n);
+        let result = result.into_dyn().into_shape(out_shape).unwrap();
+
+        // Only a tracked `lhs` needs the weight again for its gradient.
+        let weight = (super::is_grad_enabled() && lhs.is_tracked()).then(|| self.clone());
+        Ok(Tensor::from_op(result, &[lhs], move |grad| {
+            let weight = weight.as_ref().expect("the weight is kept whenever lhs is tracked");
+            let grad_2d = grad.to_shape((grad.len() / n.max(1), n)).unwrap();
+            let grad_x = weight.matmul_transposed(&grad_2d.view());
+            vec![Some(grad_x.into_dyn().into_shape(lhs_shape.clone()).unwrap())]
+        }))
+    }
+}
+
+impl QuantizedTensor {
+    // Calls `f(start, rows)` for each block of `DEQUANT_ROWS` rows of a 2D
+    // `self`, in order, so that it is never dequantized whole. Every block is
+    // dequantized into the same buffer.
+    pub(crate) fn for_each_row_block(&self, mut f: impl FnMut(usize, ArrayView2<f32>)) {
+        let (k, n) = (self.shape[0], self.shape[1]);
+        let mut scratch = vec![0.0; DEQUANT_ROWS.min(k) * n];
+        for start in (0..k).step_by(DEQUANT_ROWS) {
+            let rows = (start + DEQUANT_ROWS).min(k) - start;
+            let block = &mut scratch[..rows * n];
+            self.dequantize_into(start * n, block);
+            f(start, ArrayView2::from_shape((rows, n), block).unwrap());
+        }
+    }
+
+    // `lhs [m, k] x self` for a 2D `self [k, n]`, a block of rows at a time;
+    // each block's product goes through the same buffer.
+    pub(crate) fn matmul(&self, lhs: &ArrayView2<f32>) -> Array2<f32> {
+        let mut out = Array2::<f32>::zeros((lhs.nrows(), self.shape[1]));
+        let mut product = out.clone();
+        let backend = current_backend();
+        self.for_each_row_block(|start, rows| {
+            backend.gemm_into(&lhs.slice(s![.., start..start + rows.nrows()]), &rows, product.view_mut());
+            out += &product;
+        });
+        out
+    }
+
+    // `lhs [m, n] x self^T` for a 2D `self [k, n]`, a block of rows at a time:
+    // the input gradient of a matmul against `self`.
+    pub(crate) fn matmul_transposed(&self, lhs: &ArrayView2<f32>) -> Array2<f32> {
+        let mut out = Array2::<f32>::zeros((lhs.nrows(), self.shape[0]));
+        let backend = current_backend();
+        self.for_each_row_block(|start, rows| {
+            backend.gemm_into(lhs, &rows.t(), out.slice_mut(s![.., start..start + rows.nrows()]));
+        });
+        out
+    }
+}
+
+// A frozen weight, kept dense (in any dtype) or quantized.
+#[derive(Debug, Clone)]
+pub enum Weight<T: Element = f32> {
+    Dense(Tensor<T>),
+    Quantized(QuantizedTensor),
+}
+
+impl<T: Element> From<Tensor<T>> for Weight<T> {
+    fn from(tensor: Tensor<T>) -> Self {
+        Weight::Dense(tensor)
+    }
+}
+
+impl<T: Element> From<QuantizedTensor> for Weight<T> {
+    fn from(tensor: QuantizedTensor) -> Self {
+        Weight::Quantized(tensor)
+    }
+}
+
+impl<T: Element> Weight<T> {
+    pub fn shape(&self) -> &[usize] {
+        match self {
+            Weight::Dense(tensor) => tensor.data.shape(),
+            Weight::Quantized(tensor) => tensor.shape(),
+        }
+    }
+
+    pub fn is_quantized(&self) -> bool {
+        matches!(self, Weight::Quantized(_))
+    }
+
+    pub fn as_dense(&self) -> Option<&Tensor<T>> {
+        match self {
+            Weight::Dense(tensor) => Some(tensor),
+            Weight::Quantized(_) => None,
+        }
+    }
+
+    // The weight as a dense `T` tensor, borrowed unless it has to be dequantized.
+    pub fn to_dense(&self) -> Cow<'_, Tensor<T>> {
+        match self {
+            Weight::Dense(tensor) => Cow::Borrowed(tensor),
+            Weight::Quantized(tensor) => Cow::Owned(tensor.dequantize().to_dtype()),
+        }
+    }
+
+    // Quantizes a dense weight in place; already quantized weights are kept.
+    pub fn quantize(&mut self, qtype: QuantType) {
+        if let Weight::Dense(tensor) = self {
+            *self = Weight::Quantized(QuantizedTensor::quantize(tensor, qtype));
+        }
+    }
+}
+
+impl<T: Element> MatmulRhs for Weight<T> {
+    fn try_rmatmul(&self, lhs: &Tensor) -> Result<Tensor, TensorError> {
+        match self {
+            Weight::Dense(tensor) => tensor.try_rmatmul(lhs),
+            Weight::Quantized(tensor) => tensor.try_rmatmul(lhs),
+        }
+    }
+}
+
+// Quantized weights are frozen, so only a dense weight can be on the tape.
+impl<T: Element> TapeInput for Weight<T> {
+    fn tape_node(&self) -> Option<Rc<Node>> {
+        match self {
+            Weight::Dense(tensor) => tensor.tape_node(),
+            Weight::Quantized(_) => None,
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use std::cell::RefCell;
+use std::f32::consts::PI;
+
+// Seed of every thread's generator until `manual_seed` is called, so runs are
+// reproducible by default.
+pub const DEFAULT_SEED: u64 = 0;
+
+// xoshiro256++, seeded through SplitMix64: small, fast, and the same stream on
+// every platform.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rng {
+    state: [u64; 4],
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        let mut x = seed;
+        let mut splitmix = || {
+            x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = x;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^ (z >> 31)
+        };
+        Rng {
+            state: [splitmix(), splitmix(), splitmix(), splitmix()],
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let s = &mut self.state;
+        let result = s[0].wrapping_add(s[3]).rotate_left(23).wrapping_add(s[0]);
+        let t = s[1] << 17;
+        s[2] ^= s[0];
+        s[3] ^= s[1];
+        s[1] ^= s[2];
+        s[0] ^= s[3];
+        s[2] ^= t;
+        s[3] = s[3].rotate_left(45);
+        result
+    }
+
+    // Uniform in [0, 1), with 24 bits of precision.
+    pub fn uniform(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    // Uniform in [low, high).
+    pub fn uniform_range(&mut self, low: f32, high: f32) -> f32 {
+        low + (high - low) * self.uniform()
+    }
+
+    // Standard normal, by the Box-Muller transform.
+    pub fn normal(&mut self) -> f32 {
+        // 1 - u is in (0, 1], so the log is finite.
+        let radius = (-2.0 * (1.0 - self.uniform()).ln()).sqrt();
+        radius * (2.0 * PI * self.uniform()).cos()
+    }
+}
+
+thread_local! {
+    static RNG: RefCell<Rng> = RefCell::new(Rng::new(DEFAULT_SEED));
+}
+
+// Reseeds this thread's generator, which weight initialization draws from.
+pub fn manual_seed(seed: u64) {
+    RNG.with(|rng| *rng.borrow_mut() = Rng::new(seed));
+}
+
+// Runs `f` with this thread's generator.
+pub fn with_rng<R>(f: impl FnOnce(&mut Rng) -> R) -> R {
+    RNG.with(|rng| f(&mut rng.borrow_mut()))
+}
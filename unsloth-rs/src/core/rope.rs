@@ -0,0 +1,250 @@
+use super::{current_backend, unwrap_or_panic, Tensor, TensorError};
+use ndarray::Array2;
+use std::f32::consts::PI;
+
+// How the rotated dimensions are paired up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RopeLayout {
+    // (x[2i], x[2i + 1]), as in GPT-J and the original RoFormer.
+    Interleaved,
+    // (x[i], x[i + rotary_dim / 2]), as in GPT-NeoX and HF Llama checkpoints.
+    HalfSplit,
+}
+
+// Frequency scaling for contexts longer than the model was pretrained on,
+// matching the `rope_scaling` options of HF transformers.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RopeScaling {
+    // Position interpolation: every frequency is divided by `factor`.
+    Linear { factor: f32 },
+    // NTK-aware base rescaling, applied only once the sequence is longer than
+    // `original_max_position_embeddings`.
+    DynamicNtk {
+        factor: f32,
+        original_max_position_embeddings: usize,
+    },
+    // Interpolates low frequencies, keeps high ones, and blends in between;
+    // `attention_factor` (0.1 ln(factor) + 1 by default) scales cos and sin,
+    // which sharpens the attention logits.
+    Yarn {
+        factor: f32,
+        original_max_position_embeddings: usize,
+        beta_fast: f32,
+        beta_slow: f32,
+        attention_factor: Option<f32>,
+    },
+    // Llama 3.1: like YaRN's blend, but with wavelength thresholds.
+    Llama3 {
+        factor: f32,
+        low_freq_factor: f32,
+        high_freq_factor: f32,
+        original_max_position_embeddings: usize,
+    },
+}
+
+impl RopeScaling {
+    // YaRN with the defaults of the reference implementation.
+    pub fn yarn(factor: f32, original_max_position_embeddings: usize) -> Self {
+        RopeScaling::Yarn {
+            factor,
+            original_max_position_embeddings,
+            beta_fast: 32.0,
+            beta_slow: 1.0,
+            attention_factor: None,
+        }
+    }
+
+    // The settings Llama 3.1 checkpoints ship with.
+    pub fn llama3() -> Self {
+        RopeScaling::Llama3 {
+            factor: 8.0,
+            low_freq_factor: 1.0,
+            high_freq_factor: 4.0,
+            original_max_position_embeddings: 8192,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RopeConfig {
+    // Only the first `rotary_dim` values of each head are rotated; the rest
+    // pass through unchanged (partial rotary embeddings).
+    pub rotary_dim: usize,
+    pub theta: f32,
+    pub layout: RopeLayout,
+    pub scaling: Option<RopeScaling>,
+}
+
+impl RopeConfig {
+    // HF Llama defaults to the half-split layout.
+    pub fn new(rotary_dim: usize, theta: f32) -> Self {
+        RopeConfig {
+            rotary_dim,
+            theta,
+            layout: RopeLayout::HalfSplit,
+            scaling: None,
+        }
+    }
+
+    pub fn with_layout(mut self, layout: RopeLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    pub fn with_scaling(mut self, scaling: RopeScaling) -> Self {
+        self.scaling = Some(scaling);
+        self
+    }
+
+    // The rotation frequency of each of the `rotary_dim / 2` pairs. `seq_len`
+    // (the largest position id plus one) only matters for dynamic NTK scaling.
+    pub fn inv_freq(&self, seq_len: usize) -> Vec<f32> {
+        let dim = self.rotary_dim as f32;
+        let base_freq = |theta: f32| -> Vec<f32> {
+            (0..self.rotary_dim / 2)
+                .map(|i| 1.0 / theta.powf((2 * i) as f32 / dim))
+                .collect()
+        };
+
+        match self.scaling {
+            None => base_freq(self.theta),
+            Some(RopeScaling::Linear { factor }) => base_freq(self.theta).iter().map(|f| f / factor).collect(),
+            Some(RopeScaling::DynamicNtk {
+                factor,
+                original_max_position_embeddings: original,
+            }) => {
+                if seq_len <= original {
+                    return base_freq(self.theta);
+                }
+                let ratio = factor * seq_len as f32 / original as f32 - (factor - 1.0);
+                base_freq(self.theta * ratio.powf(dim / (dim - 2.0)))
+            }
+            Some(RopeScaling::Yarn {
+                factor,
+                original_max_position_embeddings: original,
+                beta_fast,
+                beta_slow,
+                ..
+            }) => {
+                // The dimension at which a frequency completes `rotations` full
+                // turns over the original context.
+                let correction_dim = |rotations: f32| {
+                    dim * (original as f32 / (rotations * 2.0 * PI)).ln() / (2.0 * self.theta.ln())
+                };
+                let low = correction_dim(beta_fast).floor().max(0.0);
+                let mut high = correction_dim(beta_slow).ceil().min(dim - 1.0);
+                if low == high {
+                    high += 0.001;
+                }
+                base_freq(self.theta)
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &freq)| {
+                        // 1 keeps (extrapolates) the frequency, 0 interpolates it.
+                        let extrapolation = 1.0 - ((i as f32 - low) / (high - low)).clamp(0.0, 1.0);
+                        freq / factor * (1.0 - extrapolation) + freq * extrapolation
+                    })
+                    .collect()
+            }
+            Some(RopeScaling::Llama3 {
+                factor,
+                low_freq_factor,
+                high_freq_factor,
+                original_max_position_embeddings: original,
+            }) => {
+                let low_freq_wavelen = original as f32 / low_freq_factor;
+                let high_freq_wavelen = original as f32 / high_freq_factor;
+                base_freq(self.theta)
+                    .iter()
+                    .map(|&freq| {
+                        let wavelen = 2.0 * PI / freq;
+                        if wavelen < high_freq_wavelen {
+                            freq
+                        } else if wavelen > low_freq_wavelen {
+                            freq / factor
+                        } else {
+                            let smooth =
+                                (original as f32 / wavelen - low_freq_factor) / (high_freq_factor - low_freq_factor);
+                            (1.0 - smooth) * freq / factor + smooth * freq
+                        }
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    // Factor applied to cos and sin (YaRN's attention temperature); 1 otherwise.
+    pub fn attention_scaling(&self) -> f32 {
+        match self.scaling {
+            Some(RopeScaling::Yarn {
+                attention_factor: Some(attention_factor),
+                ..
+            }) => attention_factor,
+            Some(RopeScaling::Yarn { factor, .. }) if factor > 1.0 => 0.1 * factor.ln() + 1.0,
+            _ => 1.0,
+        }
+    }
+
+    // [positions, rotary_dim / 2] tables of cos and sin of the rotation angles.
+    fn tables(&self, positions: &[usize]) -> (Array2<f32>, Array2<f32>) {
+        let seq_len = positions.iter().max().map_or(0, |&p| p + 1);
+        let inv_freq = self.inv_freq(seq_len);
+        let scale = self.attention_scaling();
+        let angles = Array2::from_shape_fn((positions.len(), inv_freq.len()), |(r, i)| positions[r] as f32 * inv_freq[i]);
+        (angles.mapv(|a| a.cos() * scale), angles.mapv(|a| a.sin() * scale))
+    }
+}
+
+impl Tensor {
+    // Rotary position embedding. The first axis holds the tokens, each rotated
+    // by its own entry of `positions`; the last axis holds the features of a
+    // head, so an input can be [seq, dim] or [seq, n_heads, head_dim].
+    pub fn rope_at(&self, positions: &[usize], config: &RopeConfig) -> Tensor {
+        unwrap_or_panic(self.try_rope_at(positions, config))
+    }
+
+    pub fn try_rope_at(&self, positions: &[usize], config: &RopeConfig) -> Result<Tensor, TensorError> {
+        let shape = self.data.shape();
+        if shape.len() < 2 {
+            return Err(TensorError::InvalidArgument {
+                op: "rope",
+                message: format!("expected at least a 2D input, got {shape:?}"),
+            });
+        }
+        if positions.len() != shape[0] {
+            return Err(TensorError::ShapeMismatch {
+                op: "rope",
+                expected: vec![shape[0]],
+                actual: vec![positions.len()],
+            });
+        }
+        let width = shape[shape.len() - 1];
+        let rotary_dim = config.rotary_dim;
+        if !rotary_dim.is_multiple_of(2) || rotary_dim > width {
+            return Err(TensorError::InvalidArgument {
+                op: "rope",
+                message: format!("rotary_dim {rotary_dim} must be even and at most {width}"),
+            });
+        }
+        // Dynamic NTK raises the base to the power `dim / (dim - 2)`.
+        if rotary_dim < 4 && matches!(config.scaling, Some(RopeScaling::DynamicNtk { .. })) {
+            return Err(TensorError::InvalidArgument {
+                op: "rope",
+                message: format!("dynamic NTK scaling needs a rotary_dim of at least 4, got {rotary_dim}"),
+            });
+        }
+
+        let (cos, sin) = config.tables(positions);
+        let layout = config.layout;
+        let mut new_data = self.data.to_owned();
+        current_backend().rope(&mut new_data, &cos, &sin, layout);
+
+        Ok(Tensor::from_op(new_data, &[self], move |grad| {
+            // The transpose of a (scaled) rotation is the rotation by the
+            // opposite angle, with the same scale.
+            let mut grad = grad.clone();
+            current_backend().rope(&mut grad, &cos, &-&sin, layout);
+            vec![Some(grad)]
+        }))
+    }
+}
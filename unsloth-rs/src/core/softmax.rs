@@ -0,0 +1,165 @@
+use super::{broadcast_shapes, current_backend, unwrap_or_panic, Tensor, TensorError};
+use ndarray::{Array, Array2, ArrayD, ArrayViewD, Axis, Dimension, Zip};
+
+// A mask for `masked_softmax`, broadcast against the input.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SoftmaxMask {
+    // `true` keeps a position, `false` masks it out.
+    Keep(ArrayD<bool>),
+    // Added to the input before the softmax; `-inf` masks a position out.
+    Additive(ArrayD<f32>),
+}
+
+impl<D: Dimension> From<Array<bool, D>> for SoftmaxMask {
+    fn from(mask: Array<bool, D>) -> Self {
+        SoftmaxMask::Keep(mask.into_dyn())
+    }
+}
+
+impl<D: Dimension> From<Array<f32, D>> for SoftmaxMask {
+    fn from(mask: Array<f32, D>) -> Self {
+        SoftmaxMask::Additive(mask.into_dyn())
+    }
+}
+
+impl SoftmaxMask {
+    // The [q_len, k_len] mask of `q_len` queries that are the last of `k_len`
+    // positions: each one sees the keys up to and including its own position.
+    pub fn causal(q_len: usize, k_len: usize) -> Self {
+        Array2::from_shape_fn((q_len, k_len), |(i, j)| j + q_len <= i + k_len).into()
+    }
+
+    // Both masks at once, in their broadcast shape: a position either one masks
+    // out stays masked, and additive biases add up.
+    pub fn combine(&self, other: &SoftmaxMask) -> Result<SoftmaxMask, TensorError> {
+        let shape = broadcast_shapes(self.shape(), other.shape()).ok_or_else(|| TensorError::IncompatibleShapes {
+            op: "combine",
+            lhs: self.shape().to_vec(),
+            rhs: other.shape().to_vec(),
+        })?;
+        Ok(match (self, other) {
+            (SoftmaxMask::Keep(a), SoftmaxMask::Keep(b)) => {
+                let (a, b) = (a.broadcast(shape.clone()).unwrap(), b.broadcast(shape).unwrap());
+                SoftmaxMask::Keep(Zip::from(&a).and(&b).map_collect(|&a, &b| a && b))
+            }
+            _ => {
+                let (a, b) = (self.bias(), other.bias());
+                SoftmaxMask::Additive(&a.broadcast(shape.clone()).unwrap() + &b.broadcast(shape).unwrap())
+            }
+        })
+    }
+
+    // As an additive mask.
+    fn bias(&self) -> ArrayD<f32> {
+        match self {
+            SoftmaxMask::Keep(mask) => mask.mapv(|keep| if keep { 0.0 } else { f32::NEG_INFINITY }),
+            SoftmaxMask::Additive(mask) => mask.clone(),
+        }
+    }
+
+    fn shape(&self) -> &[usize] {
+        match self {
+            SoftmaxMask::Keep(mask) => mask.shape(),
+            SoftmaxMask::Additive(mask) => mask.shape(),
+        }
+    }
+
+    // The input with masked positions set to `-inf` (or the bias added).
+    fn apply(&self, x: ArrayViewD<f32>) -> Option<ArrayD<f32>> {
+        let mut out = x.to_owned();
+        match self {
+            SoftmaxMask::Keep(mask) => {
+                out.zip_mut_with(&mask.broadcast(x.shape())?, |v, &keep| {
+                    if !keep {
+                        *v = f32::NEG_INFINITY;
+                    }
+                });
+            }
+            SoftmaxMask::Additive(mask) => out += &mask.broadcast(x.shape())?,
+        }
+        Some(out)
+    }
+}
+
+// Validates `axis`; as in `softmax`, a 1D input always uses its only axis.
+fn lane_axis(op: &'static str, ndim: usize, axis: usize) -> Result<usize, TensorError> {
+    let axis = if ndim == 1 { 0 } else { axis };
+    if axis >= ndim {
+        return Err(TensorError::InvalidAxis { op, axis, ndim });
+    }
+    Ok(axis)
+}
+
+impl Tensor {
+    pub fn log_softmax(&self, axis: usize) -> Tensor {
+        unwrap_or_panic(self.try_log_softmax(axis))
+    }
+
+    // x - logsumexp(x), computed without forming exp(x) / sum.
+    pub fn try_log_softmax(&self, axis: usize) -> Result<Tensor, TensorError> {
+        let axis = lane_axis("log_softmax", self.data.ndim(), axis)?;
+        let mut new_data = self.data.to_owned();
+        // Fully `-inf` lanes stay `-inf` instead of becoming NaN.
+        current_backend().log_softmax(&mut new_data, axis);
+
+        let y = new_data.clone();
+        Ok(Tensor::from_op(new_data, &[self], move |grad| {
+            // dx = g - softmax * sum(g)
+            vec![Some(current_backend().log_softmax_backward(grad, &y, axis))]
+        }))
+    }
+
+    pub fn logsumexp(&self, axis: usize) -> Tensor {
+        unwrap_or_panic(self.try_logsumexp(axis))
+    }
+
+    // Reduces `axis` away: [.., n, ..] -> [.., ..].
+    pub fn try_logsumexp(&self, axis: usize) -> Result<Tensor, TensorError> {
+        let ndim = self.data.ndim();
+        if axis >= ndim {
+            return Err(TensorError::InvalidAxis {
+                op: "logsumexp",
+                axis,
+                ndim,
+            });
+        }
+        let result = current_backend().logsumexp(&self.data.view(), axis);
+
+        let (x, lse) = (self.data.clone(), result.clone());
+        Ok(Tensor::from_op(result, &[self], move |grad| {
+            // dx = g * softmax(x); fully `-inf` lanes get no gradient.
+            let mut grad_x = x.to_owned();
+            let lse = lse.clone().insert_axis(Axis(axis));
+            let grad = grad.view().insert_axis(Axis(axis));
+            ndarray::Zip::from(&mut grad_x)
+                .and_broadcast(&lse)
+                .and_broadcast(&grad)
+                .for_each(|v, &lse, &g| {
+                    *v = if lse == f32::NEG_INFINITY { 0.0 } else { g * (*v - lse).exp() };
+                });
+            vec![Some(grad_x)]
+        }))
+    }
+
+    pub fn masked_softmax(&self, axis: usize, mask: &SoftmaxMask) -> Tensor {
+        unwrap_or_panic(self.try_masked_softmax(axis, mask))
+    }
+
+    // Softmax over `axis` with masked positions excluded. Lanes where every
+    // position is masked come out as zeros.
+    pub fn try_masked_softmax(&self, axis: usize, mask: &SoftmaxMask) -> Result<Tensor, TensorError> {
+        let axis = lane_axis("masked_softmax", self.data.ndim(), axis)?;
+        let mut new_data = mask.apply(self.data.view()).ok_or_else(|| TensorError::IncompatibleShapes {
+            op: "masked_softmax",
+            lhs: self.data.shape().to_vec(),
+            rhs: mask.shape().to_vec(),
+        })?;
+        current_backend().softmax(&mut new_data, axis);
+
+        let y = new_data.clone();
+        Ok(Tensor::from_op(new_data, &[self], move |grad| {
+            // Masked positions have y = 0, so they get no gradient.
+            vec![Some(current_backend().softmax_backward(grad, &y, axis))]
+        }))
+    }
+}
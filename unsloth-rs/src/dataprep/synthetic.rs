@@ -1,9 +1,4 @@
-use std::process::{Command, Stdio};
-use std::io::{BufReader, BufRead};
-use std::thread;
-use std::sync::{Arc, Mutex};
-use std::collections::VecDeque;
-
+#[derive(Default)]
 pub struct SyntheticDataKit {
     // We will fill this in later.
 }
@@ -18,10 +13,12 @@ impl SyntheticDataKit {
     }
 }
 
+#[allow(dead_code)]
 struct PipeCapture {
     // We will fill this in later.
 }
 
+#[allow(dead_code)]
 impl PipeCapture {
     fn new() -> Self {
         PipeCapture {}
@@ -0,0 +1,290 @@
+use crate::core::{current_backend, Element, Tensor, TensorError};
+use ndarray::{s, Array1, Array2, ArrayD, ArrayView2, Axis, CowArray, Ix1, Ix2};
+
+// Labels equal to the ignore index (this value by default) do not contribute
+// to the loss, e.g. prompt tokens or padding.
+pub const IGNORE_INDEX: usize = usize::MAX;
+
+// Mean cross-entropy of `hidden x lm_head` against `labels`, computed one block
+// of `chunk_size` vocab columns at a time, combining the logsumexp of each
+// block, so the full [tokens, vocab] logits are never materialized.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FusedCrossEntropy {
+    pub chunk_size: usize,
+    pub ignore_index: usize,
+    // With smoothing `eps`, the target puts 1 - eps on the label and spreads
+    // eps uniformly over the vocab, as in PyTorch's `label_smoothing`.
+    pub label_smoothing: f32,
+}
+
+impl Default for FusedCrossEntropy {
+    fn default() -> Self {
+        FusedCrossEntropy {
+            chunk_size: 4096,
+            ignore_index: IGNORE_INDEX,
+            label_smoothing: 0.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CrossEntropyOutput {
+    // 0 when every label is ignored.
+    pub loss: f32,
+    // Number of labels that were not ignored.
+    pub n_tokens: usize,
+    pub grad: Option<CrossEntropyGrad>,
+}
+
+// Gradients of the mean loss, shaped like `hidden` and `lm_head`.
+#[derive(Debug, Clone)]
+pub struct CrossEntropyGrad {
+    pub hidden: ArrayD<f32>,
+    pub lm_head: ArrayD<f32>,
+}
+
+// Per-row results of the first pass over the vocab; `chunk_lse` holds the
+// logsumexp of each row within each chunk, [rows, chunks].
+struct RowStats {
+    lse: Array1<f32>,
+    chunk_lse: Array2<f32>,
+    target: Vec<f32>,
+    logit_sum: Vec<f32>,
+}
+
+impl FusedCrossEntropy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    pub fn with_ignore_index(mut self, ignore_index: usize) -> Self {
+        self.ignore_index = ignore_index;
+        self
+    }
+
+    pub fn with_label_smoothing(mut self, label_smoothing: f32) -> Self {
+        self.label_smoothing = label_smoothing;
+        self
+    }
+
+    // `hidden` is [.., hidden_dim] with one row per label, `lm_head` is
+    // [hidden_dim, vocab]. With `with_grad`, a second pass over the vocab
+    // computes the gradients.
+    pub fn compute<U: Element>(
+        &self,
+        hidden: &Tensor,
+        lm_head: &Tensor<U>,
+        labels: &[usize],
+        with_grad: bool,
+    ) -> CrossEntropyOutput {
+        self.try_compute(hidden, lm_head, labels, with_grad)
+            .unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    pub fn try_compute<U: Element>(
+        &self,
+        hidden: &Tensor,
+        lm_head: &Tensor<U>,
+        labels: &[usize],
+        with_grad: bool,
+    ) -> Result<CrossEntropyOutput, TensorError> {
+        let h = self.check(hidden, lm_head, labels)?;
+        let w = lm_head.data.view().into_dimensionality::<Ix2>().unwrap();
+        let stats = self.row_stats(&h.view(), &w, labels);
+        let (loss, n_tokens) = self.reduce(&stats, labels, w.ncols());
+        let grad = with_grad.then(|| {
+            let (grad_h, grad_w) = self.grads(&h.view(), &w, labels, &stats, 1.0);
+            CrossEntropyGrad {
+                hidden: grad_h.into_shape(hidden.data.shape()).unwrap(),
+                lm_head: grad_w.into_dyn(),
+            }
+        });
+        Ok(CrossEntropyOutput { loss, n_tokens, grad })
+    }
+
+    // The loss as a 0-d tensor on the autograd tape. Only the per-row (and
+    // per-chunk) logsumexp is kept for the backward pass, which recomputes the
+    // logits chunk by chunk.
+    pub fn loss<U: Element>(&self, hidden: &Tensor, lm_head: &Tensor<U>, labels: &[usize]) -> Tensor {
+        self.try_loss(hidden, lm_head, labels).unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    pub fn try_loss<U: Element>(
+        &self,
+        hidden: &Tensor,
+        lm_head: &Tensor<U>,
+        labels: &[usize],
+    ) -> Result<Tensor, TensorError> {
+        let h = self.check(hidden, lm_head, labels)?;
+        let w = lm_head.data.view().into_dimensionality::<Ix2>().unwrap();
+        let stats = self.row_stats(&h.view(), &w, labels);
+        let (loss, _) = self.reduce(&stats, labels, w.ncols());
+
+        let this = self.clone();
+        let (h_data, w_data, labels) = (hidden.data.clone(), lm_head.data.clone(), labels.to_vec());
+        let (h_tracked, w_tracked) = (hidden.is_tracked(), lm_head.is_tracked());
+        Ok(Tensor::from_op(ArrayD::from_elem(vec![], loss), &[hidden, lm_head], move |grad| {
+            let d = h_data.shape()[h_data.ndim() - 1];
+            let h = h_data.to_shape((h_data.len() / d.max(1), d)).unwrap();
+            let w = w_data.view().into_dimensionality::<Ix2>().unwrap();
+            let (grad_h, grad_w) = this.grads(&h.view(), &w, &labels, &stats, grad[[]]);
+            vec![
+                h_tracked.then(|| grad_h.into_shape(h_data.shape()).unwrap()),
+                w_tracked.then(|| grad_w.into_dyn()),
+            ]
+        }))
+    }
+
+    fn check<'a, U: Element>(
+        &self,
+        hidden: &'a Tensor,
+        lm_head: &Tensor<U>,
+        labels: &[usize],
+    ) -> Result<CowArray<'a, f32, Ix2>, TensorError> {
+        let (h_shape, w_shape) = (hidden.data.shape(), lm_head.data.shape());
+        if h_shape.is_empty() || w_shape.len() != 2 || h_shape[h_shape.len() - 1] != w_shape[0] {
+            return Err(TensorError::IncompatibleShapes {
+                op: "cross_entropy",
+                lhs: h_shape.to_vec(),
+                rhs: w_shape.to_vec(),
+            });
+        }
+        let d = w_shape[0];
+        let rows = hidden.data.len() / d.max(1);
+        if labels.len() != rows {
+            return Err(TensorError::ShapeMismatch {
+                op: "cross_entropy",
+                expected: vec![rows],
+                actual: vec![labels.len()],
+            });
+        }
+        if let Some(&label) = labels.iter().find(|&&l| l != self.ignore_index && l >= w_shape[1]) {
+            return Err(TensorError::InvalidArgument {
+                op: "cross_entropy",
+                message: format!("label {label} is out of range for a vocab of {}", w_shape[1]),
+            });
+        }
+        if self.chunk_size == 0 || !(0.0..=1.0).contains(&self.label_smoothing) {
+            return Err(TensorError::InvalidArgument {
+                op: "cross_entropy",
+                message: format!(
+                    "invalid chunk size {} or label smoothing {}",
+                    self.chunk_size, self.label_smoothing
+                ),
+            });
+        }
+        Ok(hidden.data.to_shape((rows, d)).unwrap())
+    }
+
+    // Logits for vocab columns `start..end`.
+    fn chunk_logits<U: Element>(h: &ArrayView2<f32>, w: &ArrayView2<U>, start: usize, end: usize) -> Array2<f32> {
+        let w_chunk = U::upcast(w.slice(s![.., start..end]).into_dyn());
+        current_backend().gemm(h, &w_chunk.view().into_dimensionality::<Ix2>().unwrap())
+    }
+
+    fn row_stats<U: Element>(&self, h: &ArrayView2<f32>, w: &ArrayView2<U>, labels: &[usize]) -> RowStats {
+        let (rows, vocab) = (h.nrows(), w.ncols());
+        let mut chunk_lse = Array2::<f32>::zeros((rows, vocab.div_ceil(self.chunk_size)));
+        let mut target = vec![0.0f32; rows];
+        let mut logit_sum = vec![0.0f32; rows];
+        let backend = current_backend();
+
+        for (chunk, start) in (0..vocab).step_by(self.chunk_size).enumerate() {
+            let end = (start + self.chunk_size).min(vocab);
+            let logits = Self::chunk_logits(h, w, start, end);
+            chunk_lse.column_mut(chunk).assign(&backend.logsumexp(&logits.view().into_dyn(), 1));
+            for (r, row) in logits.axis_iter(Axis(0)).enumerate() {
+                logit_sum[r] += row.sum();
+                if (start..end).contains(&labels[r]) {
+                    target[r] = row[labels[r] - start];
+                }
+            }
+        }
+
+        let lse = backend.logsumexp(&chunk_lse.view().into_dyn(), 1);
+        let lse = lse.into_dimensionality::<Ix1>().unwrap();
+        RowStats {
+            lse,
+            chunk_lse,
+            target,
+            logit_sum,
+        }
+    }
+
+    fn reduce(&self, stats: &RowStats, labels: &[usize], vocab: usize) -> (f32, usize) {
+        let eps = self.label_smoothing;
+        let mut total = 0.0f64;
+        let mut n_tokens = 0;
+        for (r, &label) in labels.iter().enumerate() {
+            if label == self.ignore_index {
+                continue;
+            }
+            let nll = stats.lse[r] - stats.target[r];
+            let smooth = stats.lse[r] - stats.logit_sum[r] / vocab as f32;
+            total += ((1.0 - eps) * nll + eps * smooth) as f64;
+            n_tokens += 1;
+        }
+        let loss = if n_tokens > 0 { (total / n_tokens as f64) as f32 } else { 0.0 };
+        (loss, n_tokens)
+    }
+
+    // Gradients of `scale * loss`: d logits = softmax - target distribution,
+    // divided by the number of counted tokens and zero for ignored rows. The
+    // softmax of a chunk over the full vocab is its own softmax times the
+    // chunk's share of the normalizer, `exp(chunk_lse - lse)`.
+    fn grads<U: Element>(
+        &self,
+        h: &ArrayView2<f32>,
+        w: &ArrayView2<U>,
+        labels: &[usize],
+        stats: &RowStats,
+        scale: f32,
+    ) -> (Array2<f32>, Array2<f32>) {
+        let (rows, vocab) = (h.nrows(), w.ncols());
+        let n_tokens = labels.iter().filter(|&&l| l != self.ignore_index).count();
+        let mut grad_h = Array2::<f32>::zeros((rows, h.ncols()));
+        let mut grad_w = Array2::<f32>::zeros((w.nrows(), vocab));
+        if n_tokens == 0 {
+            return (grad_h, grad_w);
+        }
+        let row_scale = scale / n_tokens as f32;
+        let eps = self.label_smoothing;
+        let backend = current_backend();
+
+        for (chunk, start) in (0..vocab).step_by(self.chunk_size).enumerate() {
+            let end = (start + self.chunk_size).min(vocab);
+            let mut probs = Self::chunk_logits(h, w, start, end).into_dyn();
+            backend.softmax(&mut probs, 1);
+            let share = Array2::from_shape_fn((rows, 1), |(r, _)| {
+                if labels[r] == self.ignore_index {
+                    0.0
+                } else {
+                    (stats.chunk_lse[[r, chunk]] - stats.lse[r]).exp() * row_scale
+                }
+            })
+            .into_dyn();
+            let share = share.broadcast(probs.shape()).unwrap();
+            let mut d_logits = backend.mul(&probs.view(), &share).into_dimensionality::<Ix2>().unwrap();
+            for (r, mut row) in d_logits.axis_iter_mut(Axis(0)).enumerate() {
+                if labels[r] == self.ignore_index {
+                    continue;
+                }
+                row -= eps / vocab as f32 * row_scale;
+                if (start..end).contains(&labels[r]) {
+                    row[labels[r] - start] -= (1.0 - eps) * row_scale;
+                }
+            }
+
+            let w_chunk = U::upcast(w.slice(s![.., start..end]).into_dyn());
+            let w_chunk = w_chunk.view().into_dimensionality::<Ix2>().unwrap();
+            grad_h += &backend.gemm(&d_logits.view(), &w_chunk.t());
+            backend.gemm_into(&h.t(), &d_logits.view(), grad_w.slice_mut(s![.., start..end]));
+        }
+        (grad_h, grad_w)
+    }
+}
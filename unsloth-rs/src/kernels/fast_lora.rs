@@ -1,23 +1,1092 @@
-use crate::core::Tensor;
-use ndarray::{Array, IxDyn};
+use crate::core::{
+    current_backend, svd_lowrank, with_rng, Element, Init, QuantType, QuantizedTensor, Rng, Tensor, TensorError, Weight,
+};
+use crate::save::Model;
+use ndarray::{
+    s, ArcArray, Array1, Array2, ArrayBase, ArrayD, ArrayView1, ArrayView2, Axis, CowArray, Data, Ix1, Ix2, IxDyn,
+};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io;
 
+// A fresh rank-`rank` adapter (A, B) for a [in_features, out_features] base
+// weight, initialized as PEFT does: A is Kaiming-uniform and B is zero, so the
+// adapter starts out as a no-op.
+pub fn init_lora(in_features: usize, out_features: usize, rank: usize) -> (Tensor, Tensor) {
+    with_rng(|rng| {
+        let a = Init::KaimingUniform { a: 5f32.sqrt() }.tensor(&[in_features, rank], rng);
+        let b = Init::Zeros.tensor(&[rank, out_features], rng);
+        (a, b)
+    })
+}
+
+// PEFT's scaling of the low-rank update, `lora_alpha / r`, or
+// `lora_alpha / sqrt(r)` with rsLoRA (`use_rslora`), which keeps the update's
+// magnitude stable as the rank grows.
+pub fn lora_scaling(alpha: f32, rank: usize, use_rslora: bool) -> f32 {
+    if use_rslora {
+        alpha / (rank as f32).sqrt()
+    } else {
+        alpha / rank as f32
+    }
+}
+
+// A data-aware starting point for an adapter (see `pissa_init` and
+// `loftq_init`): the frozen base to use in place of the original weight W, the
+// adapter matrices, and `error`, the relative Frobenius error
+// `|W - (base + scaling * A B)| / |W|` of the pair.
+#[derive(Debug, Clone)]
+pub struct LoraInit<T: Element = f32> {
+    pub base: Weight<T>,
+    pub lora_a: Tensor,
+    pub lora_b: Tensor,
+    pub error: f32,
+}
+
+// PiSSA: the adapter starts as the principal rank-`rank` part of the weight
+// (its top singular vectors, so that `scaling * A B` is the best rank-`rank`
+// approximation) and the frozen base is the residual. A quantized weight is
+// dequantized first, and the residual quantized again with the same type.
+pub fn pissa_init<T: Element>(weight: &Weight<T>, rank: usize, scaling: f32) -> LoraInit<T> {
+    with_base(weight, |w| {
+        let (a, b) = principal_adapter(w, rank, scaling);
+        let residual = w - &(current_backend().gemm(&a.view(), &b.view()) * scaling);
+        let mut base = Weight::Dense(Tensor::new(residual.into_dyn()).to_dtype());
+        if let Weight::Quantized(quantized) = weight {
+            base.quantize(quantized.qtype());
+        }
+        lora_init(w, base, a, b, scaling)
+    })
+}
+
+// LoftQ: alternately quantizes the part of the weight the adapter does not
+// cover and fits the adapter to what the quantization lost (by SVD), so that
+// the quantized base plus the adapter approximates the full-precision weight.
+// The base is always quantized to `qtype`. At least one round is run, and the
+// round with the smallest error is kept, since the error need not decrease.
+pub fn loftq_init<T: Element>(
+    weight: &Weight<T>,
+    rank: usize,
+    scaling: f32,
+    qtype: QuantType,
+    iterations: usize,
+) -> LoraInit<T> {
+    with_base(weight, |w| {
+        let quantize = |target: Array2<f32>| {
+            Weight::Quantized(QuantizedTensor::quantize(&Tensor::new(target.into_dyn()), qtype))
+        };
+        let round = |base: Weight<T>| {
+            let (a, b) = principal_adapter(&with_base(&base, |q| w - q).view(), rank, scaling);
+            lora_init(w, base, a, b, scaling)
+        };
+        let mut current = round(quantize(w.to_owned()));
+        let mut best = current.clone();
+        for _ in 1..iterations {
+            let delta = lora_delta(&current.lora_a, &current.lora_b, scaling);
+            current = round(quantize(w - &delta));
+            if current.error < best.error {
+                best = current.clone();
+            }
+        }
+        best
+    })
+}
+
+// Power steps of the randomized SVD behind PiSSA and LoftQ.
+const POWER_ITERATIONS: usize = 4;
+
+// The rank-`rank` (A, B) whose `scaling * A B` best approximates `w`, from its
+// top singular vectors only (as PEFT's `pissa_niter_*` initializations).
+fn principal_adapter(w: &ArrayView2<f32>, rank: usize, scaling: f32) -> (Array2<f32>, Array2<f32>) {
+    let (a, b) = svd_lowrank(w, rank, POWER_ITERATIONS).low_rank(rank);
+    let factor = scaling.sqrt().recip();
+    (a * factor, b * factor)
+}
+
+fn lora_init<T: Element>(
+    w: &ArrayView2<f32>,
+    base: Weight<T>,
+    a: Array2<f32>,
+    b: Array2<f32>,
+    scaling: f32,
+) -> LoraInit<T> {
+    let approx = with_base(&base, |base| base + &(current_backend().gemm(&a.view(), &b.view()) * scaling));
+    let norm = |m: &ArrayView2<f32>| m.iter().map(|&x| x * x).sum::<f32>().sqrt();
+    let error = norm(&(w - &approx).view()) / norm(w).max(f32::MIN_POSITIVE);
+    LoraInit {
+        base,
+        lora_a: Tensor::new(a.into_dyn()),
+        lora_b: Tensor::new(b.into_dyn()),
+        error,
+    }
+}
+
+fn init_lora_for<T: Element>(weight: &Weight<T>, rank: usize) -> (Tensor, Tensor) {
+    match *weight.shape() {
+        [in_features, out_features] => init_lora(in_features, out_features, rank),
+        ref shape => panic!("LoRA base weights must be 2D, got {shape:?}"),
+    }
+}
+
+// PEFT's `lora_dropout`: the adapter sees a dropped-out copy of the input while
+// the base projection sees it unchanged. Every call draws a fresh mask.
+fn adapter_input(x: &Tensor, p: f32, training: bool) -> Tensor {
+    if training && p > 0.0 {
+        x.dropout(p)
+    } else {
+        x.clone()
+    }
+}
+
+// What the hand-written backward passes keep from the last `forward_for_backward`
+// call: the input and the generator state before its dropout masks were drawn. The
+// masks and the activations are recomputed from these instead of being stored.
+#[derive(Clone)]
+struct SavedInput {
+    x: ArcArray<f32, IxDyn>,
+    rng: Rng,
+    dropout: f32,
+}
+
+impl SavedInput {
+    fn new(x: &Tensor, p: f32, training: bool) -> Self {
+        SavedInput {
+            x: x.data.clone(),
+            rng: with_rng(|rng| rng.clone()),
+            dropout: if training { p } else { 0.0 },
+        }
+    }
+
+    // Redraws the masks of the forward pass, one per adapter input shape in the
+    // order they were drawn, as [rows, features]; `None` without dropout.
+    fn masks(&self, shapes: &[&[usize]]) -> Vec<Option<Array2<f32>>> {
+        if self.dropout == 0.0 {
+            return vec![None; shapes.len()];
+        }
+        let current = with_rng(|rng| std::mem::replace(rng, self.rng.clone()));
+        let masks = shapes
+            .iter()
+            .map(|shape| {
+                let mask = Tensor::new(ArrayD::ones(shape.to_vec())).dropout(self.dropout);
+                Some(rows(&mask.data).into_owned())
+            })
+            .collect();
+        with_rng(|rng| *rng = current);
+        masks
+    }
+}
+
+// `x` as [rows, features].
+fn rows<S: Data<Elem = f32>>(x: &ArrayBase<S, IxDyn>) -> CowArray<'_, f32, Ix2> {
+    let d = x.shape()[x.ndim() - 1];
+    x.to_shape((x.len() / d.max(1), d)).unwrap()
+}
+
+fn matrix(tensor: &Tensor) -> ArrayView2<'_, f32> {
+    tensor.data.view().into_dimensionality::<Ix2>().unwrap()
+}
+
+// Runs `f` on the base weight as a dense f32 matrix, which is only copied if it
+// is quantized or stored in a half type.
+fn with_base<T: Element, R>(w: &Weight<T>, f: impl FnOnce(&ArrayView2<f32>) -> R) -> R {
+    let dense = w.to_dense();
+    let data = T::upcast(dense.data.view());
+    f(&data.view().into_dimensionality::<Ix2>().unwrap())
+}
+
+fn dropped<'a>(x: &ArrayView2<'a, f32>, mask: Option<&Array2<f32>>) -> CowArray<'a, f32, Ix2> {
+    match mask {
+        Some(mask) => CowArray::from(x * mask),
+        None => CowArray::from(*x),
+    }
+}
+
+// `x W + scaling * (x * mask) A B` for one adapted projection.
+fn lora_forward<T: Element>(
+    x: &ArrayView2<f32>,
+    mask: Option<&Array2<f32>>,
+    w: &Weight<T>,
+    a: &Tensor,
+    b: &Tensor,
+    scaling: f32,
+) -> Array2<f32> {
+    let backend = current_backend();
+    let xa = backend.gemm(&dropped(x, mask).view(), &matrix(a));
+    base_forward(w, x) + backend.gemm(&xa.view(), &matrix(b)) * scaling
+}
+
+// Gradients (x, A, B) of `lora_forward` given the output gradient `dy`; the
+// frozen base weight gets none.
+fn lora_backward<T: Element>(
+    x: &ArrayView2<f32>,
+    mask: Option<&Array2<f32>>,
+    w: &Weight<T>,
+    a: &Tensor,
+    b: &Tensor,
+    scaling: f32,
+    dy: &ArrayView2<f32>,
+) -> (Array2<f32>, Array2<f32>, Array2<f32>) {
+    let backend = current_backend();
+    let (a, b) = (matrix(a), matrix(b));
+    let xd = dropped(x, mask);
+    let xa = backend.gemm(&xd.view(), &a);
+    let dy_lora = dy * scaling;
+    let grad_b = backend.gemm(&xa.t(), &dy_lora.view());
+    let dyb = backend.gemm(&dy_lora.view(), &b.t());
+    let grad_a = backend.gemm(&xd.t(), &dyb.view());
+    let mut grad_x = backend.gemm(&dyb.view(), &a.t());
+    if let Some(mask) = mask {
+        grad_x *= mask;
+    }
+    grad_x += &base_backward(w, dy);
+    (grad_x, grad_a, grad_b)
+}
+
+// `x W`; a quantized weight is dequantized a block of rows at a time, as its
+// tape matmul does.
+fn base_forward<T: Element>(w: &Weight<T>, x: &ArrayView2<f32>) -> Array2<f32> {
+    match w {
+        Weight::Quantized(quantized) => quantized.matmul(x),
+        Weight::Dense(_) => with_base(w, |w| current_backend().gemm(x, w)),
+    }
+}
+
+// `dy W^T`, the input gradient of `x W`; a quantized weight is dequantized a
+// block of rows at a time, as its forward matmul does.
+fn base_backward<T: Element>(w: &Weight<T>, dy: &ArrayView2<f32>) -> Array2<f32> {
+    match w {
+        Weight::Quantized(quantized) => quantized.matmul_transposed(dy),
+        Weight::Dense(_) => with_base(w, |w| current_backend().gemm(dy, &w.t())),
+    }
+}
+
+// One adapter of `fused_lora`: A, B and the scaling.
+type FusedAdapter<'a> = (&'a Tensor, &'a Tensor, f32);
+
+// The gradients of A and B of each adapter.
+type FusedAdapterGrads = Vec<(Array2<f32>, Array2<f32>)>;
+
+// `x W_i + scaling_i * drop(x) A_i B_i` for projections of the same input, as
+// Unsloth's fused QKV: without dropout, a single matmul against the joined A
+// matrices, leaving only the small B products per projection. Each base
+// weight keeps its own matmul, so a quantized one is never dequantized whole.
+// With dropout, each adapter draws its own mask in order, as separate
+// projections would.
+fn fused_lora<T: Element>(
+    x: &Tensor,
+    projections: &[(&Weight<T>, Option<FusedAdapter>)],
+    dropout: f32,
+    training: bool,
+) -> Vec<Tensor> {
+    let axis = x.data.ndim() - 1;
+    let a: Vec<&Tensor> = projections.iter().filter_map(|(_, adapter)| adapter.map(|(a, _, _)| a)).collect();
+    let xa = if a.is_empty() || (training && dropout > 0.0) {
+        None
+    } else {
+        Some(x.matmul(&Tensor::concat(&a, 1)))
+    };
+
+    let mut rank_start = 0;
+    let mut outputs = Vec::with_capacity(projections.len());
+    for (w, adapter) in projections {
+        let mut out = x.matmul(*w);
+        if let Some((a, b, scaling)) = adapter {
+            let rank = a.data.shape()[1];
+            let xa = match &xa {
+                Some(xa) => xa.narrow(axis, rank_start, rank),
+                None => adapter_input(x, dropout, training).matmul(*a),
+            };
+            rank_start += rank;
+            out = out.add(&xa.matmul(*b).scale(*scaling));
+        }
+        outputs.push(out);
+    }
+    outputs
+}
+
+// Gradients (x, and A and B of each adapter) of `fused_lora` without dropout,
+// given the output gradients `dys`; the joined A matrices turn the A
+// gradients and their part of the input gradient into single matmuls.
+fn fused_lora_backward<T: Element>(
+    x: &ArrayView2<f32>,
+    weights: &[&Weight<T>],
+    adapters: &[FusedAdapter],
+    dys: &[ArrayView2<f32>],
+) -> (Array2<f32>, FusedAdapterGrads) {
+    let backend = current_backend();
+    let mut grad_x = Array2::zeros(x.dim());
+    for (w, dy) in weights.iter().zip(dys) {
+        grad_x += &base_backward(w, dy);
+    }
+    let a_views: Vec<_> = adapters.iter().map(|(a, _, _)| matrix(a)).collect();
+    let a = ndarray::concatenate(Axis(1), &a_views).unwrap();
+    let xa = backend.gemm(x, &a.view());
+
+    let mut dyb = Array2::zeros(xa.dim());
+    let mut grad_b = Vec::with_capacity(adapters.len());
+    let mut start = 0;
+    for ((_, b, scaling), dy) in adapters.iter().zip(dys) {
+        let end = start + b.data.shape()[0];
+        let dy_lora = dy * *scaling;
+        grad_b.push(backend.gemm(&xa.slice(s![.., start..end]).t(), &dy_lora.view()));
+        dyb.slice_mut(s![.., start..end]).assign(&backend.gemm(&dy_lora.view(), &matrix(b).t()));
+        start = end;
+    }
+    let grad_a = backend.gemm(&x.t(), &dyb.view());
+    grad_x += &backend.gemm(&dyb.view(), &a.t());
+
+    let mut start = 0;
+    let grads = grad_b
+        .into_iter()
+        .map(|grad_b| {
+            let end = start + grad_b.nrows();
+            let grad_a = grad_a.slice(s![.., start..end]).to_owned();
+            start = end;
+            (grad_a, grad_b)
+        })
+        .collect();
+    (grad_x, grads)
+}
+
+// `scaling * A B`, shaped like the base weight.
+fn lora_delta(a: &Tensor, b: &Tensor, scaling: f32) -> Array2<f32> {
+    current_backend().gemm(&matrix(a), &matrix(b)) * scaling
+}
+
+// Replaces `weight` with `merged`, quantized again with the same type if
+// `weight` was quantized and `requantize` is set. A quantized weight is
+// returned so that `unmerge_from` can restore it exactly.
+fn merge_into<T: Element>(weight: &mut Weight<T>, merged: Array2<f32>, requantize: bool) -> Option<Weight<T>> {
+    let original = std::mem::replace(weight, Weight::Dense(Tensor::new(merged.into_dyn()).to_dtype()));
+    match original {
+        Weight::Quantized(quantized) => {
+            if requantize {
+                weight.quantize(quantized.qtype());
+            }
+            Some(Weight::Quantized(quantized))
+        }
+        Weight::Dense(_) => None,
+    }
+}
+
+// Undoes `merge_into`: restores the original quantized weight, or recovers a
+// dense one from the merged weight with `unmerged`.
+fn unmerge_from<T: Element>(
+    weight: &mut Weight<T>,
+    original: Option<Weight<T>>,
+    unmerged: impl FnOnce(&ArrayView2<f32>) -> Array2<f32>,
+) {
+    *weight = match original {
+        Some(original) => original,
+        None => Weight::Dense(Tensor::new(with_base(weight, unmerged).into_dyn()).to_dtype()),
+    };
+}
+
+// Calls `f(start, rows)` on blocks of rows of DoRA's adapted weight
+// `W + scaling * A B`, in order. A quantized weight is dequantized a block at a
+// time; a dense one is a single block.
+fn for_each_adapted_block<T: Element>(
+    w: &Weight<T>,
+    a: &ArrayView2<f32>,
+    b: &ArrayView2<f32>,
+    scaling: f32,
+    mut f: impl FnMut(usize, Array2<f32>),
+) {
+    let backend = current_backend();
+    let mut adapt = |start: usize, rows: ArrayView2<f32>| {
+        let a_rows = a.slice(s![start..start + rows.nrows(), ..]);
+        f(start, &rows + &(backend.gemm(&a_rows, b) * scaling));
+    };
+    match w {
+        Weight::Quantized(quantized) => quantized.for_each_row_block(adapt),
+        Weight::Dense(_) => with_base(w, |w| adapt(0, w.view())),
+    }
+}
+
+// The norms of the output columns of DoRA's adapted weight.
+fn dora_norm<T: Element>(w: &Weight<T>, a: &Tensor, b: &Tensor, scaling: f32) -> Array1<f32> {
+    let mut squares = Array1::<f32>::zeros(b.data.shape()[1]);
+    for_each_adapted_block(w, &matrix(a), &matrix(b), scaling, |_, adapted| {
+        squares += &(&adapted * &adapted).sum_axis(Axis(0));
+    });
+    squares.mapv_into(f32::sqrt)
+}
+
+fn column_norm(w: &Array2<f32>) -> Array1<f32> {
+    w.map_axis(Axis(0), |column| column.dot(&column).sqrt())
+}
+
+// Gradients (A, B, magnitude) of DoRA's column scale `magnitude / norm` given
+// its gradient `grad`; the norm is differentiated too, recomputing the adapted
+// weight a block of rows at a time.
+fn dora_scale_backward<T: Element>(
+    w: &Weight<T>,
+    norm: &Array1<f32>,
+    magnitude: &ArrayView1<f32>,
+    a: &ArrayView2<f32>,
+    b: &ArrayView2<f32>,
+    scaling: f32,
+    grad: &ArrayView1<f32>,
+) -> (Array2<f32>, Array2<f32>, Array1<f32>) {
+    let backend = current_backend();
+    let grad_magnitude = grad / norm;
+    // d(m / |v|) / dv = -m v / |v|^3, column by column.
+    let coefficient = -&grad_magnitude * magnitude / (norm * norm) * scaling;
+    let (mut grad_a, mut grad_b) = (Array2::zeros(a.dim()), Array2::zeros(b.dim()));
+    for_each_adapted_block(w, a, b, scaling, |start, adapted| {
+        let grad_delta = adapted * &coefficient;
+        let end = start + grad_delta.nrows();
+        backend.gemm_into(&grad_delta.view(), &b.t(), grad_a.slice_mut(s![start..end, ..]));
+        grad_b += &backend.gemm(&a.slice(s![start..end, ..]).t(), &grad_delta.view());
+    });
+    (grad_a, grad_b, grad_magnitude)
+}
+
+// DoRA's scale of each output column, `magnitude / |W + scaling * A B|`, on
+// the tape for A, B and the magnitude.
+fn dora_scale<T: Element>(w: &Weight<T>, adapter: &LoraAdapter, magnitude: &Tensor) -> Tensor {
+    let (a, b, scaling) = (adapter.lora_a.data.clone(), adapter.lora_b.data.clone(), adapter.scaling);
+    let norm = dora_norm(w, &adapter.lora_a, &adapter.lora_b, scaling);
+    let w = w.clone();
+    let m = magnitude.data.clone().into_dimensionality::<Ix1>().unwrap();
+    let result = (&m / &norm).into_dyn();
+
+    Tensor::from_op(result, &[&adapter.lora_a, &adapter.lora_b, magnitude], move |grad| {
+        let (a, b) = (a.view().into_dimensionality::<Ix2>().unwrap(), b.view().into_dimensionality::<Ix2>().unwrap());
+        let grad = grad.view().into_dimensionality::<Ix1>().unwrap();
+        let (grad_a, grad_b, grad_m) = dora_scale_backward(&w, &norm, &m.view(), &a, &b, scaling, &grad);
+        vec![Some(grad_a.into_dyn()), Some(grad_b.into_dyn()), Some(grad_m.into_dyn())]
+    })
+}
+
+// Adapters are saved as `lora_a_<name>` and `lora_b_<name>`, with their
+// scaling in the `scaling_<name>` metadata entry.
+fn save_adapters(adapters: [(&str, &Tensor, &Tensor); 3], scaling: [f32; 3]) -> Model {
+    let mut model = Model::new();
+    for ((name, a, b), scaling) in adapters.into_iter().zip(scaling) {
+        model.insert(&format!("lora_a_{name}"), a);
+        model.insert(&format!("lora_b_{name}"), b);
+        model.insert_metadata(&format!("scaling_{name}"), &scaling.to_string());
+    }
+    model
+}
+
+fn get_tensor(model: &Model, name: &str) -> io::Result<Tensor> {
+    model.get(name).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("missing tensor `{name}`")))
+}
+
+// The scaling saved under `key` for the adapter `name`, if any.
+fn get_scaling(model: &Model, key: &str, name: &str) -> io::Result<Option<f32>> {
+    let Some(value) = model.metadata(key) else {
+        return Ok(None);
+    };
+    let scaling = value.parse().map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("invalid scaling {value:?} for `{name}`"))
+    })?;
+    Ok(Some(scaling))
+}
+
+// A missing scaling entry keeps the current value.
+fn load_adapters(
+    model: &Model,
+    adapters: [(&str, &mut Tensor, &mut Tensor); 3],
+    scaling: &mut [f32; 3],
+) -> io::Result<()> {
+    for ((name, a, b), scaling) in adapters.into_iter().zip(scaling) {
+        *a = get_tensor(model, &format!("lora_a_{name}"))?;
+        *b = get_tensor(model, &format!("lora_b_{name}"))?;
+        if let Some(value) = get_scaling(model, &format!("scaling_{name}"), name)? {
+            *scaling = value;
+        }
+    }
+    Ok(())
+}
+
+fn merged_error(op: &'static str) -> TensorError {
+    TensorError::InvalidArgument {
+        op,
+        message: "the adapters are merged into the base weights".into(),
+    }
+}
+
+fn saved_input<'a>(saved: &'a Option<SavedInput>, op: &'static str) -> Result<&'a SavedInput, TensorError> {
+    saved.as_ref().ok_or_else(|| TensorError::InvalidArgument {
+        op,
+        message: "backward called before forward_for_backward".into(),
+    })
+}
+
+// Gradients from `LoraMlp::backward`, shaped like the input and the adapters.
+#[derive(Debug, Clone)]
+pub struct LoraMlpGrad {
+    pub input: ArrayD<f32>,
+    pub lora_a_gate: ArrayD<f32>,
+    pub lora_b_gate: ArrayD<f32>,
+    pub lora_a_up: ArrayD<f32>,
+    pub lora_b_up: ArrayD<f32>,
+    pub lora_a_down: ArrayD<f32>,
+    pub lora_b_down: ArrayD<f32>,
+}
+
+// Gradients from `LoraQkv::backward`, shaped like the input and the adapters.
+#[derive(Debug, Clone)]
+pub struct LoraQkvGrad {
+    pub input: ArrayD<f32>,
+    pub lora_a_q: ArrayD<f32>,
+    pub lora_b_q: ArrayD<f32>,
+    pub lora_a_k: ArrayD<f32>,
+    pub lora_b_k: ArrayD<f32>,
+    pub lora_a_v: ArrayD<f32>,
+    pub lora_b_v: ArrayD<f32>,
+}
+
+// One low-rank adapter: the update `scaling * drop(x) A B`, with A
+// [in_features, rank] and B [rank, out_features]. With DoRA, the adapted
+// output is also rescaled column by column to `magnitude / |W + scaling * A B|`,
+// so that the magnitude and the direction of each column are learned apart.
+#[derive(Debug, Clone)]
+pub struct LoraAdapter {
+    pub lora_a: Tensor,
+    pub lora_b: Tensor,
+    pub scaling: f32,
+    pub dropout: f32,
+    // DoRA's [out_features] magnitude vector; `None` for plain LoRA.
+    pub magnitude: Option<Tensor>,
+}
+
+impl LoraAdapter {
+    // Fresh (see `init_lora`), with a scaling of one and no dropout.
+    pub fn new(in_features: usize, out_features: usize, rank: usize) -> Self {
+        let (lora_a, lora_b) = init_lora(in_features, out_features, rank);
+        LoraAdapter {
+            lora_a,
+            lora_b,
+            scaling: 1.0,
+            dropout: 0.0,
+            magnitude: None,
+        }
+    }
+
+    pub fn with_lora_alpha(mut self, alpha: f32, use_rslora: bool) -> Self {
+        self.scaling = lora_scaling(alpha, self.rank(), use_rslora);
+        self
+    }
+
+    pub fn with_dropout(mut self, p: f32) -> Self {
+        self.dropout = p;
+        self
+    }
+
+    // Turns the adapter into DoRA, starting each column's magnitude at its norm
+    // in `weight` plus the current update, so that the output is unchanged. Set
+    // the scaling first.
+    pub fn with_dora<T: Element>(mut self, weight: &Weight<T>) -> Self {
+        let norm = dora_norm(weight, &self.lora_a, &self.lora_b, self.scaling);
+        self.magnitude = Some(Tensor::new(norm.into_dyn()));
+        self
+    }
+
+    pub fn rank(&self) -> usize {
+        self.lora_a.data.shape()[1]
+    }
+
+    pub fn is_dora(&self) -> bool {
+        self.magnitude.is_some()
+    }
+
+    // Saves the adapter as `<prefix>.lora_a`, `<prefix>.lora_b` and, with DoRA,
+    // `<prefix>.magnitude`, with its scaling in the `<prefix>.scaling` entry.
+    pub fn save_into(&self, model: &mut Model, prefix: &str) {
+        model.insert(&format!("{prefix}.lora_a"), &self.lora_a);
+        model.insert(&format!("{prefix}.lora_b"), &self.lora_b);
+        if let Some(magnitude) = &self.magnitude {
+            model.insert(&format!("{prefix}.magnitude"), magnitude);
+        }
+        model.insert_metadata(&format!("{prefix}.scaling"), &self.scaling.to_string());
+    }
+
+    // The adapter saved by `save_into`, which is DoRA if it has a magnitude,
+    // without dropout; a missing scaling is one.
+    pub fn load(model: &Model, prefix: &str) -> io::Result<Self> {
+        Ok(LoraAdapter {
+            lora_a: get_tensor(model, &format!("{prefix}.lora_a"))?,
+            lora_b: get_tensor(model, &format!("{prefix}.lora_b"))?,
+            scaling: get_scaling(model, &format!("{prefix}.scaling"), prefix)?.unwrap_or(1.0),
+            dropout: 0.0,
+            magnitude: model.get(&format!("{prefix}.magnitude")),
+        })
+    }
+
+    // Replaces the adapter with the one saved by `save_into`, keeping the
+    // dropout (and the scaling if none was saved). The tensors stay trainable
+    // if they were.
+    pub fn load_from(&mut self, model: &Model, prefix: &str) -> io::Result<()> {
+        let trainable = self.lora_a.requires_grad();
+        let scaling = self.scaling;
+        let dropout = self.dropout;
+        *self = LoraAdapter::load(model, prefix)?;
+        self.dropout = dropout;
+        if model.metadata(&format!("{prefix}.scaling")).is_none() {
+            self.scaling = scaling;
+        }
+        for tensor in [Some(&mut self.lora_a), Some(&mut self.lora_b), self.magnitude.as_mut()].into_iter().flatten() {
+            tensor.set_requires_grad(trainable);
+        }
+        Ok(())
+    }
+}
+
+// Gradients from `LoraLinear::backward`.
+#[derive(Debug, Clone)]
+pub struct LoraLinearGrad {
+    pub input: ArrayD<f32>,
+    pub lora_a: ArrayD<f32>,
+    pub lora_b: ArrayD<f32>,
+    // Only with DoRA.
+    pub magnitude: Option<ArrayD<f32>>,
+}
+
+// The name `LoraLinear::adapter` goes by among the named adapters, as in PEFT.
+pub const DEFAULT_ADAPTER: &str = "default";
+
+// A frozen [in_features, out_features] projection, dense in `T` or quantized,
+// with an optional LoRA adapter: `x W + scaling * drop(x) A B`, rescaled by
+// column with DoRA. Without an adapter it is a plain `x W`.
+//
+// More adapters can share the weight under other names (see `insert_adapter`).
+// `forward` applies the active ones, each adding its weighted change of the
+// output; `forward_routed` picks one adapter per row instead.
+#[derive(Clone)]
+pub struct LoraLinear<T: Element = f32> {
+    pub weight: Weight<T>,
+    // The `DEFAULT_ADAPTER`.
+    pub adapter: Option<LoraAdapter>,
+    named: BTreeMap<String, LoraAdapter>,
+    // Only the default adapter at first.
+    active: Vec<(String, f32)>,
+    training: bool,
+    merged: bool,
+    // The quantized weight the adapters were merged into.
+    unmerged: Option<Weight<T>>,
+    // The adapters `merge` folded in, with their weights and DoRA column
+    // scales, which `unmerge` takes back out.
+    merged_with: Vec<(String, f32, Option<Array1<f32>>)>,
+    saved: RefCell<Option<SavedInput>>,
+}
+
+impl<T: Element> From<Weight<T>> for LoraLinear<T> {
+    fn from(weight: Weight<T>) -> Self {
+        LoraLinear::new(weight)
+    }
+}
+
+impl<T: Element> From<Tensor<T>> for LoraLinear<T> {
+    fn from(tensor: Tensor<T>) -> Self {
+        LoraLinear::new(tensor)
+    }
+}
+
+impl<T: Element> From<QuantizedTensor> for LoraLinear<T> {
+    fn from(tensor: QuantizedTensor) -> Self {
+        LoraLinear::new(tensor)
+    }
+}
+
+impl<T: Element> LoraLinear<T> {
+    pub fn new(weight: impl Into<Weight<T>>) -> Self {
+        LoraLinear {
+            weight: weight.into(),
+            adapter: None,
+            named: BTreeMap::new(),
+            active: vec![(DEFAULT_ADAPTER.to_string(), 1.0)],
+            training: true,
+            merged: false,
+            unmerged: None,
+            merged_with: Vec::new(),
+            saved: RefCell::new(None),
+        }
+    }
+
+    pub fn with_adapter(mut self, adapter: LoraAdapter) -> Self {
+        self.adapter = Some(adapter);
+        self
+    }
+
+    // Adds a fresh rank-`rank` adapter (see `LoraAdapter::new`).
+    pub fn with_rank(self, rank: usize) -> Self {
+        let adapter = LoraAdapter::new(self.in_features(), self.out_features(), rank);
+        self.with_adapter(adapter)
+    }
+
+    pub fn in_features(&self) -> usize {
+        self.weight.shape()[0]
+    }
+
+    pub fn out_features(&self) -> usize {
+        self.weight.shape()[1]
+    }
+
+    // Adds or replaces the adapter `name`; it only applies once it is active.
+    pub fn insert_adapter(&mut self, name: &str, adapter: LoraAdapter) {
+        if name == DEFAULT_ADAPTER {
+            self.adapter = Some(adapter);
+        } else {
+            self.named.insert(name.to_string(), adapter);
+        }
+    }
+
+    pub fn remove_adapter(&mut self, name: &str) -> Option<LoraAdapter> {
+        if name == DEFAULT_ADAPTER {
+            self.adapter.take()
+        } else {
+            self.named.remove(name)
+        }
+    }
+
+    pub fn get_adapter(&self, name: &str) -> Option<&LoraAdapter> {
+        if name == DEFAULT_ADAPTER {
+            self.adapter.as_ref()
+        } else {
+            self.named.get(name)
+        }
+    }
+
+    pub fn get_adapter_mut(&mut self, name: &str) -> Option<&mut LoraAdapter> {
+        if name == DEFAULT_ADAPTER {
+            self.adapter.as_mut()
+        } else {
+            self.named.get_mut(name)
+        }
+    }
+
+    // Every adapter, the default one first and the others by name.
+    pub fn adapters(&self) -> Vec<(&str, &LoraAdapter)> {
+        let default = self.adapter.as_ref().map(|adapter| (DEFAULT_ADAPTER, adapter));
+        default.into_iter().chain(self.named.iter().map(|(name, adapter)| (name.as_str(), adapter))).collect()
+    }
+
+    // The adapters `forward` applies, each with the weight of its change to the
+    // output; names this projection has no adapter for are skipped. An empty
+    // list runs the bare weight.
+    pub fn set_active_adapters(&mut self, active: &[(&str, f32)]) {
+        self.active = active.iter().map(|&(name, weight)| (name.to_string(), weight)).collect();
+    }
+
+    pub fn active_adapters(&self) -> &[(String, f32)] {
+        &self.active
+    }
+
+    fn active(&self) -> Vec<(&str, &LoraAdapter, f32)> {
+        self.active
+            .iter()
+            .filter_map(|(name, weight)| Some((name.as_str(), self.get_adapter(name)?, *weight)))
+            .collect()
+    }
+
+    // Adapter dropout only applies in training mode, the default.
+    pub fn train(&mut self) {
+        self.training = true;
+    }
+
+    pub fn eval(&mut self) {
+        self.training = false;
+    }
+
+    pub fn is_training(&self) -> bool {
+        self.training
+    }
+
+    // Folds the active adapters into the weight, `W + scaling * A B` for a
+    // single adapter (times DoRA's column scale), so that forward is a single
+    // matmul (for inference or a 16-bit export). A quantized weight is
+    // dequantized, and quantized again if `requantize` is set. Does nothing
+    // without an active adapter or if it is already merged.
+    pub fn merge(&mut self, requantize: bool) {
+        let active = self.active();
+        if self.merged || active.is_empty() {
+            return;
+        }
+        let (merged, merged_with) = with_base(&self.weight, |w| {
+            let merged_with: Vec<_> = active
+                .iter()
+                .map(|&(name, adapter, weight)| {
+                    let scale = adapter.magnitude.as_ref().map(|magnitude| {
+                        let adapted = w + &lora_delta(&adapter.lora_a, &adapter.lora_b, adapter.scaling);
+                        &magnitude.data.view().into_dimensionality::<Ix1>().unwrap() / &column_norm(&adapted)
+                    });
+                    (name.to_string(), weight, scale)
+                })
+                .collect();
+            let (factor, offset) = self.merged_change(&merged_with, w.dim());
+            (w * &factor + offset, merged_with)
+        });
+        self.merged_with = merged_with;
+        self.unmerged = merge_into(&mut self.weight, merged, requantize);
+        self.merged = true;
+    }
+
+    // Restores the weight from before `merge`; the merged adapters must not
+    // have changed in between.
+    pub fn unmerge(&mut self) {
+        if !self.merged {
+            return;
+        }
+        let merged_with = std::mem::take(&mut self.merged_with);
+        let original = self.unmerged.take();
+        let (factor, offset) = self.merged_change(&merged_with, (self.in_features(), self.out_features()));
+        unmerge_from(&mut self.weight, original, |w| (w - &offset) / &factor);
+        self.merged = false;
+    }
+
+    // Merging turns W into `W * factor + offset`, by column: each adapter adds
+    // `weight * (c (W + delta) - W)`, with c its DoRA column scale (1 without).
+    fn merged_change(
+        &self,
+        merged_with: &[(String, f32, Option<Array1<f32>>)],
+        (rows, cols): (usize, usize),
+    ) -> (Array1<f32>, Array2<f32>) {
+        let mut factor = Array1::ones(cols);
+        let mut offset = Array2::zeros((rows, cols));
+        for (name, weight, scale) in merged_with {
+            let Some(adapter) = self.get_adapter(name) else {
+                continue;
+            };
+            let delta = lora_delta(&adapter.lora_a, &adapter.lora_b, adapter.scaling) * *weight;
+            match scale {
+                Some(scale) => {
+                    factor += &((scale - 1.0) * *weight);
+                    offset += &(delta * scale);
+                }
+                None => offset += &delta,
+            }
+        }
+        (factor, offset)
+    }
+
+    pub fn is_merged(&self) -> bool {
+        self.merged
+    }
+
+    // Merged adapters are part of the weight, so they are not applied again.
+    pub fn forward(&self, x: &Tensor) -> Tensor {
+        self.saved.replace(None);
+        let base = x.matmul(&self.weight);
+        if self.merged {
+            return base;
+        }
+        match self.active()[..] {
+            [] => base,
+            [(_, adapter, 1.0)] => self.adapted(x, &base, adapter),
+            ref active => active.iter().fold(base.clone(), |out, &(_, adapter, weight)| {
+                out.add(&self.change(x, &base, adapter).scale(weight))
+            }),
+        }
+    }
+
+    // `forward` that also keeps `x` for `backward` when a single adapter is
+    // active at weight one. The tape never needs it, so `forward` does not.
+    pub fn forward_for_backward(&self, x: &Tensor) -> Tensor {
+        let saved = match self.active()[..] {
+            [(_, adapter, 1.0)] if !self.merged => Some(SavedInput::new(x, adapter.dropout, self.training)),
+            _ => None,
+        };
+        let out = self.forward(x);
+        self.saved.replace(saved);
+        out
+    }
+
+    // `forward` of several projections of the same input, such as q, k and v,
+    // fused (see `fused_lora`) when each one is merged or has at most a single
+    // plain active adapter at weight one without dropout in training; other
+    // combinations run each projection's `forward`. Nothing is kept for
+    // `backward` either way.
+    pub fn forward_fused(projections: &[&Self], x: &Tensor) -> Vec<Tensor> {
+        let fused: Option<Vec<_>> = projections.iter().map(|projection| projection.fused_adapter()).collect();
+        let Some(adapters) = fused else {
+            return projections.iter().map(|projection| projection.forward(x)).collect();
+        };
+        let fused: Vec<_> = projections
+            .iter()
+            .zip(&adapters)
+            .map(|(projection, adapter)| {
+                projection.saved.replace(None);
+                let adapter = adapter.map(|adapter| (&adapter.lora_a, &adapter.lora_b, adapter.scaling));
+                (&projection.weight, adapter)
+            })
+            .collect();
+        fused_lora(x, &fused, 0.0, false)
+    }
+
+    // What `forward_fused` needs of this projection: `Some(None)` for a bare
+    // matmul and `Some(Some(adapter))` for a plain adapter; `None` if it cannot
+    // be fused.
+    fn fused_adapter(&self) -> Option<Option<&LoraAdapter>> {
+        if self.merged {
+            return Some(None);
+        }
+        match self.active()[..] {
+            [] => Some(None),
+            [(_, adapter, 1.0)] if adapter.is_dora() || (self.training && adapter.dropout > 0.0) => None,
+            [(_, adapter, 1.0)] => Some(Some(adapter)),
+            _ => None,
+        }
+    }
+
+    // Mixed-adapter batches: row `i` of `x` (as [rows, in_features]) goes
+    // through the adapter `routes[i]`, whether active or not, and rows routed
+    // to `None` or to an adapter this projection lacks get the bare weight.
+    pub fn forward_routed(&self, x: &Tensor, routes: &[Option<&str>]) -> Tensor {
+        self.try_forward_routed(x, routes).unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    pub fn try_forward_routed(&self, x: &Tensor, routes: &[Option<&str>]) -> Result<Tensor, TensorError> {
+        if self.merged {
+            return Err(merged_error("lora_linear_forward_routed"));
+        }
+        let n_rows = x.data.len() / x.data.shape().last().copied().unwrap_or(1).max(1);
+        if routes.len() != n_rows {
+            return Err(TensorError::ShapeMismatch {
+                op: "lora_linear_forward_routed",
+                expected: vec![n_rows],
+                actual: vec![routes.len()],
+            });
+        }
+        self.saved.replace(None);
+        let base = x.matmul(&self.weight);
+        let mut names: Vec<&str> = routes.iter().flatten().copied().collect();
+        names.sort_unstable();
+        names.dedup();
+        let mut mask_shape = x.data.shape().to_vec();
+        *mask_shape.last_mut().unwrap() = 1;
+        let mut out = base.clone();
+        for name in names {
+            let Some(adapter) = self.get_adapter(name) else {
+                continue;
+            };
+            let rows = routes.iter().map(|route| if *route == Some(name) { 1.0 } else { 0.0 }).collect();
+            let rows = Tensor::new(ArrayD::from_shape_vec(mask_shape.clone(), rows).unwrap());
+            out = out.add(&self.change(x, &base, adapter).mul(&rows));
+        }
+        Ok(out)
+    }
+
+    // `x W` plus the adapter, given `base = x W`.
+    fn adapted(&self, x: &Tensor, base: &Tensor, adapter: &LoraAdapter) -> Tensor {
+        let update = adapter_input(x, adapter.dropout, self.training)
+            .matmul(&adapter.lora_a)
+            .matmul(&adapter.lora_b)
+            .scale(adapter.scaling);
+        let out = base.add(&update);
+        match &adapter.magnitude {
+            Some(magnitude) => out.mul(&dora_scale(&self.weight, adapter, magnitude)),
+            None => out,
+        }
+    }
+
+    // What the adapter adds to `base = x W`.
+    fn change(&self, x: &Tensor, base: &Tensor, adapter: &LoraAdapter) -> Tensor {
+        if adapter.is_dora() {
+            return self.adapted(x, base, adapter).add(&base.scale(-1.0));
+        }
+        adapter_input(x, adapter.dropout, self.training)
+            .matmul(&adapter.lora_a)
+            .matmul(&adapter.lora_b)
+            .scale(adapter.scaling)
+    }
+
+    // The fused backward of the last `forward_for_backward`: gradients of the
+    // input and the active adapter, never of the frozen weight. Only a single
+    // active adapter at weight one can be differentiated.
+    pub fn backward(&self, grad_out: &ArrayD<f32>) -> LoraLinearGrad {
+        self.try_backward(grad_out).unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    pub fn try_backward(&self, grad_out: &ArrayD<f32>) -> Result<LoraLinearGrad, TensorError> {
+        let invalid = |message: &str| TensorError::InvalidArgument {
+            op: "lora_linear_backward",
+            message: message.into(),
+        };
+        let adapter = match self.active()[..] {
+            [] => return Err(invalid("there is no adapter to differentiate")),
+            [(_, adapter, 1.0)] => adapter,
+            _ => return Err(invalid("only a single active adapter at weight 1 can be differentiated")),
+        };
+        if self.merged {
+            return Err(merged_error("lora_linear_backward"));
+        }
+        let saved = self.saved.borrow();
+        let saved = saved_input(&saved, "lora_linear_backward")?;
+        let mut expected = saved.x.shape().to_vec();
+        *expected.last_mut().unwrap() = self.out_features();
+        if grad_out.shape() != expected {
+            return Err(TensorError::ShapeMismatch {
+                op: "lora_linear_backward",
+                expected,
+                actual: grad_out.shape().to_vec(),
+            });
+        }
+
+        let masks = saved.masks(&[saved.x.shape()]);
+        let (x, mask, dy) = (rows(&saved.x), masks[0].as_ref(), rows(grad_out));
+        let (w, a, b, scaling) = (&self.weight, &adapter.lora_a, &adapter.lora_b, adapter.scaling);
+        let Some(magnitude) = &adapter.magnitude else {
+            let (grad_x, lora_a, lora_b) = lora_backward(&x.view(), mask, w, a, b, scaling, &dy.view());
+            return Ok(LoraLinearGrad {
+                input: grad_x.into_dyn().into_shape(saved.x.shape()).unwrap(),
+                lora_a: lora_a.into_dyn(),
+                lora_b: lora_b.into_dyn(),
+                magnitude: None,
+            });
+        };
+
+        // DoRA: `y = z * scale` by column, with `z` the plain LoRA output.
+        let norm = dora_norm(w, a, b, scaling);
+        let magnitude = magnitude.data.view().into_dimensionality::<Ix1>().unwrap();
+        let scale = &magnitude / &norm;
+        let z = lora_forward(&x.view(), mask, w, a, b, scaling);
+        let grad_scale = (&dy * &z).sum_axis(Axis(0));
+        let (grad_x, mut lora_a, mut lora_b) = lora_backward(&x.view(), mask, w, a, b, scaling, &(&dy * &scale).view());
+        let (grad_a, grad_b, grad_magnitude) =
+            dora_scale_backward(w, &norm, &magnitude, &matrix(a), &matrix(b), scaling, &grad_scale.view());
+        lora_a += &grad_a;
+        lora_b += &grad_b;
+        Ok(LoraLinearGrad {
+            input: grad_x.into_dyn().into_shape(saved.x.shape()).unwrap(),
+            lora_a: lora_a.into_dyn(),
+            lora_b: lora_b.into_dyn(),
+            magnitude: Some(grad_magnitude.into_dyn()),
+        })
+    }
+}
+
+// The frozen base weights may be dense or quantized (QLoRA); the LoRA
+// adapters are always dense f32.
 pub struct LoraMlp {
-    gate_w: Tensor,
-    up_w: Tensor,
-    down_w: Tensor,
+    gate_w: Weight,
+    up_w: Weight,
+    down_w: Weight,
     lora_a_gate: Tensor,
     lora_b_gate: Tensor,
     lora_a_up: Tensor,
     lora_b_up: Tensor,
     lora_a_down: Tensor,
     lora_b_down: Tensor,
+    // Of each adapter's update, in gate, up, down order.
+    scaling: [f32; 3],
+    lora_dropout: f32,
+    training: bool,
+    merged: bool,
+    // The quantized base weights the adapters were merged into.
+    unmerged: [Option<Weight>; 3],
+    saved: RefCell<Option<SavedInput>>,
 }
 
 impl LoraMlp {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        gate_w: Tensor,
-        up_w: Tensor,
-        down_w: Tensor,
+        gate_w: impl Into<Weight>,
+        up_w: impl Into<Weight>,
+        down_w: impl Into<Weight>,
         lora_a_gate: Tensor,
         lora_b_gate: Tensor,
         lora_a_up: Tensor,
@@ -26,6 +1095,31 @@ impl LoraMlp {
         lora_b_down: Tensor,
     ) -> Self {
         LoraMlp {
+            gate_w: gate_w.into(),
+            up_w: up_w.into(),
+            down_w: down_w.into(),
+            lora_a_gate,
+            lora_b_gate,
+            lora_a_up,
+            lora_b_up,
+            lora_a_down,
+            lora_b_down,
+            scaling: [1.0; 3],
+            lora_dropout: 0.0,
+            training: true,
+            merged: false,
+            unmerged: Default::default(),
+            saved: RefCell::new(None),
+        }
+    }
+
+    // Fresh adapters of the given rank on every projection (see `init_lora`).
+    pub fn with_rank(gate_w: impl Into<Weight>, up_w: impl Into<Weight>, down_w: impl Into<Weight>, rank: usize) -> Self {
+        let (gate_w, up_w, down_w) = (gate_w.into(), up_w.into(), down_w.into());
+        let (lora_a_gate, lora_b_gate) = init_lora_for(&gate_w, rank);
+        let (lora_a_up, lora_b_up) = init_lora_for(&up_w, rank);
+        let (lora_a_down, lora_b_down) = init_lora_for(&down_w, rank);
+        Self::new(
             gate_w,
             up_w,
             down_w,
@@ -35,18 +1129,71 @@ impl LoraMlp {
             lora_b_up,
             lora_a_down,
             lora_b_down,
-        }
+        )
+    }
+
+    pub fn with_lora_dropout(mut self, p: f32) -> Self {
+        self.lora_dropout = p;
+        self
+    }
+
+    pub fn lora_dropout(&self) -> f32 {
+        self.lora_dropout
+    }
+
+    // Scales every adapter by `lora_alpha` over its rank (see `lora_scaling`).
+    pub fn with_lora_alpha(mut self, alpha: f32, use_rslora: bool) -> Self {
+        let ranks = [&self.lora_a_gate, &self.lora_a_up, &self.lora_a_down].map(|a| a.data.shape()[1]);
+        self.scaling = ranks.map(|rank| lora_scaling(alpha, rank, use_rslora));
+        self
+    }
+
+    pub fn with_scaling(mut self, scaling: f32) -> Self {
+        self.scaling = [scaling; 3];
+        self
+    }
+
+    // In gate, up, down order.
+    pub fn scaling(&self) -> [f32; 3] {
+        self.scaling
+    }
+
+    // Adapter dropout only applies in training mode, the default.
+    pub fn train(&mut self) {
+        self.training = true;
+    }
+
+    pub fn eval(&mut self) {
+        self.training = false;
+    }
+
+    pub fn is_training(&self) -> bool {
+        self.training
     }
 
     pub fn forward(&self, x: &Tensor) -> Tensor {
+        self.saved.replace(None);
+        if self.merged {
+            let act = x.matmul(&self.gate_w).silu().mul(&x.matmul(&self.up_w));
+            return act.matmul(&self.down_w);
+        }
+
         // Gate path
         let gate_main = x.matmul(&self.gate_w);
-        let gate_lora = x.matmul(&self.lora_a_gate).matmul(&self.lora_b_gate);
+        let gate_lora = self
+            .adapter_input(x)
+            .matmul(&self.lora_a_gate)
+            .matmul(&self.lora_b_gate)
+            .scale(self.scaling[0]);
         let gate = gate_main.add(&gate_lora);
 
         // Up path
         let up_main = x.matmul(&self.up_w);
-        let up_lora = x.matmul(&self.lora_a_up).matmul(&self.lora_b_up);
+        let up_lora = self
+            .adapter_input(x)
+            .matmul(&self.lora_a_up)
+            .matmul(&self.lora_b_up)
+            .scale(self.scaling[1]);
         let up = up_main.add(&up_lora);
 
         // Activation
@@ -54,28 +1201,198 @@ impl LoraMlp {
 
         // Down path
         let down_main = act.matmul(&self.down_w);
-        let down_lora = act.matmul(&self.lora_a_down).matmul(&self.lora_b_down);
+        let down_lora = self
+            .adapter_input(&act)
+            .matmul(&self.lora_a_down)
+            .matmul(&self.lora_b_down)
+            .scale(self.scaling[2]);
         down_main.add(&down_lora)
     }
+
+    // `forward` that also keeps `x` for `backward`.
+    pub fn forward_for_backward(&self, x: &Tensor) -> Tensor {
+        let saved = (!self.merged).then(|| SavedInput::new(x, self.lora_dropout, self.training));
+        let out = self.forward(x);
+        self.saved.replace(saved);
+        out
+    }
+
+    // The fused backward of the last `forward_for_backward`: gradients of the
+    // input and the six adapter matrices only, never of the frozen base
+    // weights. SwiGLU's gate and up activations are recomputed from the saved
+    // input.
+    pub fn backward(&self, grad_out: &ArrayD<f32>) -> LoraMlpGrad {
+        self.try_backward(grad_out).unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    pub fn try_backward(&self, grad_out: &ArrayD<f32>) -> Result<LoraMlpGrad, TensorError> {
+        if self.merged {
+            return Err(merged_error("lora_mlp_backward"));
+        }
+        let saved = self.saved.borrow();
+        let saved = saved_input(&saved, "lora_mlp_backward")?;
+        let mut expected = saved.x.shape().to_vec();
+        *expected.last_mut().unwrap() = self.down_w.shape()[1];
+        if grad_out.shape() != expected {
+            return Err(TensorError::ShapeMismatch {
+                op: "lora_mlp_backward",
+                expected,
+                actual: grad_out.shape().to_vec(),
+            });
+        }
+
+        let x = rows(&saved.x);
+        let mut act_shape = saved.x.shape().to_vec();
+        *act_shape.last_mut().unwrap() = self.gate_w.shape()[1];
+        let masks = saved.masks(&[saved.x.shape(), saved.x.shape(), &act_shape]);
+        let (gate_mask, up_mask, down_mask) = (masks[0].as_ref(), masks[1].as_ref(), masks[2].as_ref());
+
+        let backend = current_backend();
+        let (gate_w, up_w) = (&self.gate_w, &self.up_w);
+        let gate = lora_forward(&x.view(), gate_mask, gate_w, &self.lora_a_gate, &self.lora_b_gate, self.scaling[0]);
+        let up = lora_forward(&x.view(), up_mask, up_w, &self.lora_a_up, &self.lora_b_up, self.scaling[1]);
+        let silu_gate = backend.silu(&gate.view().into_dyn()).into_dimensionality::<Ix2>().unwrap();
+        let act = &silu_gate * &up;
+
+        let (grad_act, lora_a_down, lora_b_down) = lora_backward(
+            &act.view(),
+            down_mask,
+            &self.down_w,
+            &self.lora_a_down,
+            &self.lora_b_down,
+            self.scaling[2],
+            &rows(grad_out).view(),
+        );
+        let grad_up = &grad_act * &silu_gate;
+        let grad_gate = backend.silu_backward(&gate.view().into_dyn(), &(&grad_act * &up).view().into_dyn());
+        let grad_gate = grad_gate.into_dimensionality::<Ix2>().unwrap();
+        let (grad_x_gate, lora_a_gate, lora_b_gate) = lora_backward(
+            &x.view(),
+            gate_mask,
+            &self.gate_w,
+            &self.lora_a_gate,
+            &self.lora_b_gate,
+            self.scaling[0],
+            &grad_gate.view(),
+        );
+        let (grad_x_up, lora_a_up, lora_b_up) = lora_backward(
+            &x.view(),
+            up_mask,
+            &self.up_w,
+            &self.lora_a_up,
+            &self.lora_b_up,
+            self.scaling[1],
+            &grad_up.view(),
+        );
+
+        Ok(LoraMlpGrad {
+            input: (grad_x_gate + grad_x_up).into_dyn().into_shape(saved.x.shape()).unwrap(),
+            lora_a_gate: lora_a_gate.into_dyn(),
+            lora_b_gate: lora_b_gate.into_dyn(),
+            lora_a_up: lora_a_up.into_dyn(),
+            lora_b_up: lora_b_up.into_dyn(),
+            lora_a_down: lora_a_down.into_dyn(),
+            lora_b_down: lora_b_down.into_dyn(),
+        })
+    }
+
+    fn adapter_input(&self, x: &Tensor) -> Tensor {
+        adapter_input(x, self.lora_dropout, self.training)
+    }
+
+    pub fn quantize_base(&mut self, qtype: QuantType) {
+        self.gate_w.quantize(qtype);
+        self.up_w.quantize(qtype);
+        self.down_w.quantize(qtype);
+    }
+
+    // Folds every adapter into its base weight (see `LoraLinear::merge`).
+    pub fn merge(&mut self, requantize: bool) {
+        if self.merged {
+            return;
+        }
+        let projections = [
+            (&mut self.gate_w, &self.lora_a_gate, &self.lora_b_gate),
+            (&mut self.up_w, &self.lora_a_up, &self.lora_b_up),
+            (&mut self.down_w, &self.lora_a_down, &self.lora_b_down),
+        ];
+        for ((projection, unmerged), scaling) in projections.into_iter().zip(&mut self.unmerged).zip(self.scaling) {
+            let (weight, a, b) = projection;
+            let merged = with_base(weight, |w| w + &lora_delta(a, b, scaling));
+            *unmerged = merge_into(weight, merged, requantize);
+        }
+        self.merged = true;
+    }
+
+    pub fn unmerge(&mut self) {
+        if !self.merged {
+            return;
+        }
+        let projections = [
+            (&mut self.gate_w, &self.lora_a_gate, &self.lora_b_gate),
+            (&mut self.up_w, &self.lora_a_up, &self.lora_b_up),
+            (&mut self.down_w, &self.lora_a_down, &self.lora_b_down),
+        ];
+        for ((projection, unmerged), scaling) in projections.into_iter().zip(&mut self.unmerged).zip(self.scaling) {
+            let (weight, a, b) = projection;
+            unmerge_from(weight, unmerged.take(), |w| w - &lora_delta(a, b, scaling));
+        }
+        self.merged = false;
+    }
+
+    pub fn is_merged(&self) -> bool {
+        self.merged
+    }
+
+    // The adapters and their scaling (see `save_adapters`); the frozen base
+    // weights are not included.
+    pub fn adapter_state_dict(&self) -> Model {
+        save_adapters(
+            [
+                ("gate", &self.lora_a_gate, &self.lora_b_gate),
+                ("up", &self.lora_a_up, &self.lora_b_up),
+                ("down", &self.lora_a_down, &self.lora_b_down),
+            ],
+            self.scaling,
+        )
+    }
+
+    pub fn load_adapter_state_dict(&mut self, model: &Model) -> io::Result<()> {
+        let adapters = [
+            ("gate", &mut self.lora_a_gate, &mut self.lora_b_gate),
+            ("up", &mut self.lora_a_up, &mut self.lora_b_up),
+            ("down", &mut self.lora_a_down, &mut self.lora_b_down),
+        ];
+        load_adapters(model, adapters, &mut self.scaling)
+    }
 }
 
 pub struct LoraQkv {
-    q_w: Tensor,
-    k_w: Tensor,
-    v_w: Tensor,
+    q_w: Weight,
+    k_w: Weight,
+    v_w: Weight,
     lora_a_q: Tensor,
     lora_b_q: Tensor,
     lora_a_k: Tensor,
     lora_b_k: Tensor,
     lora_a_v: Tensor,
     lora_b_v: Tensor,
+    // Of each adapter's update, in q, k, v order.
+    scaling: [f32; 3],
+    lora_dropout: f32,
+    training: bool,
+    merged: bool,
+    // The quantized base weights the adapters were merged into.
+    unmerged: [Option<Weight>; 3],
+    saved: RefCell<Option<SavedInput>>,
 }
 
 impl LoraQkv {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        q_w: Tensor,
-        k_w: Tensor,
-        v_w: Tensor,
+        q_w: impl Into<Weight>,
+        k_w: impl Into<Weight>,
+        v_w: impl Into<Weight>,
         lora_a_q: Tensor,
         lora_b_q: Tensor,
         lora_a_k: Tensor,
@@ -84,34 +1401,230 @@ impl LoraQkv {
         lora_b_v: Tensor,
     ) -> Self {
         LoraQkv {
-            q_w,
-            k_w,
-            v_w,
+            q_w: q_w.into(),
+            k_w: k_w.into(),
+            v_w: v_w.into(),
             lora_a_q,
             lora_b_q,
             lora_a_k,
             lora_b_k,
             lora_a_v,
             lora_b_v,
+            scaling: [1.0; 3],
+            lora_dropout: 0.0,
+            training: true,
+            merged: false,
+            unmerged: Default::default(),
+            saved: RefCell::new(None),
         }
     }
 
-    pub fn forward(&self, x: &Tensor) -> (Tensor, Tensor, Tensor) {
-        // Q path
-        let q_main = x.matmul(&self.q_w);
-        let q_lora = x.matmul(&self.lora_a_q).matmul(&self.lora_b_q);
-        let q = q_main.add(&q_lora);
+    // Fresh adapters of the given rank on every projection (see `init_lora`).
+    pub fn with_rank(q_w: impl Into<Weight>, k_w: impl Into<Weight>, v_w: impl Into<Weight>, rank: usize) -> Self {
+        let (q_w, k_w, v_w) = (q_w.into(), k_w.into(), v_w.into());
+        let (lora_a_q, lora_b_q) = init_lora_for(&q_w, rank);
+        let (lora_a_k, lora_b_k) = init_lora_for(&k_w, rank);
+        let (lora_a_v, lora_b_v) = init_lora_for(&v_w, rank);
+        Self::new(q_w, k_w, v_w, lora_a_q, lora_b_q, lora_a_k, lora_b_k, lora_a_v, lora_b_v)
+    }
 
-        // K path
-        let k_main = x.matmul(&self.k_w);
-        let k_lora = x.matmul(&self.lora_a_k).matmul(&self.lora_b_k);
-        let k = k_main.add(&k_lora);
+    pub fn with_lora_dropout(mut self, p: f32) -> Self {
+        self.lora_dropout = p;
+        self
+    }
 
-        // V path
-        let v_main = x.matmul(&self.v_w);
-        let v_lora = x.matmul(&self.lora_a_v).matmul(&self.lora_b_v);
-        let v = v_main.add(&v_lora);
+    pub fn lora_dropout(&self) -> f32 {
+        self.lora_dropout
+    }
 
+    // Scales every adapter by `lora_alpha` over its rank (see `lora_scaling`).
+    pub fn with_lora_alpha(mut self, alpha: f32, use_rslora: bool) -> Self {
+        let ranks = [&self.lora_a_q, &self.lora_a_k, &self.lora_a_v].map(|a| a.data.shape()[1]);
+        self.scaling = ranks.map(|rank| lora_scaling(alpha, rank, use_rslora));
+        self
+    }
+
+    pub fn with_scaling(mut self, scaling: f32) -> Self {
+        self.scaling = [scaling; 3];
+        self
+    }
+
+    // In q, k, v order.
+    pub fn scaling(&self) -> [f32; 3] {
+        self.scaling
+    }
+
+    // Adapter dropout only applies in training mode, the default.
+    pub fn train(&mut self) {
+        self.training = true;
+    }
+
+    pub fn eval(&mut self) {
+        self.training = false;
+    }
+
+    pub fn is_training(&self) -> bool {
+        self.training
+    }
+
+    // The three projections share their adapter A matmul (see `fused_lora`).
+    pub fn forward(&self, x: &Tensor) -> (Tensor, Tensor, Tensor) {
+        self.saved.replace(None);
+        let weights = [&self.q_w, &self.k_w, &self.v_w];
+        let outputs = if self.merged {
+            fused_lora(x, &weights.map(|w| (w, None)), 0.0, false)
+        } else {
+            let projections: Vec<_> = weights.into_iter().zip(self.adapters()).map(|(w, a)| (w, Some(a))).collect();
+            fused_lora(x, &projections, self.lora_dropout, self.training)
+        };
+        let [q, k, v]: [Tensor; 3] = outputs.try_into().unwrap();
         (q, k, v)
     }
+
+    // `forward` that also keeps `x` for `backward`.
+    pub fn forward_for_backward(&self, x: &Tensor) -> (Tensor, Tensor, Tensor) {
+        let saved = (!self.merged).then(|| SavedInput::new(x, self.lora_dropout, self.training));
+        let out = self.forward(x);
+        self.saved.replace(saved);
+        out
+    }
+
+    // The q, k and v adapters, each as (A, B, scaling).
+    fn adapters(&self) -> [FusedAdapter<'_>; 3] {
+        [
+            (&self.lora_a_q, &self.lora_b_q, self.scaling[0]),
+            (&self.lora_a_k, &self.lora_b_k, self.scaling[1]),
+            (&self.lora_a_v, &self.lora_b_v, self.scaling[2]),
+        ]
+    }
+
+    // The fused backward of the last `forward_for_backward`, as Unsloth's
+    // `LoRA_QKV`: the input gradient summed over the three projections and the
+    // gradients of the six adapter matrices, skipping the frozen `q_w`, `k_w`
+    // and `v_w`.
+    pub fn backward(&self, dq: &ArrayD<f32>, dk: &ArrayD<f32>, dv: &ArrayD<f32>) -> LoraQkvGrad {
+        self.try_backward(dq, dk, dv).unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    pub fn try_backward(
+        &self,
+        dq: &ArrayD<f32>,
+        dk: &ArrayD<f32>,
+        dv: &ArrayD<f32>,
+    ) -> Result<LoraQkvGrad, TensorError> {
+        if self.merged {
+            return Err(merged_error("lora_qkv_backward"));
+        }
+        let saved = self.saved.borrow();
+        let saved = saved_input(&saved, "lora_qkv_backward")?;
+        for (grad, w) in [(dq, &self.q_w), (dk, &self.k_w), (dv, &self.v_w)] {
+            let mut expected = saved.x.shape().to_vec();
+            *expected.last_mut().unwrap() = w.shape()[1];
+            if grad.shape() != expected {
+                return Err(TensorError::ShapeMismatch {
+                    op: "lora_qkv_backward",
+                    expected,
+                    actual: grad.shape().to_vec(),
+                });
+            }
+        }
+
+        let x = rows(&saved.x);
+        let shape = saved.x.shape();
+        let weights = [&self.q_w, &self.k_w, &self.v_w];
+        let dys = [rows(dq), rows(dk), rows(dv)];
+        let dys = [dys[0].view(), dys[1].view(), dys[2].view()];
+        let (grad_x, grads) = if saved.dropout == 0.0 {
+            fused_lora_backward(&x.view(), &weights, &self.adapters(), &dys)
+        } else {
+            // Each adapter saw its own mask, so the A matrices cannot be joined.
+            let masks = saved.masks(&[shape, shape, shape]);
+            let mut grad_x = Array2::zeros(x.dim());
+            let grads = (weights.into_iter().zip(self.adapters()).zip(masks).zip(dys))
+                .map(|(((w, (a, b, scaling)), mask), dy)| {
+                    let (grad, grad_a, grad_b) = lora_backward(&x.view(), mask.as_ref(), w, a, b, scaling, &dy);
+                    grad_x += &grad;
+                    (grad_a, grad_b)
+                })
+                .collect();
+            (grad_x, grads)
+        };
+        let [(lora_a_q, lora_b_q), (lora_a_k, lora_b_k), (lora_a_v, lora_b_v)]: [_; 3] = grads.try_into().unwrap();
+
+        Ok(LoraQkvGrad {
+            input: grad_x.into_dyn().into_shape(shape).unwrap(),
+            lora_a_q: lora_a_q.into_dyn(),
+            lora_b_q: lora_b_q.into_dyn(),
+            lora_a_k: lora_a_k.into_dyn(),
+            lora_b_k: lora_b_k.into_dyn(),
+            lora_a_v: lora_a_v.into_dyn(),
+            lora_b_v: lora_b_v.into_dyn(),
+        })
+    }
+
+    pub fn quantize_base(&mut self, qtype: QuantType) {
+        self.q_w.quantize(qtype);
+        self.k_w.quantize(qtype);
+        self.v_w.quantize(qtype);
+    }
+
+    // Folds every adapter into its base weight (see `LoraLinear::merge`).
+    pub fn merge(&mut self, requantize: bool) {
+        if self.merged {
+            return;
+        }
+        let projections = [
+            (&mut self.q_w, &self.lora_a_q, &self.lora_b_q),
+            (&mut self.k_w, &self.lora_a_k, &self.lora_b_k),
+            (&mut self.v_w, &self.lora_a_v, &self.lora_b_v),
+        ];
+        for ((projection, unmerged), scaling) in projections.into_iter().zip(&mut self.unmerged).zip(self.scaling) {
+            let (weight, a, b) = projection;
+            let merged = with_base(weight, |w| w + &lora_delta(a, b, scaling));
+            *unmerged = merge_into(weight, merged, requantize);
+        }
+        self.merged = true;
+    }
+
+    pub fn unmerge(&mut self) {
+        if !self.merged {
+            return;
+        }
+        let projections = [
+            (&mut self.q_w, &self.lora_a_q, &self.lora_b_q),
+            (&mut self.k_w, &self.lora_a_k, &self.lora_b_k),
+            (&mut self.v_w, &self.lora_a_v, &self.lora_b_v),
+        ];
+        for ((projection, unmerged), scaling) in projections.into_iter().zip(&mut self.unmerged).zip(self.scaling) {
+            let (weight, a, b) = projection;
+            unmerge_from(weight, unmerged.take(), |w| w - &lora_delta(a, b, scaling));
+        }
+        self.merged = false;
+    }
+
+    pub fn is_merged(&self) -> bool {
+        self.merged
+    }
+
+    // The adapters and their scaling (see `save_adapters`); the frozen base
+    // weights are not included.
+    pub fn adapter_state_dict(&self) -> Model {
+        save_adapters(
+            [
+                ("q", &self.lora_a_q, &self.lora_b_q),
+                ("k", &self.lora_a_k, &self.lora_b_k),
+                ("v", &self.lora_a_v, &self.lora_b_v),
+            ],
+            self.scaling,
+        )
+    }
+
+    pub fn load_adapter_state_dict(&mut self, model: &Model) -> io::Result<()> {
+        let adapters = [
+            ("q", &mut self.lora_a_q, &mut self.lora_b_q),
+            ("k", &mut self.lora_a_k, &mut self.lora_b_k),
+            ("v", &mut self.lora_a_v, &mut self.lora_b_v),
+        ];
+        load_adapters(model, adapters, &mut self.scaling)
+    }
 }
@@ -1 +1,2 @@
+pub mod cross_entropy;
 pub mod fast_lora;
\ No newline at end of file
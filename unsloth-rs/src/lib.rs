@@ -2,6 +2,7 @@ pub mod core;
 pub mod dataprep;
 pub mod kernels;
 pub mod models;
+pub mod peft;
 pub mod rl;
 pub mod save;
 pub mod trainer;
@@ -1,12 +1,13 @@
 use clap::Parser;
 use ndarray::{Array, IxDyn};
-use unsloth_rs::core::Tensor;
+use unsloth_rs::core::{CheckpointMode, Tensor};
 use unsloth_rs::dataprep::synthetic::SyntheticDataKit;
 use unsloth_rs::kernels::fast_lora::{LoraMlp, LoraQkv};
 use unsloth_rs::models::llama::LlamaModel;
+use unsloth_rs::peft::{get_peft_model, LoraConfig};
 use unsloth_rs::rl::ppo::PPO;
 use unsloth_rs::save::Model;
-use unsloth_rs::trainer::Trainer;
+use unsloth_rs::trainer::{Trainer, TrainerConfig};
 use unsloth_rs::utils::hf_hub;
 
 #[derive(Parser, Debug)]
@@ -14,6 +15,9 @@ use unsloth_rs::utils::hf_hub;
 struct Args {
     #[arg(short, long)]
     model: String,
+    // none, full or offload.
+    #[arg(long, default_value_t = CheckpointMode::None)]
+    gradient_checkpointing: CheckpointMode,
 }
 
 fn main() {
@@ -29,28 +33,9 @@ fn main() {
     synthetic_data_kit.prepare_qa_generation();
     println!("Synthetic data generation complete!");
 
-    let lora_mlp = LoraMlp::new(
-        Tensor::new(Array::zeros(IxDyn(&[2, 2]))),
-        Tensor::new(Array::zeros(IxDyn(&[2, 2]))),
-        Tensor::new(Array::zeros(IxDyn(&[2, 2]))),
-        Tensor::new(Array::zeros(IxDyn(&[2, 2]))),
-        Tensor::new(Array::zeros(IxDyn(&[2, 2]))),
-        Tensor::new(Array::zeros(IxDyn(&[2, 2]))),
-        Tensor::new(Array::zeros(IxDyn(&[2, 2]))),
-        Tensor::new(Array::zeros(IxDyn(&[2, 2]))),
-        Tensor::new(Array::zeros(IxDyn(&[2, 2]))),
-    );
-    let lora_qkv = LoraQkv::new(
-        Tensor::new(Array::zeros(IxDyn(&[2, 2]))),
-        Tensor::new(Array::zeros(IxDyn(&[2, 2]))),
-        Tensor::new(Array::zeros(IxDyn(&[2, 2]))),
-        Tensor::new(Array::zeros(IxDyn(&[2, 2]))),
-        Tensor::new(Array::zeros(IxDyn(&[2, 2]))),
-        Tensor::new(Array::zeros(IxDyn(&[2, 2]))),
-        Tensor::new(Array::zeros(IxDyn(&[2, 2]))),
-        Tensor::new(Array::zeros(IxDyn(&[2, 2]))),
-        Tensor::new(Array::zeros(IxDyn(&[2, 2]))),
-    );
+    let weight = || Tensor::new(Array::zeros(IxDyn(&[2, 2])));
+    let lora_mlp = LoraMlp::with_rank(weight(), weight(), weight(), 1);
+    let lora_qkv = LoraQkv::with_rank(weight(), weight(), weight(), 1);
 
     let mlp_output = lora_mlp.forward(&tensor);
     let (q, k, v) = lora_qkv.forward(&tensor);
@@ -61,17 +46,23 @@ fn main() {
     println!("V: {:?}", v);
 
     let model = Model::new();
-    model.save("model.bin");
-    let loaded_model = Model::load("model.bin");
+    model.save("model.safetensors").expect("failed to save model");
+    let _loaded_model = Model::load("model.safetensors").expect("failed to load model");
 
-    let llama_model = LlamaModel::new(8, 4, 128, 32000, 32);
+    let llama_model = LlamaModel::new(8, 4, 16, 1000, 4);
     let llama_output = llama_model.forward(&[1, 2, 3, 4]);
     println!("Llama output: {:?}", llama_output);
 
     let ppo = PPO::new();
     ppo.train();
 
-    let trainer = Trainer::new(llama_model);
+    // LoRA adapters on every projection; only they are trained.
+    let lora_config = LoraConfig::new(16, 16.0);
+    let llama_model = get_peft_model(llama_model, &lora_config).expect("failed to add LoRA adapters");
+    println!("LoRA adapters: {}", llama_model.named_adapters().len());
+
+    let config = TrainerConfig::new().with_gradient_checkpointing(args.gradient_checkpointing);
+    let trainer = Trainer::with_config(llama_model, config);
     trainer.train();
 
     hf_hub::get_model_info("unsloth/llama-3-8b-bnb-4bit");
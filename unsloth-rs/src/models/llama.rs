@@ -1,156 +1,459 @@
-use crate::core::Tensor;
-use ndarray::{s, Array, Array3, Ix3, IxDyn};
-
-pub struct LlamaAttention {
-    pub wq: Tensor,
-    pub wk: Tensor,
-    pub wv: Tensor,
-    pub wo: Tensor,
+use crate::core::{with_rng, CheckpointMode, Element, Init, QuantType, RopeConfig, Tensor, Weight};
+use crate::core::{no_grad, SoftmaxMask, TapeInput, TensorError};
+use crate::kernels::cross_entropy::FusedCrossEntropy;
+use crate::kernels::fast_lora::{LoraAdapter, LoraLinear, DEFAULT_ADAPTER};
+use crate::peft::{InitLoraWeights, LoraConfig};
+use crate::save::Model;
+use ndarray::{s, ArcArray, Array2, ArrayD, IxDyn};
+use std::io;
+use std::rc::Rc;
+
+// Draws a weight from this thread's generator (see `core::manual_seed`).
+fn init<T: Element>(init: Init, shape: &[usize]) -> Weight<T> {
+    with_rng(|rng| init.tensor_with_dtype(shape, rng)).into()
+}
+
+fn unknown_adapter(op: &'static str, name: &str, names: &[String]) -> TensorError {
+    TensorError::InvalidArgument {
+        op,
+        message: format!("unknown adapter {name:?} (expected one of {names:?})"),
+    }
+}
+
+// `positions` must hold one position per token of `x`.
+fn check_positions(op: &'static str, x: &[usize], positions: &[usize]) -> Result<(), TensorError> {
+    if positions.len() != x.len() {
+        return Err(TensorError::ShapeMismatch {
+            op,
+            expected: vec![x.len()],
+            actual: vec![positions.len()],
+        });
+    }
+    Ok(())
+}
+
+// The adapter of each row for `forward_routed`, or `None` for the active ones.
+type Routes<'a> = Option<&'a [Option<&'a str>]>;
+
+fn project<T: Element>(projection: &LoraLinear<T>, x: &Tensor, routes: Routes) -> Tensor {
+    match routes {
+        Some(routes) => projection.forward_routed(x, routes),
+        None => projection.forward(x),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LlamaConfig {
     pub n_heads: usize,
     pub n_kv_heads: usize,
     pub head_dim: usize,
-    pub rotary_dim: usize,
+    pub vocab_size: usize,
+    pub n_layers: usize,
+    pub intermediate_size: usize,
+    pub rope_theta: f32,
+    // Dropout on the attention probabilities while training, as HF's
+    // `attention_dropout`.
+    pub attention_dropout: f32,
+    // Std of the normal init of the embedding and every projection, as HF's
+    // `initializer_range`; norm weights start at one.
+    pub initializer_range: f32,
+}
+
+impl LlamaConfig {
+    // The MLP width defaults to Llama's 8/3 of the hidden size, rounded up to
+    // a multiple of 256.
+    pub fn new(n_heads: usize, n_kv_heads: usize, head_dim: usize, vocab_size: usize, n_layers: usize) -> Self {
+        let hidden = n_heads * head_dim;
+        LlamaConfig {
+            n_heads,
+            n_kv_heads,
+            head_dim,
+            vocab_size,
+            n_layers,
+            intermediate_size: (8 * hidden / 3).div_ceil(256) * 256,
+            rope_theta: 10000.0,
+            attention_dropout: 0.0,
+            initializer_range: 0.02,
+        }
+    }
+
+    pub fn hidden_size(&self) -> usize {
+        self.n_heads * self.head_dim
+    }
+
+    pub fn with_intermediate_size(mut self, intermediate_size: usize) -> Self {
+        self.intermediate_size = intermediate_size;
+        self
+    }
+
+    pub fn with_rope_theta(mut self, rope_theta: f32) -> Self {
+        self.rope_theta = rope_theta;
+        self
+    }
+
+    pub fn with_attention_dropout(mut self, attention_dropout: f32) -> Self {
+        self.attention_dropout = attention_dropout;
+        self
+    }
+
+    pub fn with_initializer_range(mut self, initializer_range: f32) -> Self {
+        self.initializer_range = initializer_range;
+        self
+    }
+
+    fn projection(&self) -> Init {
+        Init::Normal {
+            std: self.initializer_range,
+        }
+    }
+}
+
+// The rotated keys and the values of every position one attention layer has
+// seen, for incremental decoding (see `LlamaModel::forward_step`): new tokens
+// only compute their own and attend to these as well.
+#[derive(Debug, Clone, Default)]
+pub struct KvCache {
+    // [capacity, n_kv_heads, head_dim] each, of which the first `len` rows are
+    // filled.
+    k: ArcArray<f32, IxDyn>,
+    v: ArcArray<f32, IxDyn>,
+    len: usize,
+    // (n_kv_heads, head_dim), set by the first `append`.
+    heads: Option<(usize, usize)>,
+}
+
+impl KvCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The number of cached positions, which is where the next token goes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    // Appends the [n, n_kv_heads, head_dim] keys and values of `n` new
+    // positions and returns all of them, cached ones first, as views of the
+    // cache. The cache is not on the tape: no gradient flows back into earlier
+    // steps.
+    fn append(&mut self, k: &Tensor, v: &Tensor) -> Result<(Tensor, Tensor), TensorError> {
+        let shape = k.data.shape();
+        let (n, heads) = (shape[0], (shape[1], shape[2]));
+        if let Some(expected) = self.heads.filter(|&expected| expected != heads) {
+            return Err(TensorError::ShapeMismatch {
+                op: "kv_cache",
+                expected: vec![expected.0, expected.1],
+                actual: vec![heads.0, heads.1],
+            });
+        }
+        let capacity = if self.heads.is_some() { self.k.shape()[0] } else { 0 };
+        self.heads = Some(heads);
+        let (start, end) = (self.len, self.len + n);
+        if end > capacity {
+            // Doubling the capacity copies each position a constant number of
+            // times on average.
+            let capacity = end.max(2 * capacity);
+            for buffer in [&mut self.k, &mut self.v] {
+                let mut grown = ArrayD::zeros(IxDyn(&[capacity, heads.0, heads.1]));
+                if start > 0 {
+                    grown.slice_mut(s![..start, .., ..]).assign(&buffer.slice(s![..start, .., ..]));
+                }
+                *buffer = grown.into_shared();
+            }
+        }
+        self.k.slice_mut(s![start..end, .., ..]).assign(&k.data);
+        self.v.slice_mut(s![start..end, .., ..]).assign(&v.data);
+        self.len = end;
+        let filled = |buffer: &ArcArray<f32, IxDyn>| {
+            Tensor::from_shared(buffer.clone().slice_move(s![..end, .., ..]).into_dyn())
+        };
+        Ok((filled(&self.k), filled(&self.v)))
+    }
+}
+
+// Weights are stored as `T` (f32 by default), and the projection matrices can
+// be quantized for QLoRA and carry LoRA adapters; activations are always f32.
+#[derive(Clone)]
+pub struct LlamaAttention<T: Element = f32> {
+    pub wq: LoraLinear<T>,
+    pub wk: LoraLinear<T>,
+    pub wv: LoraLinear<T>,
+    pub wo: LoraLinear<T>,
+    pub n_heads: usize,
+    pub n_kv_heads: usize,
+    pub head_dim: usize,
+    pub rope: RopeConfig,
+    pub attention_dropout: f32,
+    training: bool,
 }
 
 impl LlamaAttention {
     pub fn new(n_heads: usize, n_kv_heads: usize, head_dim: usize) -> Self {
-        let rotary_dim = head_dim; // Typically the same as head_dim
-        let wq = Tensor::new(Array::zeros(IxDyn(&[1, 1])));
-        let wk = Tensor::new(Array::zeros(IxDyn(&[1, 1])));
-        let wv = Tensor::new(Array::zeros(IxDyn(&[1, 1])));
-        let wo = Tensor::new(Array::zeros(IxDyn(&[1, 1])));
+        Self::new_with_dtype(n_heads, n_kv_heads, head_dim)
+    }
+
+    pub fn from_config(config: &LlamaConfig) -> Self {
+        Self::from_config_with_dtype(config)
+    }
+}
+
+impl<T: Element> LlamaAttention<T> {
+    // With the defaults of `LlamaConfig`.
+    pub fn new_with_dtype(n_heads: usize, n_kv_heads: usize, head_dim: usize) -> Self {
+        Self::from_config_with_dtype(&LlamaConfig::new(n_heads, n_kv_heads, head_dim, 0, 0))
+    }
+
+    pub fn from_config_with_dtype(config: &LlamaConfig) -> Self {
+        let (n_heads, n_kv_heads) = (config.n_heads, config.n_kv_heads);
+        if n_kv_heads == 0 || n_heads % n_kv_heads != 0 {
+            panic!("n_heads must be a multiple of n_kv_heads, got {n_heads} and {n_kv_heads}");
+        }
+        let hidden = config.hidden_size();
+        let kv_dim = config.n_kv_heads * config.head_dim;
+        let rope = RopeConfig::new(config.head_dim, config.rope_theta);
+        let wq = init(config.projection(), &[hidden, hidden]).into();
+        let wk = init(config.projection(), &[hidden, kv_dim]).into();
+        let wv = init(config.projection(), &[hidden, kv_dim]).into();
+        let wo = init(config.projection(), &[hidden, hidden]).into();
 
         LlamaAttention {
             wq,
             wk,
             wv,
             wo,
-            n_heads,
-            n_kv_heads,
-            head_dim,
-            rotary_dim,
+            n_heads: config.n_heads,
+            n_kv_heads: config.n_kv_heads,
+            head_dim: config.head_dim,
+            rope,
+            attention_dropout: config.attention_dropout,
+            training: true,
         }
     }
 
     pub fn forward(&self, x: &Tensor) -> Tensor {
-        let (seq_len, hidden_dim) = (x.data.shape()[0], x.data.shape()[1]);
-        let x_reshaped =
-            Tensor::new(x.data.clone().into_shape((seq_len, hidden_dim)).unwrap().into_dyn());
+        let positions: Vec<usize> = (0..x.data.shape()[0]).collect();
+        self.forward_with_positions(x, &positions)
+    }
 
-        let mut q_proj = x_reshaped.matmul(&self.wq);
-        let mut k_proj = x_reshaped.matmul(&self.wk);
-        let v_proj = x_reshaped.matmul(&self.wv);
+    // `positions` holds the position id of each row of `x`. Every token only
+    // attends to itself and the tokens before it.
+    pub fn forward_with_positions(&self, x: &Tensor, positions: &[usize]) -> Tensor {
+        self.attend(x, positions, None, None, None)
+    }
 
-        q_proj = q_proj.rope(0, self.rotary_dim, 2048, 10000.0);
-        k_proj = k_proj.rope(0, self.rotary_dim, 2048, 10000.0);
+    // `x` holds the tokens after the ones in `cache`, at the positions that
+    // follow; they attend to the cached ones too, and are appended to `cache`.
+    pub fn forward_step(&self, x: &Tensor, cache: &mut KvCache) -> Tensor {
+        let positions: Vec<usize> = (cache.len()..cache.len() + x.data.shape()[0]).collect();
+        self.attend(x, &positions, None, None, Some(cache))
+    }
 
-        // Reshape: [seq_len, n_heads, head_dim]
-        let q = q_proj.data.into_shape((seq_len, self.n_heads, self.head_dim)).unwrap();
-        let k = k_proj.data.into_shape((seq_len, self.n_kv_heads, self.head_dim)).unwrap();
-        let v = v_proj.data.into_shape((seq_len, self.n_kv_heads, self.head_dim)).unwrap();
+    // Also excludes what `mask` masks out, e.g. a [seq_len] `Keep` mask that is
+    // false at padded positions (HF's `attention_mask`), or a [seq_len, seq_len]
+    // one of (query, key) pairs to keep packed sequences apart. The causal mask
+    // still applies.
+    pub fn forward_masked(&self, x: &Tensor, positions: &[usize], mask: &SoftmaxMask) -> Tensor {
+        let seq_len = x.data.shape()[0];
+        let mask = SoftmaxMask::causal(seq_len, seq_len).combine(mask).unwrap_or_else(|err| panic!("{err}"));
+        self.attend(x, positions, None, Some(&mask), None)
+    }
 
-        // Transpose to: [n_heads, seq_len, head_dim]
-        let q = q.permuted_axes([1, 0, 2]);
-        let k = k.permuted_axes([1, 0, 2]);
-        let v = v.permuted_axes([1, 0, 2]);
+    // Runs each row of `x` through the adapters `routes` names for it (see
+    // `LoraLinear::forward_routed`), masked as in `forward_masked`, e.g. to
+    // keep the requests of a batch apart.
+    pub fn forward_routed(
+        &self,
+        x: &Tensor,
+        positions: &[usize],
+        mask: &SoftmaxMask,
+        routes: &[Option<&str>],
+    ) -> Tensor {
+        let seq_len = x.data.shape()[0];
+        let mask = SoftmaxMask::causal(seq_len, seq_len).combine(mask).unwrap_or_else(|err| panic!("{err}"));
+        self.attend(x, positions, Some(routes), Some(&mask), None)
+    }
 
-        // Repeat K and V to match number of Q heads
+    // `mask` is the whole mask of the scores; `None` is the causal one. With a
+    // cache, the keys are the cached ones followed by those of `x`.
+    fn attend(
+        &self,
+        x: &Tensor,
+        positions: &[usize],
+        routes: Routes,
+        mask: Option<&SoftmaxMask>,
+        cache: Option<&mut KvCache>,
+    ) -> Tensor {
+        let seq_len = x.data.shape()[0];
         let n_rep = self.n_heads / self.n_kv_heads;
 
-        // CRITICAL: Make K and V contiguous before reshaping
-        let k = k.as_standard_layout().to_owned();
-        let v = v.as_standard_layout().to_owned();
+        // Q, K and V share their adapter matmul unless rows take different adapters.
+        let [q_proj, k_proj, v_proj]: [Tensor; 3] = match routes {
+            Some(_) => [&self.wq, &self.wk, &self.wv].map(|projection| project(projection, x, routes)),
+            None => LoraLinear::forward_fused(&[&self.wq, &self.wk, &self.wv], x).try_into().unwrap(),
+        };
 
-        // Reshape K and V: [n_kv_heads, seq_len, head_dim] -> [n_kv_heads, 1, seq_len, head_dim]
-        let k_reshaped = k.into_shape((self.n_kv_heads, 1, seq_len, self.head_dim)).unwrap();
-        let v_reshaped = v.into_shape((self.n_kv_heads, 1, seq_len, self.head_dim)).unwrap();
+        // Rotate each head of each token by the token's position.
+        let q_proj = q_proj
+            .reshape(&[seq_len, self.n_heads, self.head_dim])
+            .rope_at(positions, &self.rope);
+        let k_proj = k_proj
+            .reshape(&[seq_len, self.n_kv_heads, self.head_dim])
+            .rope_at(positions, &self.rope);
+        let v_proj = v_proj.reshape(&[seq_len, self.n_kv_heads, self.head_dim]);
+        let (k_proj, v_proj) = match cache {
+            Some(cache) => cache.append(&k_proj, &v_proj).unwrap_or_else(|err| panic!("{err}")),
+            None => (k_proj, v_proj),
+        };
+        let kv_len = k_proj.data.shape()[0];
 
-        // Broadcast: [n_kv_heads, 1, seq_len, head_dim] -> [n_kv_heads, n_rep, seq_len, head_dim]
-        let k_broadcasted =
-            k_reshaped.broadcast((self.n_kv_heads, n_rep, seq_len, self.head_dim)).unwrap();
-        let v_broadcasted =
-            v_reshaped.broadcast((self.n_kv_heads, n_rep, seq_len, self.head_dim)).unwrap();
+        // Group the query heads by the K/V head they share:
+        // Q: [n_kv_heads, n_rep, seq_len, head_dim]
+        // K^T: [n_kv_heads, 1, head_dim, kv_len]
+        // V: [n_kv_heads, 1, kv_len, head_dim]
+        let q = q_proj
+            .permute(&[1, 0, 2])
+            .reshape(&[self.n_kv_heads, n_rep, seq_len, self.head_dim]);
+        let k_t = k_proj
+            .reshape(&[kv_len, self.n_kv_heads, 1, self.head_dim])
+            .permute(&[1, 2, 3, 0]);
+        let v = v_proj
+            .reshape(&[kv_len, self.n_kv_heads, 1, self.head_dim])
+            .permute(&[1, 2, 0, 3]);
 
-        // Materialize the broadcast before reshaping
-        // Flatten: [n_kv_heads, n_rep, seq_len, head_dim] -> [n_heads, seq_len, head_dim]
-        let k_repeated = k_broadcasted
-            .to_owned()
-            .into_shape((self.n_heads, seq_len, self.head_dim))
-            .unwrap();
-        let v_repeated = v_broadcasted
-            .to_owned()
-            .into_shape((self.n_heads, seq_len, self.head_dim))
-            .unwrap();
+        // The size-1 axis of K and V broadcasts over the n_rep query heads,
+        // so there is no need to materialize repeated K/V heads.
+        // Result: [n_kv_heads, n_rep, seq_len, kv_len]
+        let scores = q.matmul(&k_t).scale(1.0 / (self.head_dim as f32).sqrt());
 
-        // Compute attention scores: Q @ K^T
-        // Q: [n_heads, seq_len, head_dim]
-        // K^T: [n_heads, head_dim, seq_len]
-        // Result: [n_heads, seq_len, seq_len]
+        // Softmax over the keys each query can see.
+        let causal;
+        let mask = match mask {
+            Some(mask) => mask,
+            None => {
+                causal = SoftmaxMask::causal(seq_len, kv_len);
+                &causal
+            }
+        };
+        let mut attention_weights = scores.masked_softmax(3, mask);
+        if self.training && self.attention_dropout > 0.0 {
+            attention_weights = attention_weights.dropout(self.attention_dropout);
+        }
 
-        let k_t = k_repeated.permuted_axes([0, 2, 1]); // [n_heads, head_dim, seq_len]
+        // Result: [n_kv_heads, n_rep, seq_len, head_dim]
+        let attention_output = attention_weights.matmul(&v);
 
-        // Batch matmul for each head
-        let mut scores = Array3::<f32>::zeros((self.n_heads, seq_len, seq_len));
-        for h in 0..self.n_heads {
-            let q_h = q.slice(s![h, .., ..]);
-            let k_h = k_t.slice(s![h, .., ..]);
-            scores.slice_mut(s![h, .., ..]).assign(&q_h.dot(&k_h));
-        }
+        // Back to [seq_len, n_heads * head_dim]
+        let attention_output = attention_output
+            .reshape(&[self.n_heads, seq_len, self.head_dim])
+            .permute(&[1, 0, 2])
+            .reshape(&[seq_len, self.n_heads * self.head_dim]);
 
-        scores = scores / (self.head_dim as f32).sqrt();
+        project(&self.wo, &attention_output, routes)
+    }
 
-        // Apply softmax over last dimension
-        let scores_tensor = Tensor::new(scores.into_dyn());
-        let attention_weights = scores_tensor.softmax(2);
+    // Modules start in training mode; `eval` turns dropout off.
+    pub fn train(&mut self) {
+        self.training = true;
+        for (_, projection) in self.projections_mut() {
+            projection.train();
+        }
+    }
 
-        // Attention weights @ V
-        // attention_weights: [n_heads, seq_len, seq_len]
-        // V: [n_heads, seq_len, head_dim]
-        // Result: [n_heads, seq_len, head_dim]
+    pub fn eval(&mut self) {
+        self.training = false;
+        for (_, projection) in self.projections_mut() {
+            projection.eval();
+        }
+    }
 
-        let attn_3d = attention_weights.data.into_dimensionality::<Ix3>().unwrap();
-        let mut attention_output = Array3::<f32>::zeros((self.n_heads, seq_len, self.head_dim));
+    pub fn is_training(&self) -> bool {
+        self.training
+    }
 
-        for h in 0..self.n_heads {
-            let attn_h = attn_3d.slice(s![h, .., ..]);
-            let v_h = v_repeated.slice(s![h, .., ..]);
-            attention_output.slice_mut(s![h, .., ..]).assign(&attn_h.dot(&v_h));
+    pub fn quantize(&mut self, qtype: QuantType) {
+        for (_, projection) in self.projections_mut() {
+            projection.weight.quantize(qtype);
         }
+    }
 
-        // Transpose back: [n_heads, seq_len, head_dim] -> [seq_len, n_heads, head_dim]
-        let attention_output = attention_output.permuted_axes([1, 0, 2]);
+    // The projections under the names PEFT's `target_modules` use.
+    pub fn projections(&self) -> Vec<(&'static str, &LoraLinear<T>)> {
+        vec![("q_proj", &self.wq), ("k_proj", &self.wk), ("v_proj", &self.wv), ("o_proj", &self.wo)]
+    }
 
-        // Flatten: [seq_len, n_heads, head_dim] -> [seq_len, n_heads * head_dim]
-        let attention_output = attention_output
-            .as_standard_layout()
-            .to_owned()
-            .into_shape((seq_len, self.n_heads * self.head_dim))
-            .unwrap();
+    pub fn projections_mut(&mut self) -> Vec<(&'static str, &mut LoraLinear<T>)> {
+        vec![
+            ("q_proj", &mut self.wq),
+            ("k_proj", &mut self.wk),
+            ("v_proj", &mut self.wv),
+            ("o_proj", &mut self.wo),
+        ]
+    }
 
-        let final_output = Tensor::new(attention_output.into_dyn()).matmul(&self.wo);
-        final_output
+    pub fn named_weights(&self) -> Vec<(String, &Weight<T>)> {
+        vec![
+            ("wq".to_string(), &self.wq.weight),
+            ("wk".to_string(), &self.wk.weight),
+            ("wv".to_string(), &self.wv.weight),
+            ("wo".to_string(), &self.wo.weight),
+        ]
+    }
+
+    pub fn named_weights_mut(&mut self) -> Vec<(String, &mut Weight<T>)> {
+        vec![
+            ("wq".to_string(), &mut self.wq.weight),
+            ("wk".to_string(), &mut self.wk.weight),
+            ("wv".to_string(), &mut self.wv.weight),
+            ("wo".to_string(), &mut self.wo.weight),
+        ]
     }
 }
 
-pub struct LlamaDecoderLayer {
-    self_attn: LlamaAttention,
-    attention_norm: Tensor,
-    ffn_norm: Tensor,
-    w1: Tensor,
-    w2: Tensor,
-    w3: Tensor,
+#[derive(Clone)]
+pub struct LlamaDecoderLayer<T: Element = f32> {
+    self_attn: LlamaAttention<T>,
+    attention_norm: Weight<T>,
+    ffn_norm: Weight<T>,
+    w1: LoraLinear<T>,
+    w2: LoraLinear<T>,
+    w3: LoraLinear<T>,
 }
 
 impl LlamaDecoderLayer {
     pub fn new(n_heads: usize, n_kv_heads: usize, head_dim: usize) -> Self {
-        let attention_norm = Tensor::new(Array::zeros(IxDyn(&[1, 1])));
-        let ffn_norm = Tensor::new(Array::zeros(IxDyn(&[1, 1])));
-        let w1 = Tensor::new(Array::zeros(IxDyn(&[1, 1])));
-        let w2 = Tensor::new(Array::zeros(IxDyn(&[1, 1])));
-        let w3 = Tensor::new(Array::zeros(IxDyn(&[1, 1])));
+        Self::new_with_dtype(n_heads, n_kv_heads, head_dim)
+    }
+
+    pub fn from_config(config: &LlamaConfig) -> Self {
+        Self::from_config_with_dtype(config)
+    }
+}
+
+impl<T: Element> LlamaDecoderLayer<T> {
+    // With the defaults of `LlamaConfig`.
+    pub fn new_with_dtype(n_heads: usize, n_kv_heads: usize, head_dim: usize) -> Self {
+        Self::from_config_with_dtype(&LlamaConfig::new(n_heads, n_kv_heads, head_dim, 0, 0))
+    }
+
+    pub fn from_config_with_dtype(config: &LlamaConfig) -> Self {
+        let (hidden, ff) = (config.hidden_size(), config.intermediate_size);
+        let self_attn = LlamaAttention::from_config_with_dtype(config);
+        let attention_norm = init(Init::Ones, &[hidden]);
+        let ffn_norm = init(Init::Ones, &[hidden]);
+        let w1 = init(config.projection(), &[hidden, ff]).into();
+        let w2 = init(config.projection(), &[ff, hidden]).into();
+        let w3 = init(config.projection(), &[hidden, ff]).into();
 
         LlamaDecoderLayer {
-            self_attn: LlamaAttention::new(n_heads, n_kv_heads, head_dim),
+            self_attn,
             attention_norm,
             ffn_norm,
             w1,
@@ -160,48 +463,637 @@ impl LlamaDecoderLayer {
     }
 
     pub fn forward(&self, x: &Tensor) -> Tensor {
-        let h = x.rmsnorm(&self.attention_norm, 1e-5);
-        let attention_output = self.self_attn.forward(&h);
+        let positions: Vec<usize> = (0..x.data.shape()[0]).collect();
+        self.forward_with_positions(x, &positions)
+    }
+
+    pub fn forward_with_positions(&self, x: &Tensor, positions: &[usize]) -> Tensor {
+        self.forward_inner(x, positions, None, None, None)
+    }
+
+    // See `LlamaAttention::forward_masked`.
+    pub fn forward_masked(&self, x: &Tensor, positions: &[usize], mask: &SoftmaxMask) -> Tensor {
+        let seq_len = x.data.shape()[0];
+        let mask = SoftmaxMask::causal(seq_len, seq_len).combine(mask).unwrap_or_else(|err| panic!("{err}"));
+        self.forward_inner(x, positions, None, Some(&mask), None)
+    }
+
+    // See `LlamaAttention::forward_routed`.
+    pub fn forward_routed(
+        &self,
+        x: &Tensor,
+        positions: &[usize],
+        mask: &SoftmaxMask,
+        routes: &[Option<&str>],
+    ) -> Tensor {
+        let seq_len = x.data.shape()[0];
+        let mask = SoftmaxMask::causal(seq_len, seq_len).combine(mask).unwrap_or_else(|err| panic!("{err}"));
+        self.forward_inner(x, positions, Some(routes), Some(&mask), None)
+    }
+
+    // See `LlamaAttention::forward_step`.
+    pub fn forward_step(&self, x: &Tensor, cache: &mut KvCache) -> Tensor {
+        let positions: Vec<usize> = (cache.len()..cache.len() + x.data.shape()[0]).collect();
+        self.forward_inner(x, &positions, None, None, Some(cache))
+    }
+
+    // `mask` and `cache` as in `LlamaAttention::attend`.
+    fn forward_inner(
+        &self,
+        x: &Tensor,
+        positions: &[usize],
+        routes: Routes,
+        mask: Option<&SoftmaxMask>,
+        cache: Option<&mut KvCache>,
+    ) -> Tensor {
+        let h = x.rmsnorm(&*self.attention_norm.to_dense(), 1e-5);
+        let attention_output = self.self_attn.attend(&h, positions, routes, mask, cache);
         let h = x.add(&attention_output);
 
-        let h_norm = h.rmsnorm(&self.ffn_norm, 1e-5);
-        let gate = h_norm.matmul(&self.w1).silu();
-        let up = h_norm.matmul(&self.w3);
+        let h_norm = h.rmsnorm(&*self.ffn_norm.to_dense(), 1e-5);
+        let gate = project(&self.w1, &h_norm, routes).silu();
+        let up = project(&self.w3, &h_norm, routes);
         let ff = gate.mul(&up);
-        let ff = ff.matmul(&self.w2);
+        let ff = project(&self.w2, &ff, routes);
 
         h.add(&ff)
     }
+
+    // `forward_with_positions` under gradient checkpointing: with `Full` or
+    // `Offload`, only `x` is kept for backward and the layer is re-run from it.
+    pub fn forward_checkpointed(self: &Rc<Self>, x: &Tensor, positions: &[usize], mode: CheckpointMode) -> Tensor {
+        self.checkpointed(x, positions, None, mode)
+    }
+
+    // `mask` as in `attend`.
+    fn checkpointed(
+        self: &Rc<Self>,
+        x: &Tensor,
+        positions: &[usize],
+        mask: Option<&SoftmaxMask>,
+        mode: CheckpointMode,
+    ) -> Tensor {
+        if mode == CheckpointMode::None {
+            return self.forward_inner(x, positions, None, mask, None);
+        }
+        let weights = self.named_weights();
+        let mut params: Vec<&dyn TapeInput> = weights.iter().map(|(_, weight)| *weight as _).collect();
+        let projections = self.projections();
+        for (_, adapter) in projections.iter().flat_map(|(_, projection)| projection.adapters()) {
+            params.push(&adapter.lora_a);
+            params.push(&adapter.lora_b);
+            if let Some(magnitude) = &adapter.magnitude {
+                params.push(magnitude);
+            }
+        }
+        let (layer, positions, mask) = (Rc::clone(self), positions.to_vec(), mask.cloned());
+        x.checkpoint(&params, mode, move |x| layer.forward_inner(x, &positions, None, mask.as_ref(), None))
+            .unwrap_or_else(|err| panic!("failed to offload a checkpointed activation: {err}"))
+    }
+
+    pub fn set_rope(&mut self, rope: RopeConfig) {
+        self.self_attn.rope = rope;
+    }
+
+    pub fn train(&mut self) {
+        self.self_attn.train();
+        for projection in [&mut self.w1, &mut self.w2, &mut self.w3] {
+            projection.train();
+        }
+    }
+
+    pub fn eval(&mut self) {
+        self.self_attn.eval();
+        for projection in [&mut self.w1, &mut self.w2, &mut self.w3] {
+            projection.eval();
+        }
+    }
+
+    pub fn is_training(&self) -> bool {
+        self.self_attn.is_training()
+    }
+
+    // Quantizes the attention and MLP projections; the norms stay dense.
+    pub fn quantize(&mut self, qtype: QuantType) {
+        for (_, projection) in self.projections_mut() {
+            projection.weight.quantize(qtype);
+        }
+    }
+
+    // Every attention and MLP projection, under PEFT's names.
+    pub fn projections(&self) -> Vec<(&'static str, &LoraLinear<T>)> {
+        let mut projections = self.self_attn.projections();
+        projections.extend([("gate_proj", &self.w1), ("up_proj", &self.w3), ("down_proj", &self.w2)]);
+        projections
+    }
+
+    pub fn projections_mut(&mut self) -> Vec<(&'static str, &mut LoraLinear<T>)> {
+        let mut projections = self.self_attn.projections_mut();
+        projections.extend([("gate_proj", &mut self.w1), ("up_proj", &mut self.w3), ("down_proj", &mut self.w2)]);
+        projections
+    }
+
+    pub fn merge_adapters(&mut self, requantize: bool) {
+        for (_, projection) in self.projections_mut() {
+            projection.merge(requantize);
+        }
+    }
+
+    pub fn unmerge_adapters(&mut self) {
+        for (_, projection) in self.projections_mut() {
+            projection.unmerge();
+        }
+    }
+
+    pub fn adapters(&self) -> Vec<(&'static str, &LoraAdapter)> {
+        self.projections()
+            .into_iter()
+            .filter_map(|(name, projection)| Some((name, projection.adapter.as_ref()?)))
+            .collect()
+    }
+
+    pub fn adapters_mut(&mut self) -> Vec<(&'static str, &mut LoraAdapter)> {
+        self.projections_mut()
+            .into_iter()
+            .filter_map(|(name, projection)| Some((name, projection.adapter.as_mut()?)))
+            .collect()
+    }
+
+    pub fn named_weights(&self) -> Vec<(String, &Weight<T>)> {
+        let mut weights: Vec<(String, &Weight<T>)> = self
+            .self_attn
+            .named_weights()
+            .into_iter()
+            .map(|(name, weight)| (format!("self_attn.{name}"), weight))
+            .collect();
+        weights.push(("attention_norm".to_string(), &self.attention_norm));
+        weights.push(("ffn_norm".to_string(), &self.ffn_norm));
+        weights.push(("w1".to_string(), &self.w1.weight));
+        weights.push(("w2".to_string(), &self.w2.weight));
+        weights.push(("w3".to_string(), &self.w3.weight));
+        weights
+    }
+
+    pub fn named_weights_mut(&mut self) -> Vec<(String, &mut Weight<T>)> {
+        let mut weights: Vec<(String, &mut Weight<T>)> = self
+            .self_attn
+            .named_weights_mut()
+            .into_iter()
+            .map(|(name, weight)| (format!("self_attn.{name}"), weight))
+            .collect();
+        weights.push(("attention_norm".to_string(), &mut self.attention_norm));
+        weights.push(("ffn_norm".to_string(), &mut self.ffn_norm));
+        weights.push(("w1".to_string(), &mut self.w1.weight));
+        weights.push(("w2".to_string(), &mut self.w2.weight));
+        weights.push(("w3".to_string(), &mut self.w3.weight));
+        weights
+    }
 }
 
-pub struct LlamaModel {
-    embedding: Tensor,
-    layers: Vec<LlamaDecoderLayer>,
-    norm: Tensor,
-    output: Tensor,
+pub struct LlamaModel<T: Element = f32> {
+    embedding: Weight<T>,
+    // Shared, so a checkpointed layer can keep itself for its backward cheaply.
+    layers: Vec<Rc<LlamaDecoderLayer<T>>>,
+    norm: Weight<T>,
+    output: Weight<T>,
+    checkpointing: CheckpointMode,
+    training: bool,
 }
 
 impl LlamaModel {
     pub fn new(n_heads: usize, n_kv_heads: usize, head_dim: usize, vocab_size: usize, n_layers: usize) -> Self {
-        let embedding = Tensor::new(Array::zeros(IxDyn(&[vocab_size, n_heads * head_dim])));
-        let layers = (0..n_layers).map(|_| LlamaDecoderLayer::new(n_heads, n_kv_heads, head_dim)).collect();
-        let norm = Tensor::new(Array::zeros(IxDyn(&[1, 1])));
-        let output = Tensor::new(Array::zeros(IxDyn(&[1, 1])));
+        Self::new_with_dtype(n_heads, n_kv_heads, head_dim, vocab_size, n_layers)
+    }
+
+    // Every weight correctly shaped and initialized, drawn from this thread's
+    // generator, so `core::manual_seed(seed)` first makes it reproducible.
+    pub fn from_config(config: &LlamaConfig) -> Self {
+        Self::from_config_with_dtype(config)
+    }
+}
+
+impl<T: Element> LlamaModel<T> {
+    // Same as `LlamaModel::new`, but stores every weight as `T`, e.g.
+    // `LlamaModel::<bf16>::new_with_dtype(..)`.
+    pub fn new_with_dtype(
+        n_heads: usize,
+        n_kv_heads: usize,
+        head_dim: usize,
+        vocab_size: usize,
+        n_layers: usize,
+    ) -> Self {
+        Self::from_config_with_dtype(&LlamaConfig::new(n_heads, n_kv_heads, head_dim, vocab_size, n_layers))
+    }
+
+    pub fn from_config_with_dtype(config: &LlamaConfig) -> Self {
+        let (hidden, vocab_size) = (config.hidden_size(), config.vocab_size);
+        let embedding = init(config.projection(), &[vocab_size, hidden]);
+        let layers = (0..config.n_layers)
+            .map(|_| Rc::new(LlamaDecoderLayer::from_config_with_dtype(config)))
+            .collect();
+        let norm = init(Init::Ones, &[hidden]);
+        let output = init(config.projection(), &[hidden, vocab_size]);
         LlamaModel {
             embedding,
             layers,
             norm,
             output,
+            checkpointing: CheckpointMode::None,
+            training: true,
         }
     }
 
     pub fn forward(&self, x: &[usize]) -> Tensor {
-        let mut h = self.embedding.data.select(ndarray::Axis(0), x);
-        let mut h = Tensor::new(h.to_owned().into_dyn());
+        let positions: Vec<usize> = (0..x.len()).collect();
+        self.forward_with_positions(x, &positions)
+    }
+
+    // `positions` gives the position id of each token, e.g. to restart at 0
+    // for every sequence packed into `x`.
+    pub fn forward_with_positions(&self, x: &[usize], positions: &[usize]) -> Tensor {
+        self.hidden_states(x, positions).matmul(&self.output)
+    }
+
+    // Also excludes what `mask` masks out, broadcast against the
+    // [seq_len, seq_len] (query, key) scores; see
+    // `LlamaAttention::forward_masked`.
+    pub fn forward_masked(&self, x: &[usize], positions: &[usize], mask: &SoftmaxMask) -> Tensor {
+        self.try_forward_masked(x, positions, mask).unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    pub fn try_forward_masked(
+        &self,
+        x: &[usize],
+        positions: &[usize],
+        mask: &SoftmaxMask,
+    ) -> Result<Tensor, TensorError> {
+        check_positions("forward_masked", x, positions)?;
+        let mask = SoftmaxMask::causal(x.len(), x.len()).combine(mask)?;
+        Ok(self.hidden(x, positions, Some(&mask)).matmul(&self.output))
+    }
+
+    // The final normed hidden states, before the output projection.
+    pub fn hidden_states(&self, x: &[usize], positions: &[usize]) -> Tensor {
+        self.hidden(x, positions, None)
+    }
+
+    // An empty `KvCache` for every decoder layer, to start `forward_step` with.
+    pub fn new_kv_cache(&self) -> Vec<KvCache> {
+        vec![KvCache::new(); self.layers.len()]
+    }
+
+    // Incremental decoding: runs only the new tokens `x`, at the positions
+    // after those in `cache` (one `KvCache` per layer), attending to the cached
+    // ones as well, and appends them to `cache`. Returns the logits of the new
+    // tokens, so feeding a sequence in pieces gives the logits `forward` gives
+    // for all of it. Nothing is recorded on the tape; call `eval` first to turn
+    // dropout off.
+    pub fn forward_step(&self, x: &[usize], cache: &mut [KvCache]) -> Tensor {
+        self.try_forward_step(x, cache).unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    pub fn try_forward_step(&self, x: &[usize], cache: &mut [KvCache]) -> Result<Tensor, TensorError> {
+        if cache.len() != self.layers.len() {
+            return Err(TensorError::ShapeMismatch {
+                op: "forward_step",
+                expected: vec![self.layers.len()],
+                actual: vec![cache.len()],
+            });
+        }
+        let lens: Vec<usize> = cache.iter().map(KvCache::len).collect();
+        if lens.windows(2).any(|pair| pair[0] != pair[1]) {
+            return Err(TensorError::InvalidArgument {
+                op: "forward_step",
+                message: format!("the layer caches hold different numbers of positions: {lens:?}"),
+            });
+        }
+        Ok(no_grad(|| {
+            let mut h = self.embed(x);
+            for (layer, cache) in self.layers.iter().zip(cache) {
+                h = layer.forward_step(&h, cache);
+            }
+            h.rmsnorm(&*self.norm.to_dense(), 1e-5).matmul(&self.output)
+        }))
+    }
+
+    fn embed(&self, x: &[usize]) -> Tensor {
+        let h = self.embedding.to_dense().data.select(ndarray::Axis(0), x);
+        Tensor::new(h.mapv(T::to_f32))
+    }
+
+    // `mask` as in `LlamaAttention::attend`.
+    fn hidden(&self, x: &[usize], positions: &[usize], mask: Option<&SoftmaxMask>) -> Tensor {
+        let mut h = self.embed(x);
+        for layer in &self.layers {
+            h = layer.checkpointed(&h, positions, mask, self.checkpointing);
+        }
+        h.rmsnorm(&*self.norm.to_dense(), 1e-5)
+    }
+
+    // Next-token loss of `x` against `labels` (one per token), computed from
+    // the hidden states without materializing the [seq, vocab] logits.
+    pub fn loss(&self, x: &[usize], labels: &[usize], loss_fn: &FusedCrossEntropy) -> Tensor {
+        let positions: Vec<usize> = (0..x.len()).collect();
+        loss_fn.loss(&self.hidden_states(x, &positions), &*self.output.to_dense(), labels)
+    }
+
+    // Gradient checkpointing of every decoder layer; `CheckpointMode::None`
+    // by default.
+    pub fn set_checkpointing(&mut self, mode: CheckpointMode) {
+        self.checkpointing = mode;
+    }
+
+    pub fn checkpointing(&self) -> CheckpointMode {
+        self.checkpointing
+    }
+
+    // Switches every layer to training mode (dropout on), the default.
+    pub fn train(&mut self) {
+        self.training = true;
+        for layer in self.layers.iter_mut().map(Rc::make_mut) {
+            layer.train();
+        }
+    }
+
+    // Switches every layer to inference mode (dropout off).
+    pub fn eval(&mut self) {
+        self.training = false;
+        for layer in self.layers.iter_mut().map(Rc::make_mut) {
+            layer.eval();
+        }
+    }
+
+    pub fn is_training(&self) -> bool {
+        self.training
+    }
+
+    // Sets the rotary embedding (theta, layout, `rope_scaling`) of every layer,
+    // e.g. `RopeConfig::new(head_dim, 500000.0).with_scaling(RopeScaling::llama3())`.
+    pub fn set_rope(&mut self, rope: RopeConfig) {
+        for layer in self.layers.iter_mut().map(Rc::make_mut) {
+            layer.set_rope(rope.clone());
+        }
+    }
+
+    // QLoRA-style: quantizes every decoder layer projection, keeping the
+    // embedding, the norms and the output head in `T`.
+    pub fn quantize(&mut self, qtype: QuantType) {
+        for layer in self.layers.iter_mut().map(Rc::make_mut) {
+            layer.quantize(qtype);
+        }
+    }
+
+    // Wraps the projections named in `config.target_modules` with fresh, trainable
+    // adapters (see `peft::get_peft_model`). Fails on a target that names no
+    // projection, leaving the model unchanged.
+    pub fn add_lora(&mut self, config: &LoraConfig) -> Result<(), TensorError> {
+        self.add_adapter(DEFAULT_ADAPTER, config)
+    }
+
+    // `add_lora` under another name, next to the adapters already there; it
+    // only applies once activated (see `set_adapter`). PiSSA and LoftQ change
+    // the shared base weights, so they are only allowed for the first adapter.
+    pub fn add_adapter(&mut self, name: &str, config: &LoraConfig) -> Result<(), TensorError> {
+        let invalid = |message: String| TensorError::InvalidArgument { op: "add_lora", message };
+        if let Some(layer) = self.layers.first() {
+            let known: Vec<&str> = layer.projections().into_iter().map(|(name, _)| name).collect();
+            if let Some(target) = config.target_modules.iter().find(|t| !known.contains(&t.as_str())) {
+                return Err(invalid(format!("unknown target module {target:?} (expected one of {known:?})")));
+            }
+        }
+        if config.init_lora_weights != InitLoraWeights::Default && !self.adapter_names().is_empty() {
+            let init = config.init_lora_weights;
+            return Err(invalid(format!("{init:?} would change the base weights of the other adapters")));
+        }
+        for layer in self.layers.iter_mut().map(Rc::make_mut) {
+            for (target, projection) in layer.projections_mut() {
+                if let Some(adapter) = config.adapter_for(target, &mut projection.weight) {
+                    projection.insert_adapter(name, adapter);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Adds the adapters saved by `named_adapter_state_dict` (possibly from
+    // another model with the same base) under `name`, without activating them.
+    pub fn load_adapter(&mut self, name: &str, model: &Model) -> io::Result<()> {
+        let mut found = false;
+        for (i, layer) in self.layers.iter_mut().map(Rc::make_mut).enumerate() {
+            for (target, projection) in layer.projections_mut() {
+                let prefix = format!("layers.{i}.{target}");
+                if model.contains(&format!("{prefix}.lora_a")) {
+                    projection.insert_adapter(name, LoraAdapter::load(model, &prefix)?);
+                    found = true;
+                }
+            }
+        }
+        if !found {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "no adapter tensors to load"));
+        }
+        Ok(())
+    }
+
+    pub fn remove_adapter(&mut self, name: &str) {
+        for layer in self.layers.iter_mut().map(Rc::make_mut) {
+            for (_, projection) in layer.projections_mut() {
+                projection.remove_adapter(name);
+            }
+        }
+    }
+
+    // The names of every adapter in the model, sorted.
+    pub fn adapter_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .layers
+            .iter()
+            .flat_map(|layer| layer.projections())
+            .flat_map(|(_, projection)| projection.adapters().into_iter().map(|(name, _)| name.to_string()))
+            .collect();
+        names.sort_unstable();
+        names.dedup();
+        names
+    }
+
+    // Makes `name` the only active adapter (the default one at first).
+    pub fn set_adapter(&mut self, name: &str) -> Result<(), TensorError> {
+        self.set_weighted_adapters(&[(name, 1.0)])
+    }
+
+    // Applies several adapters at once, each output change scaled by its
+    // weight, e.g. to blend two fine-tunes. Nothing is copied, and a merged
+    // model is not affected until it is unmerged.
+    pub fn set_weighted_adapters(&mut self, adapters: &[(&str, f32)]) -> Result<(), TensorError> {
+        let names = self.adapter_names();
+        if let Some((name, _)) = adapters.iter().find(|(name, _)| !names.iter().any(|known| known == name)) {
+            return Err(unknown_adapter("set_adapter", name, &names));
+        }
+        for layer in self.layers.iter_mut().map(Rc::make_mut) {
+            for (_, projection) in layer.projections_mut() {
+                projection.set_active_adapters(adapters);
+            }
+        }
+        Ok(())
+    }
+
+    // Runs the bare base model until an adapter is set again.
+    pub fn disable_adapters(&mut self) {
+        for layer in self.layers.iter_mut().map(Rc::make_mut) {
+            for (_, projection) in layer.projections_mut() {
+                projection.set_active_adapters(&[]);
+            }
+        }
+    }
+
+    // Serves several requests in one pass: `x` packs requests of `lens` tokens
+    // one after another, each starting at position 0 and attending only to
+    // itself, and `routes` gives the adapter of each token (`None` for the base
+    // model), whatever the active adapters are. Checkpointing does not apply.
+    pub fn forward_routed(&self, x: &[usize], lens: &[usize], routes: &[Option<&str>]) -> Tensor {
+        self.try_forward_routed(x, lens, routes).unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    pub fn try_forward_routed(
+        &self,
+        x: &[usize],
+        lens: &[usize],
+        routes: &[Option<&str>],
+    ) -> Result<Tensor, TensorError> {
+        let positions: Vec<usize> = lens.iter().flat_map(|&len| 0..len).collect();
+        check_positions("forward_routed", x, &positions)?;
+        if routes.len() != x.len() {
+            return Err(TensorError::ShapeMismatch {
+                op: "forward_routed",
+                expected: vec![x.len()],
+                actual: vec![routes.len()],
+            });
+        }
+        let names = self.adapter_names();
+        if let Some(name) = routes.iter().flatten().find(|name| !names.iter().any(|known| known == *name)) {
+            return Err(unknown_adapter("forward_routed", name, &names));
+        }
+        if self.layers.iter().flat_map(|layer| layer.projections()).any(|(_, projection)| projection.is_merged()) {
+            return Err(TensorError::InvalidArgument {
+                op: "forward_routed",
+                message: "the adapters are merged into the base weights".into(),
+            });
+        }
+        // Causal within each request, and blind to the others.
+        let request: Vec<usize> = lens.iter().enumerate().flat_map(|(i, &len)| std::iter::repeat_n(i, len)).collect();
+        let same_request = Array2::from_shape_fn((x.len(), x.len()), |(i, j)| request[i] == request[j]);
+        let mask = SoftmaxMask::causal(x.len(), x.len()).combine(&same_request.into())?;
+        let mut h = self.embed(x);
         for layer in &self.layers {
-            h = layer.forward(&h);
+            h = layer.forward_inner(&h, &positions, Some(routes), Some(&mask), None);
+        }
+        Ok(h.rmsnorm(&*self.norm.to_dense(), 1e-5).matmul(&self.output))
+    }
+
+    // Folds every adapter into its projection's weight (see `LoraLinear::merge`),
+    // e.g. before serving the model or exporting it with `state_dict`.
+    pub fn merge_adapters(&mut self, requantize: bool) {
+        for layer in self.layers.iter_mut().map(Rc::make_mut) {
+            layer.merge_adapters(requantize);
+        }
+    }
+
+    pub fn unmerge_adapters(&mut self) {
+        for layer in self.layers.iter_mut().map(Rc::make_mut) {
+            layer.unmerge_adapters();
+        }
+    }
+
+    // Every adapter, named `layers.<i>.<target>`.
+    pub fn named_adapters(&self) -> Vec<(String, &LoraAdapter)> {
+        let mut adapters = Vec::new();
+        for (i, layer) in self.layers.iter().enumerate() {
+            for (name, adapter) in layer.adapters() {
+                adapters.push((format!("layers.{i}.{name}"), adapter));
+            }
+        }
+        adapters
+    }
+
+    pub fn named_adapters_mut(&mut self) -> Vec<(String, &mut LoraAdapter)> {
+        let mut adapters = Vec::new();
+        for (i, layer) in self.layers.iter_mut().map(Rc::make_mut).enumerate() {
+            for (name, adapter) in layer.adapters_mut() {
+                adapters.push((format!("layers.{i}.{name}"), adapter));
+            }
+        }
+        adapters
+    }
+
+    // Every default adapter (see `LoraAdapter::save_into`), without the base
+    // weights.
+    pub fn adapter_state_dict(&self) -> Model {
+        self.named_adapter_state_dict(DEFAULT_ADAPTER)
+    }
+
+    // Like `adapter_state_dict`, for the adapters called `name`.
+    pub fn named_adapter_state_dict(&self, name: &str) -> Model {
+        let mut model = Model::new();
+        for (i, layer) in self.layers.iter().enumerate() {
+            for (target, projection) in layer.projections() {
+                if let Some(adapter) = projection.get_adapter(name) {
+                    adapter.save_into(&mut model, &format!("layers.{i}.{target}"));
+                }
+            }
+        }
+        model
+    }
+
+    // Replaces every adapter with the one of the same name; the model needs
+    // the same adapted projections, e.g. from `get_peft_model`.
+    pub fn load_adapter_state_dict(&mut self, model: &Model) -> io::Result<()> {
+        for (name, adapter) in self.named_adapters_mut() {
+            adapter.load_from(model, &name)?;
+        }
+        Ok(())
+    }
+
+    pub fn named_weights(&self) -> Vec<(String, &Weight<T>)> {
+        let mut weights = vec![("embedding".to_string(), &self.embedding)];
+        for (i, layer) in self.layers.iter().enumerate() {
+            for (name, weight) in layer.named_weights() {
+                weights.push((format!("layers.{i}.{name}"), weight));
+            }
+        }
+        weights.push(("norm".to_string(), &self.norm));
+        weights.push(("output".to_string(), &self.output));
+        weights
+    }
+
+    pub fn named_weights_mut(&mut self) -> Vec<(String, &mut Weight<T>)> {
+        let mut weights = vec![("embedding".to_string(), &mut self.embedding)];
+        for (i, layer) in self.layers.iter_mut().map(Rc::make_mut).enumerate() {
+            for (name, weight) in layer.named_weights_mut() {
+                weights.push((format!("layers.{i}.{name}"), weight));
+            }
+        }
+        weights.push(("norm".to_string(), &mut self.norm));
+        weights.push(("output".to_string(), &mut self.output));
+        weights
+    }
+
+    // Snapshot of every weight, stored in the model's own dtype (quantized
+    // weights are kept quantized).
+    pub fn state_dict(&self) -> Model {
+        let mut model = Model::new();
+        for (name, weight) in self.named_weights() {
+            model.insert_weight(&name, weight);
+        }
+        model
+    }
+
+    // Replaces every weight with the tensor of the same name, converting it to
+    // `T` whatever dtype it was saved in. Quantized weights stay quantized.
+    pub fn load_state_dict(&mut self, model: &Model) -> io::Result<()> {
+        for (name, weight) in self.named_weights_mut() {
+            *weight = model.get_weight(&name).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("missing tensor `{name}`"))
+            })?;
         }
-        h = h.rmsnorm(&self.norm, 1e-5);
-        h.matmul(&self.output)
+        Ok(())
     }
 }
@@ -0,0 +1,153 @@
+use crate::core::{Element, QuantType, TensorError, Weight};
+use crate::kernels::fast_lora::{loftq_init, pissa_init, LoraAdapter};
+use crate::models::llama::LlamaModel;
+use std::collections::HashMap;
+
+// Every projection of a Llama decoder layer, as Unsloth targets by default.
+pub const LLAMA_TARGET_MODULES: [&str; 7] =
+    ["q_proj", "k_proj", "v_proj", "o_proj", "gate_proj", "up_proj", "down_proj"];
+
+// How fresh adapters start, as PEFT's `init_lora_weights`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum InitLoraWeights {
+    // A Kaiming-uniform and B zero (see `init_lora`), so the model is unchanged.
+    #[default]
+    Default,
+    // `pissa_init`: the adapter takes the principal part of each weight and the
+    // base keeps the residual.
+    Pissa,
+    // `loftq_init`: the base is quantized to `qtype`, with the adapter fitted to
+    // the quantization error over `iterations` rounds.
+    Loftq { qtype: QuantType, iterations: usize },
+}
+
+// Which projections get LoRA adapters and how they are set up, as PEFT's
+// `LoraConfig`. `r`, `lora_alpha` and `lora_dropout` apply to every target
+// unless overridden for it in the matching `*_pattern` map. `use_dora` makes
+// every adapter DoRA (see `LoraAdapter::with_dora`), and `init_lora_weights`
+// picks how the adapters start.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoraConfig {
+    pub r: usize,
+    pub lora_alpha: f32,
+    pub lora_dropout: f32,
+    pub use_rslora: bool,
+    pub use_dora: bool,
+    pub init_lora_weights: InitLoraWeights,
+    pub target_modules: Vec<String>,
+    pub rank_pattern: HashMap<String, usize>,
+    pub alpha_pattern: HashMap<String, f32>,
+    pub dropout_pattern: HashMap<String, f32>,
+}
+
+impl Default for LoraConfig {
+    fn default() -> Self {
+        LoraConfig {
+            r: 8,
+            lora_alpha: 8.0,
+            lora_dropout: 0.0,
+            use_rslora: false,
+            use_dora: false,
+            init_lora_weights: InitLoraWeights::Default,
+            target_modules: LLAMA_TARGET_MODULES.iter().map(|name| name.to_string()).collect(),
+            rank_pattern: HashMap::new(),
+            alpha_pattern: HashMap::new(),
+            dropout_pattern: HashMap::new(),
+        }
+    }
+}
+
+impl LoraConfig {
+    pub fn new(r: usize, lora_alpha: f32) -> Self {
+        LoraConfig {
+            r,
+            lora_alpha,
+            ..Self::default()
+        }
+    }
+
+    pub fn with_target_modules(mut self, target_modules: &[&str]) -> Self {
+        self.target_modules = target_modules.iter().map(|name| name.to_string()).collect();
+        self
+    }
+
+    pub fn with_lora_dropout(mut self, lora_dropout: f32) -> Self {
+        self.lora_dropout = lora_dropout;
+        self
+    }
+
+    pub fn with_rslora(mut self, use_rslora: bool) -> Self {
+        self.use_rslora = use_rslora;
+        self
+    }
+
+    pub fn with_dora(mut self, use_dora: bool) -> Self {
+        self.use_dora = use_dora;
+        self
+    }
+
+    pub fn with_init_lora_weights(mut self, init_lora_weights: InitLoraWeights) -> Self {
+        self.init_lora_weights = init_lora_weights;
+        self
+    }
+
+    pub fn with_rank_for(mut self, target: &str, r: usize) -> Self {
+        self.rank_pattern.insert(target.to_string(), r);
+        self
+    }
+
+    pub fn with_alpha_for(mut self, target: &str, lora_alpha: f32) -> Self {
+        self.alpha_pattern.insert(target.to_string(), lora_alpha);
+        self
+    }
+
+    pub fn with_dropout_for(mut self, target: &str, lora_dropout: f32) -> Self {
+        self.dropout_pattern.insert(target.to_string(), lora_dropout);
+        self
+    }
+
+    // A fresh adapter for the projection named `target` with base `weight`, or
+    // `None` if it is not targeted. With PiSSA or LoftQ, `weight` is replaced
+    // by the base the adapter was fitted to.
+    pub fn adapter_for<T: Element>(&self, target: &str, weight: &mut Weight<T>) -> Option<LoraAdapter> {
+        if !self.target_modules.iter().any(|t| t == target) {
+            return None;
+        }
+        let r = self.rank_pattern.get(target).copied().unwrap_or(self.r);
+        let lora_alpha = self.alpha_pattern.get(target).copied().unwrap_or(self.lora_alpha);
+        let lora_dropout = self.dropout_pattern.get(target).copied().unwrap_or(self.lora_dropout);
+        let (in_features, out_features) = (weight.shape()[0], weight.shape()[1]);
+        let mut adapter = LoraAdapter::new(in_features, out_features, r)
+            .with_lora_alpha(lora_alpha, self.use_rslora)
+            .with_dropout(lora_dropout);
+        let init = match self.init_lora_weights {
+            InitLoraWeights::Default => None,
+            InitLoraWeights::Pissa => Some(pissa_init(weight, r, adapter.scaling)),
+            InitLoraWeights::Loftq { qtype, iterations } => {
+                Some(loftq_init(weight, r, adapter.scaling, qtype, iterations))
+            }
+        };
+        if let Some(init) = init {
+            *weight = init.base;
+            adapter.lora_a = init.lora_a;
+            adapter.lora_b = init.lora_b;
+        }
+        if self.use_dora {
+            adapter = adapter.with_dora(weight);
+        }
+        adapter.lora_a.set_requires_grad(true);
+        adapter.lora_b.set_requires_grad(true);
+        if let Some(magnitude) = &mut adapter.magnitude {
+            magnitude.set_requires_grad(true);
+        }
+        Some(adapter)
+    }
+}
+
+// PEFT's `get_peft_model`: wraps the targeted projections of every layer with
+// fresh adapters, which are the only tensors on the tape; the base weights
+// stay frozen (and possibly quantized).
+pub fn get_peft_model<T: Element>(mut model: LlamaModel<T>, config: &LoraConfig) -> Result<LlamaModel<T>, TensorError> {
+    model.add_lora(config)?;
+    Ok(model)
+}
@@ -1,5 +1,5 @@
-use crate::core::Tensor;
 
+#[derive(Default)]
 pub struct PPO {
     // We will fill this in later.
 }
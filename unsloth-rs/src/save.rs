@@ -1,27 +1,226 @@
-use crate::core::Tensor;
+use crate::core::{bf16, f16, DType, Element, QuantType, QuantizedTensor, Tensor, Weight};
+use ndarray::{Array, IxDyn};
+use safetensors::tensor::{Dtype, SafeTensors, View};
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::fs;
+use std::io;
 
+// A named collection of tensors, saved to and loaded from safetensors files.
+// Each tensor keeps the dtype it was inserted with, and is converted to the
+// dtype the caller asks for when it is read back. Quantized weights are stored
+// as u8 blobs (see `QuantizedTensor::to_bytes`) and flagged in the metadata.
+// Other metadata entries are free-form strings, e.g. a LoRA adapter's scaling.
+#[derive(Default)]
 pub struct Model {
-    tensors: HashMap<String, Tensor>,
+    tensors: HashMap<String, StoredTensor>,
+    metadata: HashMap<String, String>,
+}
+
+struct StoredTensor {
+    format: Format,
+    shape: Vec<usize>,
+    bytes: Vec<u8>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Dense(DType),
+    Quantized(QuantType),
+}
+
+const QUANTIZED_PREFIX: &str = "quantized.";
+
+// Metadata key marking `name` as a quantized weight; the value is its qtype.
+fn quantized_key(name: &str) -> String {
+    format!("{QUANTIZED_PREFIX}{name}")
+}
+
+impl StoredTensor {
+    fn encode<T: Element>(tensor: &Tensor<T>) -> Self {
+        let mut bytes = Vec::with_capacity(tensor.data.len() * T::DTYPE.size_in_bytes());
+        for &x in tensor.data.iter() {
+            x.extend_le_bytes(&mut bytes);
+        }
+        StoredTensor {
+            format: Format::Dense(T::DTYPE),
+            shape: tensor.data.shape().to_vec(),
+            bytes,
+        }
+    }
+
+    fn encode_quantized(tensor: &QuantizedTensor) -> Self {
+        let bytes = tensor.to_bytes();
+        StoredTensor {
+            format: Format::Quantized(tensor.qtype()),
+            shape: vec![bytes.len()],
+            bytes,
+        }
+    }
+
+    fn decode<T: Element>(&self) -> Tensor<T> {
+        match self.decode_weight() {
+            Weight::Dense(tensor) => tensor,
+            Weight::Quantized(tensor) => tensor.dequantize().to_dtype(),
+        }
+    }
+
+    fn decode_weight<T: Element>(&self) -> Weight<T> {
+        let dtype = match self.format {
+            Format::Dense(dtype) => dtype,
+            // Blobs are validated when the file is loaded.
+            Format::Quantized(_) => {
+                return Weight::Quantized(QuantizedTensor::from_bytes(&self.bytes).unwrap());
+            }
+        };
+        let chunks = self.bytes.chunks_exact(dtype.size_in_bytes());
+        let values: Vec<T> = if dtype == T::DTYPE {
+            chunks.map(T::from_le_bytes).collect()
+        } else {
+            chunks.map(|chunk| T::from_f32(read_f32(dtype, chunk))).collect()
+        };
+        Weight::Dense(Tensor::from_array(Array::from_shape_vec(IxDyn(&self.shape), values).unwrap()))
+    }
+}
+
+fn read_f32(dtype: DType, bytes: &[u8]) -> f32 {
+    match dtype {
+        DType::F32 => <f32 as Element>::from_le_bytes(bytes),
+        DType::F16 => <f16 as Element>::from_le_bytes(bytes).to_f32(),
+        DType::BF16 => <bf16 as Element>::from_le_bytes(bytes).to_f32(),
+    }
+}
+
+impl View for &StoredTensor {
+    fn dtype(&self) -> Dtype {
+        match self.format {
+            Format::Dense(DType::F32) => Dtype::F32,
+            Format::Dense(DType::F16) => Dtype::F16,
+            Format::Dense(DType::BF16) => Dtype::BF16,
+            Format::Quantized(_) => Dtype::U8,
+        }
+    }
+
+    fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+
+    fn data(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.bytes)
+    }
+
+    fn data_len(&self) -> usize {
+        self.bytes.len()
+    }
+}
+
+fn invalid_data(err: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
 }
 
 impl Model {
     pub fn new() -> Self {
         Model {
             tensors: HashMap::new(),
+            metadata: HashMap::new(),
         }
     }
 
-    pub fn save(&self, filepath: &str) {
-        // We will implement this later.
-        println!("Saving model to {}", filepath);
+    pub fn insert<T: Element>(&mut self, name: &str, tensor: &Tensor<T>) {
+        self.tensors.insert(name.to_string(), StoredTensor::encode(tensor));
     }
 
-    pub fn load(filepath: &str) -> Self {
-        // We will implement this later.
-        println!("Loading model from {}", filepath);
-        Model {
-            tensors: HashMap::new(),
+    pub fn insert_weight<T: Element>(&mut self, name: &str, weight: &Weight<T>) {
+        let stored = match weight {
+            Weight::Dense(tensor) => StoredTensor::encode(tensor),
+            Weight::Quantized(tensor) => StoredTensor::encode_quantized(tensor),
+        };
+        self.tensors.insert(name.to_string(), stored);
+    }
+
+    // Reads a tensor back as `T`, whatever dtype it was stored in. Quantized
+    // weights are dequantized.
+    pub fn get<T: Element>(&self, name: &str) -> Option<Tensor<T>> {
+        self.tensors.get(name).map(StoredTensor::decode)
+    }
+
+    // Like `get`, but keeps quantized weights quantized.
+    pub fn get_weight<T: Element>(&self, name: &str) -> Option<Weight<T>> {
+        self.tensors.get(name).map(StoredTensor::decode_weight)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.tensors.contains_key(name)
+    }
+
+    // The dtype of a dense tensor; `None` if it is missing or quantized.
+    pub fn dtype(&self, name: &str) -> Option<DType> {
+        match self.tensors.get(name)?.format {
+            Format::Dense(dtype) => Some(dtype),
+            Format::Quantized(_) => None,
+        }
+    }
+
+    pub fn qtype(&self, name: &str) -> Option<QuantType> {
+        match self.tensors.get(name)?.format {
+            Format::Dense(_) => None,
+            Format::Quantized(qtype) => Some(qtype),
         }
     }
+
+    // Keys starting with "quantized." are reserved for the quantization flags.
+    pub fn insert_metadata(&mut self, key: &str, value: &str) {
+        self.metadata.insert(key.to_string(), value.to_string());
+    }
+
+    pub fn metadata(&self, key: &str) -> Option<&str> {
+        self.metadata.get(key).map(String::as_str)
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.tensors.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+
+    pub fn save(&self, filepath: &str) -> io::Result<()> {
+        let quantized = self.tensors.iter().filter_map(|(name, stored)| match stored.format {
+            Format::Quantized(qtype) => Some((quantized_key(name), qtype.to_string())),
+            Format::Dense(_) => None,
+        });
+        let metadata: HashMap<String, String> = self.metadata.clone().into_iter().chain(quantized).collect();
+        let metadata = (!metadata.is_empty()).then_some(metadata);
+        let bytes = safetensors::serialize(&self.tensors, &metadata).map_err(invalid_data)?;
+        fs::write(filepath, bytes)
+    }
+
+    pub fn load(filepath: &str) -> io::Result<Self> {
+        let bytes = fs::read(filepath)?;
+        let (_, header) = SafeTensors::read_metadata(&bytes).map_err(invalid_data)?;
+        let mut metadata = header.metadata().clone().unwrap_or_default();
+        let file = SafeTensors::deserialize(&bytes).map_err(invalid_data)?;
+
+        let mut tensors = HashMap::new();
+        for (name, view) in file.tensors() {
+            let format = match view.dtype() {
+                Dtype::F32 => Format::Dense(DType::F32),
+                Dtype::F16 => Format::Dense(DType::F16),
+                Dtype::BF16 => Format::Dense(DType::BF16),
+                Dtype::U8 if metadata.contains_key(&quantized_key(&name)) => {
+                    let tensor = QuantizedTensor::from_bytes(view.data()).map_err(invalid_data)?;
+                    Format::Quantized(tensor.qtype())
+                }
+                other => return Err(invalid_data(format!("unsupported dtype {other:?} for `{name}`"))),
+            };
+            let stored = StoredTensor {
+                format,
+                shape: view.shape().to_vec(),
+                bytes: view.data().to_vec(),
+            };
+            tensors.insert(name, stored);
+        }
+
+        metadata.retain(|key, _| !key.starts_with(QUANTIZED_PREFIX));
+        Ok(Model { tensors, metadata })
+    }
 }
@@ -1,13 +1,46 @@
+use crate::core::CheckpointMode;
 use crate::models::llama::LlamaModel;
-use crate::core::Tensor;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TrainerConfig {
+    // Off by default. `Offload` corresponds to Unsloth's
+    // `use_gradient_checkpointing="unsloth"`: the input of every decoder layer
+    // goes to disk and the layer is recomputed on backward.
+    pub gradient_checkpointing: CheckpointMode,
+}
+
+impl TrainerConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_gradient_checkpointing(mut self, mode: CheckpointMode) -> Self {
+        self.gradient_checkpointing = mode;
+        self
+    }
+}
 
 pub struct Trainer {
     model: LlamaModel,
+    config: TrainerConfig,
 }
 
 impl Trainer {
     pub fn new(model: LlamaModel) -> Self {
-        Trainer { model }
+        Self::with_config(model, TrainerConfig::default())
+    }
+
+    pub fn with_config(mut model: LlamaModel, config: TrainerConfig) -> Self {
+        model.set_checkpointing(config.gradient_checkpointing);
+        Trainer { model, config }
+    }
+
+    pub fn model(&self) -> &LlamaModel {
+        &self.model
+    }
+
+    pub fn config(&self) -> &TrainerConfig {
+        &self.config
     }
 
     pub fn train(&self) {
@@ -58,3 +58,853 @@ fn test_softmax() {
     let max_abs_diff = diff.mapv(f32::abs).iter().fold(0.0, |max, &val| val.max(max));
     assert!(max_abs_diff < 1e-6, "Softmax test failed");
 }
+
+#[test]
+fn test_softmax_normalizes_along_axis() {
+    let input = Tensor::new(array![[1.0, 2.0, 3.0], [0.0, 0.0, 0.0]].into_dyn());
+
+    let result = input.softmax(1);
+
+    let third = 1.0 / 3.0;
+    let expected = array![[0.09003057, 0.24472847, 0.66524096], [third, third, third]].into_dyn();
+    let diff = &result.data - &expected;
+    let max_abs_diff = diff.mapv(f32::abs).iter().fold(0.0, |max, &val| val.max(max));
+    assert!(max_abs_diff < 1e-6, "Softmax along axis 1 failed");
+}
+
+// Central finite differences of `f` with respect to every element of `input`.
+fn numeric_grad(input: &Tensor, f: impl Fn(&Tensor) -> f32) -> ndarray::ArrayD<f32> {
+    let eps = 1e-2;
+    let mut grad = ndarray::ArrayD::zeros(input.data.raw_dim());
+    for (idx, g) in grad.indexed_iter_mut() {
+        let mut plus = input.data.to_owned();
+        plus[&idx] += eps;
+        let mut minus = input.data.to_owned();
+        minus[&idx] -= eps;
+        *g = (f(&Tensor::new(plus)) - f(&Tensor::new(minus))) / (2.0 * eps);
+    }
+    grad
+}
+
+fn assert_close(actual: &ndarray::ArrayD<f32>, expected: &ndarray::ArrayD<f32>, tol: f32) {
+    assert_eq!(actual.shape(), expected.shape());
+    let max_abs_diff = (actual - expected).mapv(f32::abs).iter().fold(0.0, |max, &val| val.max(max));
+    assert!(max_abs_diff < tol, "Gradient check failed. Max diff: {}", max_abs_diff);
+}
+
+#[test]
+fn test_backward_matmul_add_mul_silu() {
+    let mut x = Tensor::new(array![[0.5, -1.0, 2.0], [1.5, 0.25, -0.75]].into_dyn());
+    let mut w = Tensor::new(array![[0.1, -0.2], [0.3, 0.4], [-0.5, 0.6]].into_dyn());
+    let bias = Tensor::new(array![0.05, -0.1].into_dyn());
+    x.set_requires_grad(true);
+    w.set_requires_grad(true);
+
+    let f = |x: &Tensor, w: &Tensor| {
+        let h = x.matmul(w).add(&bias);
+        h.silu().mul(&h).sum()
+    };
+    f(&x, &w).backward();
+
+    assert!(bias.grad().is_none());
+    assert_close(&x.grad().unwrap(), &numeric_grad(&x, |x| f(x, &w).data[[]]), 1e-3);
+    assert_close(&w.grad().unwrap(), &numeric_grad(&w, |w| f(&x, w).data[[]]), 1e-3);
+}
+
+#[test]
+fn test_backward_rmsnorm_rope_softmax() {
+    let mut x = Tensor::new(array![[1.0, 2.0, 3.0, 4.0], [-0.5, 0.5, 1.5, -2.0]].into_dyn());
+    let mut weight = Tensor::new(array![0.1, 0.2, 0.3, 0.4].into_dyn());
+    let probe = Tensor::new(array![[1.0, -2.0, 3.0, 0.5], [0.0, 1.0, -1.0, 2.0]].into_dyn());
+    x.set_requires_grad(true);
+    weight.set_requires_grad(true);
+
+    let f = |x: &Tensor, weight: &Tensor| {
+        x.rmsnorm(weight, 1e-5).rope(3, 4, 10, 10000.0).softmax(1).mul(&probe).sum()
+    };
+    f(&x, &weight).backward();
+
+    assert_close(&x.grad().unwrap(), &numeric_grad(&x, |x| f(x, &weight).data[[]]), 1e-3);
+    assert_close(
+        &weight.grad().unwrap(),
+        &numeric_grad(&weight, |w| f(&x, w).data[[]]),
+        1e-3,
+    );
+}
+
+#[test]
+fn test_backward_accumulates_and_no_grad() {
+    let mut x = Tensor::new(array![1.0, 2.0, 3.0].into_dyn());
+    x.set_requires_grad(true);
+
+    x.mul(&x).sum().backward();
+    x.mul(&x).sum().backward();
+    assert_close(&x.grad().unwrap(), &array![4.0, 8.0, 12.0].into_dyn(), 1e-6);
+
+    x.zero_grad();
+    let y = unsloth_rs::core::no_grad(|| x.mul(&x));
+    assert!(!y.requires_grad());
+    y.backward();
+    assert!(x.grad().is_none());
+}
+
+#[test]
+fn test_batched_matmul_broadcasts() {
+    let a_data = ndarray::Array::from_shape_fn((2, 1, 3, 4), |(i, j, k, l)| {
+        (i * 7 + j * 5 + k * 3 + l) as f32 * 0.1 - 1.0
+    });
+    let b_data = ndarray::Array::from_shape_fn((3, 4, 2), |(i, j, k)| (i * 4 + j * 2 + k) as f32 * 0.05);
+    let a = Tensor::new(a_data.clone().into_dyn());
+    let b = Tensor::new(b_data.clone().into_dyn());
+
+    let result = a.matmul(&b);
+    assert_eq!(result.data.shape(), &[2, 3, 3, 2]);
+    for i in 0..2 {
+        for j in 0..3 {
+            let a_mat: ndarray::ArrayView2<f32> = a_data.slice(ndarray::s![i, 0, .., ..]);
+            let b_mat: ndarray::ArrayView2<f32> = b_data.slice(ndarray::s![j, .., ..]);
+            let expected = a_mat.dot(&b_mat);
+            let actual = result.data.slice(ndarray::s![i, j, .., ..]);
+            assert_close(&actual.to_owned().into_dyn(), &expected.into_dyn(), 1e-6);
+        }
+    }
+}
+
+#[test]
+fn test_matmul_vector_operands() {
+    let m = Tensor::new(array![[1.0, 2.0, 3.0], [4.0, 5.0, 6.0]].into_dyn());
+    let v = Tensor::new(array![1.0, 0.5, -1.0].into_dyn());
+    let u = Tensor::new(array![2.0, -1.0].into_dyn());
+
+    assert_close(&m.matmul(&v).data.to_owned(), &array![-1.0, 0.5].into_dyn(), 1e-6);
+    assert_close(&u.matmul(&m).data.to_owned(), &array![-2.0, -1.0, 0.0].into_dyn(), 1e-6);
+    assert_eq!(v.matmul(&v).data.shape(), &[] as &[usize]);
+    assert!((v.matmul(&v).data[[]] - 2.25).abs() < 1e-6);
+}
+
+#[test]
+fn test_backward_batched_matmul() {
+    let mut a = Tensor::new(
+        ndarray::Array::from_shape_fn((2, 2, 3), |(i, j, k)| (i + 2 * j) as f32 * 0.3 - k as f32 * 0.2)
+            .into_dyn(),
+    );
+    let mut b = Tensor::new(
+        ndarray::Array::from_shape_fn((1, 3, 2), |(_, j, k)| j as f32 * 0.5 - k as f32 * 0.25).into_dyn(),
+    );
+    let mut w = Tensor::new(array![[0.2, -0.1], [0.4, 0.3], [-0.6, 0.5]].into_dyn());
+    a.set_requires_grad(true);
+    b.set_requires_grad(true);
+    w.set_requires_grad(true);
+
+    let f = |a: &Tensor, b: &Tensor, w: &Tensor| {
+        let batched = a.matmul(b).silu().sum();
+        let shared = a.matmul(w).transpose(0, 2).reshape(&[8]);
+        batched.add(&shared.mul(&shared).scale(0.5).sum())
+    };
+    f(&a, &b, &w).backward();
+
+    assert_close(&a.grad().unwrap(), &numeric_grad(&a, |a| f(a, &b, &w).data[[]]), 1e-2);
+    assert_close(&b.grad().unwrap(), &numeric_grad(&b, |b| f(&a, b, &w).data[[]]), 1e-2);
+    assert_close(&w.grad().unwrap(), &numeric_grad(&w, |w| f(&a, &b, w).data[[]]), 1e-2);
+}
+
+#[test]
+fn test_try_ops_report_shape_errors() {
+    use unsloth_rs::core::TensorError;
+
+    let a = Tensor::new(ndarray::Array::zeros(ndarray::IxDyn(&[2, 3])));
+    let b = Tensor::new(ndarray::Array::zeros(ndarray::IxDyn(&[4, 5])));
+    assert_eq!(
+        a.try_matmul(&b).unwrap_err(),
+        TensorError::IncompatibleShapes { op: "matmul", lhs: vec![2, 3], rhs: vec![4, 5] }
+    );
+    assert_eq!(
+        a.try_add(&b).unwrap_err(),
+        TensorError::IncompatibleShapes { op: "add", lhs: vec![2, 3], rhs: vec![4, 5] }
+    );
+
+    let weight = Tensor::new(ndarray::Array::zeros(ndarray::IxDyn(&[4])));
+    assert_eq!(
+        a.try_rmsnorm(&weight, 1e-5).unwrap_err(),
+        TensorError::ShapeMismatch { op: "rmsnorm", expected: vec![3], actual: vec![4] }
+    );
+    assert_eq!(
+        a.try_reshape(&[4, 2]).unwrap_err(),
+        TensorError::ShapeMismatch { op: "reshape", expected: vec![4, 2], actual: vec![2, 3] }
+    );
+    assert_eq!(
+        a.try_softmax(2).unwrap_err(),
+        TensorError::InvalidAxis { op: "softmax", axis: 2, ndim: 2 }
+    );
+    assert!(matches!(a.try_rope(0, 3, 10, 10000.0), Err(TensorError::InvalidArgument { op: "rope", .. })));
+    assert!(a.try_matmul(&Tensor::new(ndarray::Array::zeros(ndarray::IxDyn(&[3, 5])))).is_ok());
+}
+
+#[test]
+#[should_panic(expected = "matmul: incompatible shapes [2, 3] and [4, 5]")]
+fn test_matmul_panics_with_tensor_error() {
+    let a = Tensor::new(ndarray::Array::zeros(ndarray::IxDyn(&[2, 3])));
+    let b = Tensor::new(ndarray::Array::zeros(ndarray::IxDyn(&[4, 5])));
+    a.matmul(&b);
+}
+
+#[test]
+fn test_half_precision_matmul_accumulates_in_f32() {
+    use unsloth_rs::core::{bf16, f16, DType};
+
+    // Wider than one upcast block, so the blocked path is exercised.
+    let x = Tensor::new(ndarray::Array::from_shape_fn((3, 8), |(i, j)| (i * 8 + j) as f32 * 0.01 - 0.1).into_dyn());
+    let w = Tensor::new(
+        ndarray::Array::from_shape_fn((8, 300), |(i, j)| ((i * 300 + j) % 17) as f32 * 0.125 - 1.0).into_dyn(),
+    );
+
+    let w_bf16 = w.to_dtype::<bf16>();
+    let w_f16 = w.to_dtype::<f16>();
+    assert_eq!(w_bf16.dtype(), DType::BF16);
+    assert_eq!(w_f16.dtype(), DType::F16);
+
+    // Multiples of 1/8 in [-1, 1] are exact in both formats.
+    let expected = x.matmul(&w);
+    assert_close(&x.matmul(&w_bf16).data.to_owned(), &expected.data.to_owned(), 1e-5);
+    assert_close(&x.matmul(&w_f16).data.to_owned(), &expected.data.to_owned(), 1e-5);
+    assert_close(&w_bf16.to_f32().data.to_owned(), &w.data.to_owned(), 1e-7);
+
+    let weight = Tensor::new(array![0.5, 0.25, -1.0, 2.0, 0.5, 0.25, 1.0, 0.125].into_dyn());
+    assert_close(
+        &x.rmsnorm(&weight.to_dtype::<bf16>(), 1e-5).data.to_owned(),
+        &x.rmsnorm(&weight, 1e-5).data.to_owned(),
+        1e-6,
+    );
+}
+
+#[test]
+fn test_backward_through_half_precision_weight() {
+    use unsloth_rs::core::bf16;
+
+    let mut x = Tensor::new(array![[0.5, -1.0], [2.0, 0.25]].into_dyn());
+    x.set_requires_grad(true);
+    let w = Tensor::new(array![[1.0, 0.5, -2.0], [0.25, 4.0, 1.0]].into_dyn());
+
+    x.matmul(&w.to_dtype::<bf16>()).sum().backward();
+    assert_close(&x.grad().unwrap(), &array![[-0.5, 5.25], [-0.5, 5.25]].into_dyn(), 1e-6);
+}
+
+// Deterministic values in [-1, 1) with no obvious structure.
+fn pseudo_random(shape: &[usize], seed: usize) -> Tensor {
+    let n: usize = shape.iter().product();
+    let values = (0..n).map(|i| ((i * 7919 + seed * 104_729) % 1000) as f32 / 500.0 - 1.0).collect();
+    Tensor::new(ndarray::Array::from_shape_vec(ndarray::IxDyn(shape), values).unwrap())
+}
+
+#[test]
+fn test_quantize_round_trip_error() {
+    use unsloth_rs::core::{QuantType, QuantizedTensor};
+
+    let mut weight = pseudo_random(&[32, 48], 1);
+    // One block of larger values and one of zeros, to exercise the absmax scales.
+    weight.data.as_slice_mut().unwrap()[..64].iter_mut().for_each(|x| *x *= 3.0);
+    weight.data.as_slice_mut().unwrap()[64..128].fill(0.0);
+
+    for (qtype, tol) in [(QuantType::Nf4, 0.16), (QuantType::Int8, 0.005)] {
+        let quantized = QuantizedTensor::quantize(&weight, qtype);
+        assert_eq!(quantized.shape(), &[32, 48]);
+        let restored = quantized.dequantize();
+        let blocks = weight.data.as_slice().unwrap().chunks(64);
+        for (block, restored_block) in blocks.zip(restored.data.as_slice().unwrap().chunks(64)) {
+            let absmax = block.iter().fold(0.0f32, |m, x| m.max(x.abs()));
+            for (x, y) in block.iter().zip(restored_block) {
+                assert!((x - y).abs() <= tol * absmax + 1e-6, "{qtype}: {x} restored as {y}");
+            }
+        }
+        assert!(restored.data.as_slice().unwrap()[64..128].iter().all(|&x| x == 0.0));
+    }
+
+    // 4 bits per value plus about one byte of scale per block.
+    let nf4 = QuantizedTensor::quantize(&weight, QuantType::Nf4);
+    assert!(nf4.size_in_bytes() < 32 * 48 / 2 + 32 * 48 / 64 + 16);
+}
+
+#[test]
+fn test_quantized_tensor_bytes_round_trip() {
+    use unsloth_rs::core::{QuantType, QuantizedTensor};
+
+    for qtype in [QuantType::Nf4, QuantType::Int8] {
+        let quantized = QuantizedTensor::try_quantize(&pseudo_random(&[5, 7], 2), qtype, 16).unwrap();
+        let bytes = quantized.to_bytes();
+        assert_eq!(QuantizedTensor::from_bytes(&bytes).unwrap(), quantized);
+        assert!(QuantizedTensor::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+
+        // The block size follows the type tag; only NF4 needs it even.
+        let mut odd = bytes.clone();
+        odd[1] = 15;
+        let result = QuantizedTensor::from_bytes(&odd);
+        match qtype {
+            QuantType::Nf4 => {
+                let message = "QuantizedTensor::from_bytes: invalid block size 15 for nf4";
+                assert_eq!(result.unwrap_err().to_string(), message);
+            }
+            QuantType::Int8 => assert_eq!(result.unwrap().block_size(), 15),
+        }
+    }
+    assert!(QuantizedTensor::try_quantize(&pseudo_random(&[4], 0), QuantType::Nf4, 3).is_err());
+}
+
+#[test]
+fn test_quantized_matmul_matches_dequantized() {
+    use unsloth_rs::core::{QuantType, QuantizedTensor, Weight};
+
+    // More rows than are dequantized at a time, to cover the banding.
+    let weight = pseudo_random(&[150, 6], 3);
+    let mut x = pseudo_random(&[2, 3, 150], 4);
+    x.set_requires_grad(true);
+
+    for qtype in [QuantType::Nf4, QuantType::Int8] {
+        let quantized = QuantizedTensor::quantize(&weight, qtype);
+        let dense = quantized.dequantize();
+        let expected = x.detach().matmul(&dense);
+
+        let weight: Weight = quantized.into();
+        let out = x.matmul(&weight);
+        assert_close(&out.data.to_owned(), &expected.data.to_owned(), 1e-4);
+
+        x.zero_grad();
+        out.sum().backward();
+        let numeric = numeric_grad(&x, |x| x.matmul(&dense).sum().data.sum());
+        assert_close(&x.grad().unwrap(), &numeric, 1e-2);
+    }
+
+    let err = pseudo_random(&[2, 5], 0).try_matmul(&QuantizedTensor::quantize(&weight, QuantType::Int8));
+    assert_eq!(err.unwrap_err().to_string(), "matmul: incompatible shapes [2, 5] and [150, 6]");
+}
+
+#[test]
+fn test_multithreaded_matmul_matches_single_threaded() {
+    use ndarray::{ArrayD, ArrayView2, Axis, Ix2};
+
+    // Large enough to be split into tiles; run on a multi-thread pool even on
+    // single-core machines. Tensors are not `Send`, so they are built inside it.
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+    let matmul = |a: &Tensor, b: &Tensor| {
+        let (a, b) = (a.data.to_owned(), b.data.to_owned());
+        pool.install(move || Tensor::new(a).matmul(&Tensor::new(b)).data.to_owned())
+    };
+    let reference = |a: ArrayD<f32>, b: ArrayD<f32>| {
+        let a: ArrayView2<f32> = a.view().into_dimensionality::<Ix2>().unwrap();
+        let b: ArrayView2<f32> = b.view().into_dimensionality::<Ix2>().unwrap();
+        a.dot(&b).into_dyn()
+    };
+
+    let (tall, wide) = (pseudo_random(&[300, 70], 1), pseudo_random(&[70, 50], 2));
+    let (short, wider) = (pseudo_random(&[5, 70], 3), pseudo_random(&[70, 900], 4));
+    assert_close(&matmul(&tall, &wide), &reference(tall.data.to_owned(), wide.data.to_owned()), 1e-4);
+    assert_close(&matmul(&short, &wider), &reference(short.data.to_owned(), wider.data.to_owned()), 1e-4);
+
+    // Batched: every matrix of the batch is computed on its own task.
+    let a = pseudo_random(&[6, 40, 30], 5);
+    let b = pseudo_random(&[6, 30, 20], 6);
+    let out = matmul(&a, &b);
+    for i in 0..6 {
+        let expected = reference(a.data.index_axis(Axis(0), i).to_owned(), b.data.index_axis(Axis(0), i).to_owned());
+        assert_close(&out.index_axis(Axis(0), i).to_owned(), &expected, 1e-4);
+    }
+}
+
+#[test]
+fn test_rope_positions_and_layouts() {
+    use unsloth_rs::core::{RopeConfig, RopeLayout, TensorError};
+
+    let x = Tensor::new(array![[1.0, 2.0, 3.0, 4.0, 5.0, 6.0], [7.0, 8.0, 9.0, 10.0, 11.0, 12.0]].into_dyn());
+    // Partial rotary: only the first 4 of 6 features are rotated.
+    let half_split = RopeConfig::new(4, 10000.0);
+    let interleaved = half_split.clone().with_layout(RopeLayout::Interleaved);
+
+    let expected = array![
+        [-3.1440391, 1.9196053, -0.3391431, 4.0391974, 5.0, 6.0],
+        [10.615954, 7.4902104, -4.15951, 10.387336, 11.0, 12.0],
+    ];
+    assert_close(&x.rope_at(&[2, 5], &half_split).data.to_owned(), &expected.into_dyn(), 1e-4);
+
+    let expected = array![
+        [-2.2347417, 0.07700375, 2.9194054, 4.059196, 5.0, 6.0],
+        [9.657029, -4.4431724, 8.48896, 10.437315, 11.0, 12.0],
+    ];
+    assert_close(&x.rope_at(&[2, 5], &interleaved).data.to_owned(), &expected.into_dyn(), 1e-4);
+
+    // A scalar position is the same as repeating it for every row.
+    assert_eq!(x.rope(3, 4, 10, 10000.0).data, x.rope_at(&[3, 3], &interleaved).data);
+
+    // [seq, n_heads, head_dim]: every head of a token uses the token's position.
+    let heads = Tensor::new(ndarray::stack![ndarray::Axis(1), x.data.view(), x.data.view().mapv(|v| -v)]);
+    let rotated = heads.rope_at(&[2, 5], &half_split);
+    let first = x.rope_at(&[2, 5], &half_split).data.to_owned();
+    assert_close(&rotated.data.index_axis(ndarray::Axis(1), 0).to_owned(), &first, 1e-6);
+    assert_close(&rotated.data.index_axis(ndarray::Axis(1), 1).to_owned(), &first.mapv(|v| -v), 1e-6);
+
+    assert!(matches!(x.try_rope_at(&[0], &half_split), Err(TensorError::ShapeMismatch { op: "rope", .. })));
+}
+
+#[test]
+fn test_backward_rope_at() {
+    use unsloth_rs::core::RopeConfig;
+
+    let mut x = pseudo_random(&[3, 2, 6], 7);
+    x.set_requires_grad(true);
+    let probe = pseudo_random(&[3, 2, 6], 8);
+    let config = RopeConfig::new(4, 100.0);
+    let f = |x: &Tensor| x.rope_at(&[4, 0, 9], &config).mul(&probe).sum();
+
+    f(&x).backward();
+    let numeric = numeric_grad(&x, |x| f(x).data.sum());
+    assert_close(&x.grad().unwrap(), &numeric, 1e-2);
+}
+
+// Reference values below were computed with the `rope_scaling` formulas of HF
+// transformers (`modeling_rope_utils.py`) in float64.
+#[test]
+fn test_rope_scaling_frequencies() {
+    use unsloth_rs::core::{RopeConfig, RopeScaling};
+
+    let assert_freqs = |actual: Vec<f32>, expected: &[f64]| {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert!(((*a as f64) - e).abs() <= 1e-5 * e.abs(), "{actual:?} != {expected:?}");
+        }
+    };
+    let config = RopeConfig::new(16, 10000.0);
+    let base = [1.0, 0.316_227_766, 0.1, 0.031_622_777, 0.01, 0.003_162_278, 0.001, 0.000_316_228];
+    assert_freqs(config.inv_freq(100), &base);
+
+    let linear = config.clone().with_scaling(RopeScaling::Linear { factor: 4.0 });
+    assert_freqs(linear.inv_freq(100), &base.map(|f| f / 4.0));
+
+    let dynamic = config.clone().with_scaling(RopeScaling::DynamicNtk {
+        factor: 2.0,
+        original_max_position_embeddings: 64,
+    });
+    assert_freqs(dynamic.inv_freq(64), &base);
+    assert_freqs(
+        dynamic.inv_freq(100),
+        &[1.0, 0.283_945_139, 0.080_624_842, 0.022_893_032, 0.006_500_365, 0.001_845_747, 0.000_524_091, 0.000_148_813],
+    );
+
+    let yarn = config.clone().with_scaling(RopeScaling::yarn(4.0, 64));
+    assert_freqs(
+        yarn.inv_freq(100),
+        &[1.0, 0.237_170_825, 0.05, 0.007_905_694, 0.0025, 0.000_790_569, 0.000_25, 0.000_079_056_94],
+    );
+    assert!((yarn.attention_scaling() - 1.138_629_4).abs() < 1e-6);
+    assert_eq!(linear.attention_scaling(), 1.0);
+
+    let llama3 = config.with_scaling(RopeScaling::Llama3 {
+        factor: 8.0,
+        low_freq_factor: 1.0,
+        high_freq_factor: 4.0,
+        original_max_position_embeddings: 64,
+    });
+    assert_freqs(
+        llama3.inv_freq(100),
+        &[1.0, 0.244_384_599, 0.013_042_256, 0.003_952_847, 0.001_25, 0.000_395_285, 0.000_125, 0.000_039_528_47],
+    );
+}
+
+#[test]
+fn test_rope_scaling_in_rope_at() {
+    use unsloth_rs::core::{RopeConfig, RopeScaling};
+
+    let x = pseudo_random(&[3, 2, 16], 9);
+    let config = RopeConfig::new(16, 10000.0);
+
+    // Linear scaling is the same as dividing the positions by the factor.
+    let linear = config.clone().with_scaling(RopeScaling::Linear { factor: 2.0 });
+    let expected = x.rope_at(&[0, 2, 5], &config).data.to_owned();
+    assert_close(&x.rope_at(&[0, 4, 10], &linear).data.to_owned(), &expected, 1e-5);
+
+    // Dynamic NTK only kicks in past the original context.
+    let dynamic = config.clone().with_scaling(RopeScaling::DynamicNtk {
+        factor: 2.0,
+        original_max_position_embeddings: 8,
+    });
+    assert_eq!(x.rope_at(&[0, 3, 7], &dynamic).data, x.rope_at(&[0, 3, 7], &config).data);
+    assert_ne!(x.rope_at(&[0, 3, 9], &dynamic).data, x.rope_at(&[0, 3, 9], &config).data);
+    let narrow = RopeConfig { rotary_dim: 2, ..dynamic.clone() };
+    let err = x.try_rope_at(&[0, 3, 9], &narrow).unwrap_err();
+    assert_eq!(err.to_string(), "rope: dynamic NTK scaling needs a rotary_dim of at least 4, got 2");
+
+    // YaRN's attention temperature scales the rotated features; position 0 is
+    // otherwise left unrotated.
+    let yarn = config.with_scaling(RopeScaling::yarn(4.0, 64));
+    let out = x.rope_at(&[0, 1, 2], &yarn);
+    let scale = yarn.attention_scaling();
+    let first = out.data.index_axis(ndarray::Axis(0), 0).to_owned();
+    assert_close(&first, &x.data.index_axis(ndarray::Axis(0), 0).mapv(|v| v * scale), 1e-5);
+    let norm = |a: ndarray::ArrayViewD<f32>| a.mapv(|v| v * v).sum().sqrt();
+    let (rotated, original) = (out.data.index_axis(ndarray::Axis(0), 2), x.data.index_axis(ndarray::Axis(0), 2));
+    assert!((norm(rotated) - scale * norm(original)).abs() < 1e-4);
+
+    // The scaled rotation still backpropagates as its transpose.
+    let mut x = x;
+    x.set_requires_grad(true);
+    let probe = pseudo_random(&[3, 2, 16], 10);
+    let f = |x: &Tensor| x.rope_at(&[0, 1, 2], &yarn).mul(&probe).sum();
+    f(&x).backward();
+    assert_close(&x.grad().unwrap(), &numeric_grad(&x, |x| f(x).data.sum()), 1e-2);
+}
+
+#[test]
+fn test_log_softmax_and_logsumexp() {
+    let x = Tensor::new(array![[1.0, 2.0, 3.0], [1000.0, 1001.0, 1002.0]].into_dyn());
+
+    // Stable for large inputs, where exp overflows.
+    let expected = array![[-2.407606, -1.4076059, -0.40760595], [-2.407606, -1.4076059, -0.40760595]];
+    assert_close(&x.log_softmax(1).data.to_owned(), &expected.into_dyn(), 1e-4);
+    assert_close(&x.logsumexp(1).data.to_owned(), &array![3.407606, 1002.4076].into_dyn(), 1e-4);
+    assert_close(&x.logsumexp(0).data.to_owned(), &array![1000.0, 1001.0, 1002.0].into_dyn(), 1e-4);
+
+    let small = Tensor::new(array![[0.5, -1.0, 2.0], [0.0, 0.3, -0.2]].into_dyn());
+    let log_of_softmax = small.softmax(1).data.mapv(f32::ln);
+    assert_close(&small.log_softmax(1).data.to_owned(), &log_of_softmax, 1e-5);
+
+    // Fully `-inf` lanes: logsumexp is -inf and log_softmax stays -inf.
+    let masked = Tensor::new(array![[f32::NEG_INFINITY, f32::NEG_INFINITY], [0.0, f32::NEG_INFINITY]].into_dyn());
+    assert_eq!(masked.logsumexp(1).data, array![f32::NEG_INFINITY, 0.0].into_dyn().into_shared());
+    assert!(masked.log_softmax(1).data.iter().all(|v| !v.is_nan()));
+
+    let mut x = pseudo_random(&[2, 3, 4], 11);
+    x.set_requires_grad(true);
+    let probe = pseudo_random(&[2, 3, 4], 12);
+    let f = |x: &Tensor| x.log_softmax(2).mul(&probe).sum();
+    f(&x).backward();
+    assert_close(&x.grad().unwrap(), &numeric_grad(&x, |x| f(x).data.sum()), 1e-2);
+
+    x.zero_grad();
+    let probe = pseudo_random(&[2, 4], 13);
+    let g = |x: &Tensor| x.logsumexp(1).mul(&probe).sum();
+    g(&x).backward();
+    assert_close(&x.grad().unwrap(), &numeric_grad(&x, |x| g(x).data.sum()), 1e-2);
+}
+
+#[test]
+fn test_masked_softmax() {
+    use unsloth_rs::core::{SoftmaxMask, TensorError};
+
+    let x = pseudo_random(&[2, 3, 3], 14);
+    // A causal mask over the last two axes, broadcast over the first.
+    let causal = ndarray::Array2::from_shape_fn((3, 3), |(i, j)| j <= i);
+    let y = x.masked_softmax(2, &causal.clone().into());
+
+    for b in 0..2 {
+        for i in 0..3 {
+            let row = x.data.slice(ndarray::s![b, i, ..=i]).to_owned().into_dyn();
+            let expected = Tensor::new(row).softmax(0);
+            let actual = y.data.slice(ndarray::s![b, i, ..]);
+            assert_close(&actual.slice(ndarray::s![..=i]).to_owned().into_dyn(), &expected.data.to_owned(), 1e-6);
+            assert!(actual.slice(ndarray::s![i + 1..]).iter().all(|&v| v == 0.0));
+        }
+    }
+
+    // An additive `-inf` mask is the same as the boolean one.
+    let additive = causal.mapv(|keep| if keep { 0.0 } else { f32::NEG_INFINITY });
+    assert_eq!(x.masked_softmax(2, &additive.into()).data, y.data);
+    // A finite additive mask is a bias.
+    let bias = array![0.5, -1.0, 0.0];
+    let biased = Tensor::new(&x.data + &bias).softmax(2);
+    assert_close(&x.masked_softmax(2, &bias.into()).data.to_owned(), &biased.data.to_owned(), 1e-6);
+
+    // A fully masked row gives zeros, and no NaN in the gradient either.
+    let mut x = pseudo_random(&[2, 3], 15);
+    x.set_requires_grad(true);
+    let mask: SoftmaxMask = array![[true, false, true], [false, false, false]].into();
+    let y = x.masked_softmax(1, &mask);
+    assert!(y.data.index_axis(ndarray::Axis(0), 1).iter().all(|&v| v == 0.0));
+    let probe = pseudo_random(&[2, 3], 16);
+    let f = |x: &Tensor| x.masked_softmax(1, &mask).mul(&probe).sum();
+    f(&x).backward();
+    let grad = x.grad().unwrap();
+    assert!(grad.iter().all(|g| g.is_finite()));
+    assert_close(&grad, &numeric_grad(&x, |x| f(x).data.sum()), 1e-2);
+
+    let bad: SoftmaxMask = array![true, false].into();
+    assert!(matches!(
+        x.try_masked_softmax(1, &bad),
+        Err(TensorError::IncompatibleShapes { op: "masked_softmax", .. })
+    ));
+}
+
+#[test]
+fn test_with_backend_selects_and_restores() {
+    use std::sync::Arc;
+    use unsloth_rs::core::{current_backend, set_backend, with_backend, CpuBackend};
+
+    let default = current_backend().name();
+    let inner = with_backend(Arc::new(CpuBackend), || {
+        let y = Tensor::new(array![1.0, 2.0].into_dyn()).scale(2.0);
+        assert_eq!(y.data, array![2.0, 4.0].into_dyn());
+        current_backend().name()
+    });
+    assert_eq!(inner, "cpu");
+    assert_eq!(current_backend().name(), default);
+
+    // Other threads keep their own selection.
+    set_backend(Arc::new(CpuBackend));
+    assert_eq!(current_backend().name(), "cpu");
+    let other = std::thread::spawn(|| current_backend().name()).join().unwrap();
+    assert_eq!(other, default);
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn test_parallel_backend_matches_cpu_backend() {
+    use ndarray::{Array, ArrayD};
+    use std::sync::Arc;
+    use unsloth_rs::core::{with_backend, Backend, CpuBackend, ParallelCpuBackend, RopeConfig};
+
+    // [tokens, heads, head_dim], large enough for the elementwise and lane-wise
+    // ops to be split across a multi-thread pool.
+    let x = pseudo_random(&[64, 8, 64], 1).data.to_owned();
+    let w = pseudo_random(&[64, 64], 2).data.to_owned();
+    let norm = pseudo_random(&[64], 3).data.to_owned();
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+    let run = |backend: Arc<dyn Backend>| -> Vec<ArrayD<f32>> {
+        let (x, w, norm) = (x.clone(), w.clone(), norm.clone());
+        pool.install(move || {
+            with_backend(backend, || {
+                let mut x = Tensor::new(x);
+                x.set_requires_grad(true);
+                let mut w = Tensor::new(w);
+                w.set_requires_grad(true);
+                let positions: Vec<usize> = (0..64).collect();
+                let h = x.rmsnorm(&Tensor::new(norm), 1e-5).matmul(&w).silu().mul(&x).add(&x.scale(0.5));
+                let h = h.rope_at(&positions, &RopeConfig::new(64, 10000.0)).scale(0.125);
+                // [tokens, heads, heads] scores, one batched matmul per token.
+                let scores = h.matmul(&h.transpose(1, 2));
+                let causal = Array::from_shape_fn((8, 8), |(i, j)| j <= i);
+                let loss = scores
+                    .masked_softmax(2, &causal.into())
+                    .mul(&scores.log_softmax(2))
+                    .sum()
+                    .add(&scores.softmax(1).logsumexp(2).sum());
+                loss.backward();
+                vec![loss.data.to_owned(), x.grad().unwrap(), w.grad().unwrap()]
+            })
+        })
+    };
+
+    let expected = run(Arc::new(CpuBackend));
+    let actual = run(Arc::new(ParallelCpuBackend));
+    for (actual, expected) in actual.iter().zip(&expected) {
+        let scale = expected.iter().fold(1.0f32, |max, v| max.max(v.abs()));
+        assert_close(actual, expected, 1e-4 * scale);
+    }
+}
+
+#[test]
+fn test_rng_is_seeded_and_deterministic() {
+    use unsloth_rs::core::{manual_seed, with_rng, Rng};
+
+    let draw = |rng: &mut Rng| (0..4).map(|_| rng.next_u64()).collect::<Vec<_>>();
+    assert_eq!(draw(&mut Rng::new(42)), draw(&mut Rng::new(42)));
+    assert_ne!(draw(&mut Rng::new(42)), draw(&mut Rng::new(43)));
+
+    let mut rng = Rng::new(7);
+    let n = 20_000;
+    let uniform: Vec<f32> = (0..n).map(|_| rng.uniform()).collect();
+    assert!(uniform.iter().all(|&u| (0.0..1.0).contains(&u)));
+    assert!((uniform.iter().sum::<f32>() / n as f32 - 0.5).abs() < 0.01);
+    let normal: Vec<f32> = (0..n).map(|_| rng.normal()).collect();
+    let mean = normal.iter().sum::<f32>() / n as f32;
+    let var = normal.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / n as f32;
+    assert!(mean.abs() < 0.03 && (var - 1.0).abs() < 0.05);
+
+    // Reseeding the thread's generator replays the same stream.
+    manual_seed(3);
+    let first = with_rng(|rng| rng.next_u64());
+    manual_seed(3);
+    assert_eq!(with_rng(|rng| rng.next_u64()), first);
+}
+
+#[test]
+fn test_initializers() {
+    use unsloth_rs::core::init::fans;
+    use unsloth_rs::core::{bf16, DType, Init, Rng};
+
+    let mut rng = Rng::new(0);
+    let std = |t: &Tensor| {
+        let n = t.data.len() as f32;
+        let mean = t.data.sum() / n;
+        (t.data.mapv(|x| (x - mean).powi(2)).sum() / n).sqrt()
+    };
+    assert_eq!(fans(&[400, 50]), (400, 50));
+    assert_eq!(fans(&[3, 4, 5]), (12, 5));
+    assert_eq!(fans(&[7]), (7, 7));
+
+    let zeros = Init::Zeros.tensor(&[2, 3], &mut rng);
+    assert_eq!(zeros.data, ndarray::ArrayD::<f32>::zeros(vec![2, 3]));
+    assert!(Init::Ones.tensor(&[4], &mut rng).data.iter().all(|&x| x == 1.0));
+
+    // nn.Linear's default: U(-1 / sqrt(fan_in), 1 / sqrt(fan_in)).
+    let linear = Init::KaimingUniform { a: 5f32.sqrt() }.tensor(&[400, 50], &mut rng);
+    assert!(linear.data.iter().all(|x| x.abs() <= 0.05));
+    assert!((std(&linear) - 0.05 / 3f32.sqrt()).abs() < 1e-3);
+
+    let cases = [
+        (Init::Normal { std: 0.02 }, 0.02),
+        (Init::KaimingNormal { a: 0.0 }, (2.0f32 / 400.0).sqrt()),
+        (Init::XavierNormal, (2.0f32 / 450.0).sqrt()),
+        (Init::XavierUniform, (6.0f32 / 450.0).sqrt() / 3f32.sqrt()),
+    ];
+    for (init, expected) in cases {
+        let t = init.tensor(&[400, 50], &mut rng);
+        assert!((std(&t) / expected - 1.0).abs() < 0.03, "{init:?}: std {}", std(&t));
+    }
+
+    let half = Init::Normal { std: 1.0 }.tensor_with_dtype::<bf16>(&[8, 8], &mut rng);
+    assert_eq!(half.dtype(), DType::BF16);
+}
+
+#[test]
+fn test_dropout() {
+    use unsloth_rs::core::manual_seed;
+
+    let mut x = Tensor::new(ndarray::ArrayD::<f32>::ones(vec![100, 100]));
+    x.set_requires_grad(true);
+    manual_seed(5);
+    let y = x.dropout(0.25);
+    let dropped = y.data.iter().filter(|&&v| v == 0.0).count() as f32 / 10_000.0;
+    assert!((dropped - 0.25).abs() < 0.02);
+    // Kept values are rescaled so the expectation is unchanged.
+    assert!(y.data.iter().all(|&v| v == 0.0 || (v - 4.0 / 3.0).abs() < 1e-6));
+
+    // The gradient goes through the same mask.
+    y.sum().backward();
+    assert_eq!(x.grad().unwrap(), y.data.to_owned());
+
+    manual_seed(5);
+    assert_eq!(x.dropout(0.25).data, y.data);
+    assert_eq!(x.dropout(0.0).data, x.data);
+    let err = x.try_dropout(1.0).unwrap_err();
+    assert_eq!(err.to_string(), "dropout: probability 1 is not in [0, 1)");
+}
+
+#[test]
+fn test_svd() {
+    use ndarray::{Array2, Ix2};
+    use unsloth_rs::core::{manual_seed, svd, with_rng, Init};
+
+    manual_seed(3);
+    for shape in [[7, 4], [4, 7], [5, 5]] {
+        let a = with_rng(|rng| Init::Normal { std: 1.0 }.tensor(&shape, rng));
+        let a = a.data.view().into_dimensionality::<Ix2>().unwrap();
+        let k = shape[0].min(shape[1]);
+        let decomposed = svd(&a);
+        assert_eq!(decomposed.u.dim(), (shape[0], k));
+        assert_eq!(decomposed.vt.dim(), (k, shape[1]));
+        assert!(decomposed.s.windows(2).into_iter().all(|pair| pair[0] >= pair[1]));
+
+        let close = |x: &Array2<f32>, y: &Array2<f32>| (x - y).iter().all(|d| d.abs() < 1e-5);
+        let eye = Array2::eye(k);
+        assert!(close(&decomposed.u.t().dot(&decomposed.u), &eye));
+        assert!(close(&decomposed.vt.dot(&decomposed.vt.t()), &eye));
+        let rebuilt = (&decomposed.u * &decomposed.s).dot(&decomposed.vt);
+        assert!(close(&rebuilt, &a.to_owned()));
+
+        // The rank-2 factors leave exactly the smaller singular values out.
+        let (left, right) = decomposed.low_rank(2);
+        let residual = &a - &left.dot(&right);
+        let expected = decomposed.s.iter().skip(2).map(|s| s * s).sum::<f32>();
+        assert!((residual.iter().map(|r| r * r).sum::<f32>() - expected).abs() < 1e-4);
+    }
+
+    // A rank-deficient matrix, and a rank above k padded with zeros.
+    let a = array![[1.0f32, 2.0], [2.0, 4.0], [3.0, 6.0]];
+    let decomposed = svd(&a.view());
+    assert!(decomposed.s[1].abs() < 1e-6);
+    let (left, right) = decomposed.low_rank(3);
+    assert_eq!((left.dim(), right.dim()), ((3, 3), (3, 2)));
+    assert!((&left.dot(&right) - &a).iter().all(|d| d.abs() < 1e-5));
+}
+
+#[test]
+fn test_svd_lowrank() {
+    use ndarray::{Array1, Array2, Ix2};
+    use unsloth_rs::core::{manual_seed, svd, svd_lowrank, with_rng, Init};
+
+    // A rank-6 matrix plus a little noise: the top triplets match the full
+    // decomposition, with only `rank` of them returned.
+    manual_seed(4);
+    let normal = |shape: &[usize], std: f32| {
+        let t = with_rng(|rng| Init::Normal { std }.tensor(shape, rng));
+        t.data.view().into_dimensionality::<Ix2>().unwrap().to_owned()
+    };
+    let spectrum = Array1::from_vec(vec![8.0f32, 6.0, 4.0, 3.0, 2.0, 1.0]);
+    let a = (&normal(&[60, 6], 1.0) * &spectrum).dot(&normal(&[6, 40], 1.0)) + normal(&[60, 40], 1e-3);
+    let exact = svd(&a.view());
+    let fast = svd_lowrank(&a.view(), 3, 2);
+    assert_eq!((fast.u.dim(), fast.s.len(), fast.vt.dim()), ((60, 3), 3, (3, 40)));
+    for (s, expected) in fast.s.iter().zip(&exact.s) {
+        assert!((s - expected).abs() < 1e-3 * expected);
+    }
+    let close = |x: &Array2<f32>, y: &Array2<f32>, tol: f32| (x - y).iter().all(|d| d.abs() < tol);
+    let (left, right) = fast.low_rank(3);
+    let (exact_left, exact_right) = exact.low_rank(3);
+    assert!(close(&left.dot(&right), &exact_left.dot(&exact_right), 1e-3));
+
+    // Sampling every column makes it exact, rank-deficient or not.
+    let small = array![[1.0f32, 2.0], [2.0, 4.0], [3.0, 6.0]];
+    let (left, right) = svd_lowrank(&small.view(), 1, 0).low_rank(1);
+    assert!(close(&left.dot(&right), &small, 1e-5));
+}
+
+#[test]
+fn test_concat_and_narrow() {
+    use unsloth_rs::core::TensorError;
+
+    let mut a = Tensor::new(array![[0.5, -1.0], [1.5, 0.25]].into_dyn());
+    let mut b = Tensor::new(array![[2.0], [-0.75]].into_dyn());
+    a.set_requires_grad(true);
+    b.set_requires_grad(true);
+    let joined = Tensor::concat(&[&a, &b], 1);
+    assert_eq!(joined.data, array![[0.5, -1.0, 2.0], [1.5, 0.25, -0.75]].into_dyn());
+    assert_eq!(joined.narrow(1, 1, 2).data, array![[-1.0, 2.0], [0.25, -0.75]].into_dyn());
+    assert_eq!(Tensor::concat(&[&a, &a], 0).data.shape(), &[4, 2]);
+
+    let weights = Tensor::new(array![[1.0, -2.0], [0.5, 0.3]].into_dyn());
+    let f = |a: &Tensor, b: &Tensor| Tensor::concat(&[a, b], 1).narrow(1, 1, 2).matmul(&weights).silu().sum();
+    f(&a, &b).backward();
+    assert_close(&a.grad().unwrap(), &numeric_grad(&a, |a| f(a, &b).data[[]]), 1e-3);
+    assert_close(&b.grad().unwrap(), &numeric_grad(&b, |b| f(&a, b).data[[]]), 1e-3);
+
+    assert_eq!(
+        Tensor::try_concat(&[&a, &b], 0).unwrap_err(),
+        TensorError::ShapeMismatch { op: "concat", expected: vec![2, 2], actual: vec![2, 1] }
+    );
+    assert_eq!(Tensor::try_concat(&[&a], 2).unwrap_err(), TensorError::InvalidAxis { op: "concat", axis: 2, ndim: 2 });
+    assert!(Tensor::try_concat(&[], 0).is_err());
+    assert_eq!(
+        a.try_narrow(1, 1, 2).unwrap_err().to_string(),
+        "narrow: 1..3 is out of bounds for axis 1 of size 2"
+    );
+}
+
+#[test]
+fn test_softmax_mask_causal_and_combine() {
+    use unsloth_rs::core::{SoftmaxMask, TensorError};
+
+    // Two new queries after two earlier positions.
+    let causal = SoftmaxMask::causal(2, 4);
+    assert_eq!(causal, array![[true, true, true, false], [true, true, true, true]].into());
+    let padding: SoftmaxMask = array![false, true, true, true].into();
+    assert_eq!(
+        causal.combine(&padding).unwrap(),
+        array![[false, true, true, false], [false, true, true, true]].into()
+    );
+    let bias: SoftmaxMask = array![0.5, 0.0, -1.0, 0.0].into();
+    let inf = f32::NEG_INFINITY;
+    assert_eq!(
+        causal.combine(&bias).unwrap(),
+        array![[0.5, 0.0, -1.0, inf], [0.5, 0.0, -1.0, 0.0]].into()
+    );
+    assert_eq!(
+        causal.combine(&array![true, false].into()).unwrap_err(),
+        TensorError::IncompatibleShapes { op: "combine", lhs: vec![2, 4], rhs: vec![2] }
+    );
+}
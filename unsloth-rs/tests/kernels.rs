@@ -1,5 +1,6 @@
-use ndarray::{Array, IxDyn};
-use unsloth_rs::core::Tensor;
+use ndarray::{Array, Array2, ArrayD, IxDyn};
+use unsloth_rs::core::{QuantizedTensor, Tensor};
+use unsloth_rs::kernels::cross_entropy::{FusedCrossEntropy, IGNORE_INDEX};
 use unsloth_rs::kernels::fast_lora::{LoraMlp, LoraQkv};
 
 #[test]
@@ -31,3 +32,877 @@ fn test_create_lora_qkv() {
         Tensor::new(Array::zeros(IxDyn(&[0]))),
     );
 }
+
+#[test]
+fn test_lora_mlp_with_quantized_base() {
+    use unsloth_rs::core::QuantType;
+
+    let ramp = |shape: &[usize], scale: f32| {
+        let n: usize = shape.iter().product();
+        let values = (0..n).map(|i| ((i * 37 % n) as f32 / n as f32 - 0.5) * scale).collect();
+        Tensor::new(Array::from_shape_vec(IxDyn(shape), values).unwrap())
+    };
+    let (hidden, inter, rank) = (8, 12, 2);
+    let make = |gate_w: &Tensor, up_w: &Tensor, down_w: &Tensor, lora_a_gate: &Tensor| {
+        LoraMlp::new(
+            gate_w.clone(),
+            up_w.clone(),
+            down_w.clone(),
+            lora_a_gate.clone(),
+            ramp(&[rank, inter], 0.1),
+            ramp(&[hidden, rank], 0.1),
+            ramp(&[rank, inter], 0.1),
+            ramp(&[inter, rank], 0.1),
+            ramp(&[rank, hidden], 0.1),
+        )
+    };
+    let gate_w = ramp(&[hidden, inter], 1.0);
+    let up_w = ramp(&[hidden, inter], 0.8);
+    let down_w = ramp(&[inter, hidden], 0.6);
+    let mut lora_a_gate = ramp(&[hidden, rank], 0.1);
+    lora_a_gate.set_requires_grad(true);
+    let x = ramp(&[3, hidden], 2.0);
+
+    let mut qlora = make(&gate_w, &up_w, &down_w, &lora_a_gate);
+    qlora.quantize_base(QuantType::Nf4);
+    let out = qlora.forward(&x);
+
+    // Same as running with the dequantized base weights.
+    let dequantize = |w: &Tensor| QuantizedTensor::quantize(w, QuantType::Nf4).dequantize();
+    let reference = make(&dequantize(&gate_w), &dequantize(&up_w), &dequantize(&down_w), &lora_a_gate.detach());
+    let diff = (&out.data - &reference.forward(&x).data).mapv(f32::abs);
+    assert!(diff.iter().all(|&d| d < 1e-4));
+
+    // The frozen base does not stop gradients reaching the adapters.
+    out.sum().backward();
+    let grad = lora_a_gate.grad().unwrap();
+    assert!(grad.iter().any(|&g| g != 0.0));
+}
+
+// Mean cross-entropy over the full logits, in f64.
+fn naive_cross_entropy(h: &Array2<f64>, w: &Array2<f64>, labels: &[usize], smoothing: f64) -> f64 {
+    let logits = h.dot(w);
+    let vocab = w.ncols() as f64;
+    let (mut total, mut count) = (0.0, 0);
+    for (row, &label) in logits.rows().into_iter().zip(labels) {
+        if label == IGNORE_INDEX {
+            continue;
+        }
+        let max = row.fold(f64::NEG_INFINITY, |m, &z| m.max(z));
+        let lse = max + row.mapv(|z| (z - max).exp()).sum().ln();
+        total += (1.0 - smoothing) * (lse - row[label]) + smoothing * (lse - row.sum() / vocab);
+        count += 1;
+    }
+    total / count as f64
+}
+
+fn numeric_grad_f64(x: &Array2<f64>, f: impl Fn(&Array2<f64>) -> f64) -> Array2<f64> {
+    let eps = 1e-5;
+    Array2::from_shape_fn(x.raw_dim(), |idx| {
+        let (mut plus, mut minus) = (x.clone(), x.clone());
+        plus[idx] += eps;
+        minus[idx] -= eps;
+        (f(&plus) - f(&minus)) / (2.0 * eps)
+    })
+}
+
+fn assert_all_close(actual: &ArrayD<f32>, expected: &ArrayD<f64>, tol: f64) {
+    assert_eq!(actual.shape(), expected.shape());
+    for (a, e) in actual.iter().zip(expected) {
+        assert!((*a as f64 - e).abs() < tol, "{a} != {e}");
+    }
+}
+
+#[test]
+fn test_fused_cross_entropy_matches_full_logits() {
+    let (rows, dim, vocab) = (5, 4, 7);
+    let h = Array2::from_shape_fn((rows, dim), |(i, j)| ((i * 5 + j * 3) % 7) as f64 * 0.3 - 0.8);
+    let w = Array2::from_shape_fn((dim, vocab), |(i, j)| ((i * 11 + j * 7) % 13) as f64 * 0.2 - 1.1);
+    let labels = [3, IGNORE_INDEX, 0, 6, 2];
+    let (h32, w32) = (Tensor::new(h.mapv(|x| x as f32).into_dyn()), Tensor::new(w.mapv(|x| x as f32).into_dyn()));
+
+    for smoothing in [0.0, 0.1] {
+        let expected = naive_cross_entropy(&h, &w, &labels, smoothing);
+        let grad_h = numeric_grad_f64(&h, |h| naive_cross_entropy(h, &w, &labels, smoothing));
+        let grad_w = numeric_grad_f64(&w, |w| naive_cross_entropy(&h, w, &labels, smoothing));
+
+        // Chunks of one column, chunks that do not divide the vocab, and one chunk.
+        for chunk_size in [1, 3, 7, 100] {
+            let loss_fn = FusedCrossEntropy::new().with_chunk_size(chunk_size).with_label_smoothing(smoothing as f32);
+            let out = loss_fn.compute(&h32, &w32, &labels, true);
+            assert!((out.loss as f64 - expected).abs() < 1e-5, "chunk {chunk_size}: {} != {expected}", out.loss);
+            assert_eq!(out.n_tokens, 4);
+            let grad = out.grad.unwrap();
+            assert_all_close(&grad.hidden, &grad_h.clone().into_dyn(), 1e-5);
+            assert_all_close(&grad.lm_head, &grad_w.clone().into_dyn(), 1e-5);
+            assert!(grad.hidden.index_axis(ndarray::Axis(0), 1).iter().all(|&g| g == 0.0));
+        }
+    }
+}
+
+#[test]
+fn test_fused_cross_entropy_on_the_tape() {
+    let h = Array2::from_shape_fn((3, 4), |(i, j)| (i as f32 - j as f32) * 0.25);
+    let w = Array2::from_shape_fn((4, 10), |(i, j)| ((i * 3 + j) % 5) as f32 * 0.3 - 0.6);
+    let (mut hidden, mut lm_head) = (Tensor::new(h.into_dyn()), Tensor::new(w.into_dyn()));
+    hidden.set_requires_grad(true);
+    lm_head.set_requires_grad(true);
+    let labels = [9, 0, 4];
+    let loss_fn = FusedCrossEntropy::new().with_chunk_size(4).with_label_smoothing(0.05);
+
+    let loss = loss_fn.loss(&hidden, &lm_head, &labels);
+    let out = loss_fn.compute(&hidden, &lm_head, &labels, true);
+    assert_eq!(loss.data[[]], out.loss);
+    loss.scale(2.0).backward();
+    let grad = out.grad.unwrap();
+    assert_eq!(hidden.grad().unwrap(), grad.hidden.mapv(|g| 2.0 * g));
+    assert_eq!(lm_head.grad().unwrap(), grad.lm_head.mapv(|g| 2.0 * g));
+
+    // Every label ignored: a zero loss and zero gradients rather than NaN.
+    let out = loss_fn.compute(&hidden, &lm_head, &[IGNORE_INDEX; 3], true);
+    assert_eq!((out.loss, out.n_tokens), (0.0, 0));
+    assert!(out.grad.unwrap().hidden.iter().all(|&g| g == 0.0));
+
+    let err = loss_fn.try_compute(&hidden, &lm_head, &[10, 0, 0], false).unwrap_err();
+    assert_eq!(err.to_string(), "cross_entropy: label 10 is out of range for a vocab of 10");
+}
+
+#[test]
+fn test_lora_with_rank_starts_as_the_base_model() {
+    use unsloth_rs::core::{manual_seed, with_rng, Init};
+    use unsloth_rs::kernels::fast_lora::init_lora;
+
+    manual_seed(0);
+    let (a, b) = init_lora(64, 8, 4);
+    assert_eq!((a.data.shape(), b.data.shape()), (&[64, 4][..], &[4, 8][..]));
+    assert!(a.data.iter().all(|x| x.abs() <= 0.125) && a.data.iter().any(|&x| x != 0.0));
+    assert!(b.data.iter().all(|&x| x == 0.0));
+
+    let (hidden, inter) = (8, 12);
+    let (gate_w, up_w, down_w, x) = with_rng(|rng| {
+        let init = Init::Normal { std: 0.5 };
+        (
+            init.tensor(&[hidden, inter], rng),
+            init.tensor(&[hidden, inter], rng),
+            init.tensor(&[inter, hidden], rng),
+            init.tensor(&[3, hidden], rng),
+        )
+    });
+    let mlp = LoraMlp::with_rank(gate_w.clone(), up_w.clone(), down_w.clone(), 2);
+    let base = x.matmul(&gate_w).silu().mul(&x.matmul(&up_w)).matmul(&down_w);
+    assert_eq!(mlp.forward(&x).data, base.data);
+
+    let qkv = LoraQkv::with_rank(gate_w.clone(), up_w.clone(), up_w.clone(), 2);
+    let (q, k, _) = qkv.forward(&x);
+    assert_eq!(q.data, x.matmul(&gate_w).data);
+    assert_eq!(k.data, x.matmul(&up_w).data);
+}
+
+#[test]
+fn test_lora_dropout_only_applies_while_training() {
+    use unsloth_rs::core::{manual_seed, with_rng, Init};
+
+    manual_seed(1);
+    let (hidden, inter) = (8, 12);
+    let init = Init::Normal { std: 0.5 };
+    let (gate_w, up_w, down_w, x) = with_rng(|rng| {
+        (
+            init.tensor(&[hidden, inter], rng),
+            init.tensor(&[hidden, inter], rng),
+            init.tensor(&[inter, hidden], rng),
+            init.tensor(&[3, hidden], rng),
+        )
+    });
+    let lora = |rng: &mut _, (i, o)| (init.tensor(&[i, 2], rng), init.tensor(&[2, o], rng));
+    let ((a_gate, b_gate), (a_up, b_up), (a_down, b_down)) = with_rng(|rng| {
+        (lora(rng, (hidden, inter)), lora(rng, (hidden, inter)), lora(rng, (inter, hidden)))
+    });
+    let plain = LoraMlp::new(
+        gate_w.clone(),
+        up_w.clone(),
+        down_w.clone(),
+        a_gate.clone(),
+        b_gate.clone(),
+        a_up.clone(),
+        b_up.clone(),
+        a_down.clone(),
+        b_down.clone(),
+    );
+    let mut mlp = LoraMlp::new(gate_w, up_w, down_w, a_gate, b_gate, a_up, b_up, a_down, b_down).with_lora_dropout(0.5);
+    assert_eq!(mlp.lora_dropout(), 0.5);
+    let expected = plain.forward(&x);
+    assert_ne!(mlp.forward(&x).data, expected.data);
+    mlp.eval();
+    assert_eq!(mlp.forward(&x).data, expected.data);
+}
+
+// Central differences of an f32 function, evaluated in f64.
+fn numeric_grad_f32(x: &ArrayD<f32>, f: impl Fn(&ArrayD<f32>) -> f64) -> ArrayD<f64> {
+    let eps = 1e-2;
+    ArrayD::from_shape_fn(x.raw_dim(), |idx| {
+        let (mut plus, mut minus) = (x.clone(), x.clone());
+        plus[&idx] += eps;
+        minus[&idx] -= eps;
+        (f(&plus) - f(&minus)) / (2.0 * eps as f64)
+    })
+}
+
+#[test]
+fn test_lora_mlp_backward_matches_finite_differences() {
+    use unsloth_rs::core::{manual_seed, with_rng, Init, QuantType};
+
+    manual_seed(2);
+    let (hidden, inter, rank) = (4, 6, 2);
+    let shapes: [&[usize]; 10] = [
+        &[hidden, inter],
+        &[hidden, inter],
+        &[inter, hidden],
+        &[2, 3, hidden],
+        &[hidden, rank],
+        &[rank, inter],
+        &[hidden, rank],
+        &[rank, inter],
+        &[inter, rank],
+        &[rank, hidden],
+    ];
+    let init = Init::Normal { std: 0.5 };
+    let values: Vec<ArrayD<f32>> = with_rng(|rng| shapes.iter().map(|s| init.tensor(s, rng).data.to_owned()).collect());
+    let (base, params) = values.split_at(3);
+    let grad_out = with_rng(|rng| init.tensor(&[2, 3, hidden], rng).data.to_owned());
+    let make = |params: &[Tensor]| {
+        let w = |i: usize| Tensor::new(base[i].clone());
+        let p = |i: usize| params[i].clone();
+        LoraMlp::new(w(0), w(1), w(2), p(1), p(2), p(3), p(4), p(5), p(6))
+    };
+    // sum(forward(x) * grad_out), with `params[i]` replaced by `value`.
+    let loss = |i: usize, value: &ArrayD<f32>| {
+        let mut tensors: Vec<Tensor> = params.iter().map(|p| Tensor::new(p.clone())).collect();
+        tensors[i] = Tensor::new(value.clone());
+        let out = make(&tensors).forward(&tensors[0]);
+        out.data.iter().zip(&grad_out).map(|(&o, &g)| o as f64 * g as f64).sum::<f64>()
+    };
+
+    let tensors: Vec<Tensor> = params.iter().map(|p| Tensor::new(p.clone())).collect();
+    let mlp = make(&tensors);
+    mlp.forward_for_backward(&tensors[0]);
+    let grad = mlp.backward(&grad_out);
+    let actual = [
+        &grad.input,
+        &grad.lora_a_gate,
+        &grad.lora_b_gate,
+        &grad.lora_a_up,
+        &grad.lora_b_up,
+        &grad.lora_a_down,
+        &grad.lora_b_down,
+    ];
+    for (i, actual) in actual.into_iter().enumerate() {
+        assert_all_close(actual, &numeric_grad_f32(&params[i], |v| loss(i, v)), 2e-3);
+    }
+
+    // With adapter dropout, rsLoRA scaling and a quantized base, the masks are
+    // replayed and the result matches the autograd tape.
+    let mut tensors: Vec<Tensor> = params.iter().map(|p| Tensor::new(p.clone())).collect();
+    for tensor in &mut tensors {
+        tensor.set_requires_grad(true);
+    }
+    let mut mlp = make(&tensors).with_lora_dropout(0.3).with_lora_alpha(4.0, true);
+    mlp.quantize_base(QuantType::Nf4);
+    let out = mlp.forward_for_backward(&tensors[0]);
+    out.mul(&Tensor::new(grad_out.clone())).sum().backward();
+    let grad = mlp.backward(&grad_out);
+    let actual = [
+        &grad.input,
+        &grad.lora_a_gate,
+        &grad.lora_b_gate,
+        &grad.lora_a_up,
+        &grad.lora_b_up,
+        &grad.lora_a_down,
+        &grad.lora_b_down,
+    ];
+    for (actual, tensor) in actual.into_iter().zip(&tensors) {
+        let expected = tensor.grad().unwrap().mapv(f64::from);
+        assert_all_close(actual, &expected, 1e-4);
+    }
+
+    let w = |i: usize| Tensor::new(base[i].clone());
+    let err = LoraMlp::with_rank(w(0), w(1), w(2), 2).try_backward(&grad_out).unwrap_err();
+    assert_eq!(err.to_string(), "lora_mlp_backward: backward called before forward_for_backward");
+}
+
+#[test]
+fn test_lora_qkv_backward_matches_finite_differences() {
+    use unsloth_rs::core::{manual_seed, with_rng, Init, QuantType};
+
+    // Grouped-query shapes: K and V are narrower than Q.
+    manual_seed(3);
+    let (hidden, kv, rank) = (6, 4, 2);
+    let shapes: [&[usize]; 10] = [
+        &[hidden, hidden],
+        &[hidden, kv],
+        &[hidden, kv],
+        &[5, hidden],
+        &[hidden, rank],
+        &[rank, hidden],
+        &[hidden, rank],
+        &[rank, kv],
+        &[hidden, rank],
+        &[rank, kv],
+    ];
+    let init = Init::Normal { std: 0.5 };
+    let values: Vec<ArrayD<f32>> = with_rng(|rng| shapes.iter().map(|s| init.tensor(s, rng).data.to_owned()).collect());
+    let (base, params) = values.split_at(3);
+    let (dq, dk, dv) = with_rng(|rng| {
+        let draw = |rng: &mut _, n| init.tensor(&[5, n], rng).data.to_owned();
+        (draw(rng, hidden), draw(rng, kv), draw(rng, kv))
+    });
+    let make = |params: &[Tensor]| {
+        let w = |i: usize| Tensor::new(base[i].clone());
+        let p = |i: usize| params[i].clone();
+        LoraQkv::new(w(0), w(1), w(2), p(1), p(2), p(3), p(4), p(5), p(6))
+    };
+    let dot = |out: &Tensor, grad: &ArrayD<f32>| {
+        out.data.iter().zip(grad).map(|(&o, &g)| o as f64 * g as f64).sum::<f64>()
+    };
+    let loss = |i: usize, value: &ArrayD<f32>| {
+        let mut tensors: Vec<Tensor> = params.iter().map(|p| Tensor::new(p.clone())).collect();
+        tensors[i] = Tensor::new(value.clone());
+        let (q, k, v) = make(&tensors).forward(&tensors[0]);
+        dot(&q, &dq) + dot(&k, &dk) + dot(&v, &dv)
+    };
+
+    let tensors: Vec<Tensor> = params.iter().map(|p| Tensor::new(p.clone())).collect();
+    let qkv = make(&tensors);
+    qkv.forward_for_backward(&tensors[0]);
+    let grad = qkv.backward(&dq, &dk, &dv);
+    let actual = [
+        &grad.input,
+        &grad.lora_a_q,
+        &grad.lora_b_q,
+        &grad.lora_a_k,
+        &grad.lora_b_k,
+        &grad.lora_a_v,
+        &grad.lora_b_v,
+    ];
+    for (i, actual) in actual.into_iter().enumerate() {
+        assert_all_close(actual, &numeric_grad_f32(&params[i], |v| loss(i, v)), 2e-3);
+    }
+
+    // Dropout masks are replayed and the scaling applied, with a quantized
+    // base as well.
+    let mut tensors: Vec<Tensor> = params.iter().map(|p| Tensor::new(p.clone())).collect();
+    for tensor in &mut tensors {
+        tensor.set_requires_grad(true);
+    }
+    let mut qkv = make(&tensors).with_lora_dropout(0.3).with_scaling(0.5);
+    qkv.quantize_base(QuantType::Int8);
+    let (q, k, v) = qkv.forward_for_backward(&tensors[0]);
+    let weighted = |out: &Tensor, grad: &ArrayD<f32>| out.mul(&Tensor::new(grad.clone())).sum();
+    weighted(&q, &dq).add(&weighted(&k, &dk)).add(&weighted(&v, &dv)).backward();
+    let grad = qkv.backward(&dq, &dk, &dv);
+    let actual = [
+        &grad.input,
+        &grad.lora_a_q,
+        &grad.lora_b_q,
+        &grad.lora_a_k,
+        &grad.lora_b_k,
+        &grad.lora_a_v,
+        &grad.lora_b_v,
+    ];
+    for (actual, tensor) in actual.into_iter().zip(&tensors) {
+        assert_all_close(actual, &tensor.grad().unwrap().mapv(f64::from), 1e-4);
+    }
+
+    let err = qkv.try_backward(&dq, &dq, &dv).unwrap_err();
+    assert_eq!(err.to_string(), "lora_qkv_backward: expected shape [5, 4], got [5, 6]");
+}
+
+#[test]
+fn test_lora_scaling_and_adapter_round_trip() {
+    use unsloth_rs::core::{manual_seed, with_rng, Init};
+    use unsloth_rs::kernels::fast_lora::lora_scaling;
+    use unsloth_rs::save::Model;
+
+    assert_eq!(lora_scaling(16.0, 8, false), 2.0);
+    assert_eq!(lora_scaling(16.0, 16, true), 4.0);
+
+    manual_seed(4);
+    let (hidden, inter) = (8, 12);
+    let init = Init::Normal { std: 0.5 };
+    let (gate_w, up_w, down_w, x) = with_rng(|rng| {
+        (
+            init.tensor(&[hidden, inter], rng),
+            init.tensor(&[hidden, inter], rng),
+            init.tensor(&[inter, hidden], rng),
+            init.tensor(&[3, hidden], rng),
+        )
+    });
+    let (lora_a, lora_b) = with_rng(|rng| (init.tensor(&[hidden, 4], rng), init.tensor(&[4, inter], rng)));
+
+    // Only the gate adapter is non-zero, so the update is easy to isolate.
+    let mlp = |scaling: f32| {
+        let mut mlp = LoraMlp::with_rank(gate_w.clone(), up_w.clone(), down_w.clone(), 4).with_scaling(scaling);
+        let mut state = mlp.adapter_state_dict();
+        state.insert("lora_a_gate", &lora_a);
+        state.insert("lora_b_gate", &lora_b);
+        mlp.load_adapter_state_dict(&state).unwrap();
+        mlp
+    };
+    let up = x.matmul(&up_w);
+    let expected = |scaling: f32| {
+        let gate = x.matmul(&gate_w).add(&x.matmul(&lora_a).matmul(&lora_b).scale(scaling));
+        gate.silu().mul(&up).matmul(&down_w)
+    };
+    let scaled = mlp(1.0).with_lora_alpha(16.0, false);
+    assert_eq!(scaled.scaling(), [4.0; 3]);
+    let diff = (&scaled.forward(&x).data - &expected(4.0).data).mapv(f32::abs);
+    assert!(diff.iter().all(|&d| d < 1e-4));
+    let rslora = mlp(1.0).with_lora_alpha(16.0, true);
+    assert_eq!(rslora.scaling(), [8.0; 3]);
+
+    // The scaling is saved with the adapters, so a module loaded from disk
+    // reproduces the outputs of the trained one.
+    let path = std::env::temp_dir().join(format!("unsloth_rs_lora_{}.safetensors", std::process::id()));
+    let path = path.to_str().unwrap();
+    rslora.adapter_state_dict().save(path).unwrap();
+    let loaded = Model::load(path).unwrap();
+    std::fs::remove_file(path).unwrap();
+    assert_eq!(loaded.names(), ["lora_a_down", "lora_a_gate", "lora_a_up", "lora_b_down", "lora_b_gate", "lora_b_up"]);
+    assert_eq!(loaded.metadata("scaling_gate"), Some("8"));
+    let mut restored = LoraMlp::with_rank(gate_w.clone(), up_w.clone(), down_w.clone(), 4);
+    restored.load_adapter_state_dict(&loaded).unwrap();
+    assert_eq!(restored.scaling(), [8.0; 3]);
+    assert_eq!(restored.forward(&x).data, rslora.forward(&x).data);
+
+    let mut qkv = LoraQkv::with_rank(gate_w.clone(), up_w.clone(), up_w.clone(), 4).with_lora_alpha(8.0, false);
+    let state = qkv.adapter_state_dict();
+    assert_eq!(state.metadata("scaling_v"), Some("2"));
+    let err = qkv.load_adapter_state_dict(&Model::new()).unwrap_err();
+    assert_eq!(err.to_string(), "missing tensor `lora_a_q`");
+}
+
+#[test]
+fn test_lora_linear_backward_matches_the_tape() {
+    use unsloth_rs::core::{bf16, manual_seed, with_rng, Init};
+    use unsloth_rs::kernels::fast_lora::{LoraAdapter, LoraLinear};
+
+    manual_seed(5);
+    let init = Init::Normal { std: 0.5 };
+    let (weight, x, grad_out) =
+        with_rng(|rng| (init.tensor(&[6, 4], rng), init.tensor(&[2, 3, 6], rng), init.tensor(&[2, 3, 4], rng)));
+
+    // Without an adapter it is a plain projection.
+    let plain = LoraLinear::new(weight.clone());
+    assert_eq!(plain.forward(&x).data, x.matmul(&weight).data);
+    let err = plain.try_backward(&grad_out.data.to_owned()).unwrap_err();
+    assert_eq!(err.to_string(), "lora_linear_backward: there is no adapter to differentiate");
+
+    let mut adapter = LoraAdapter::new(6, 4, 2).with_lora_alpha(4.0, false).with_dropout(0.25);
+    assert_eq!((adapter.rank(), adapter.scaling), (2, 2.0));
+    adapter.lora_b = with_rng(|rng| init.tensor(&[2, 4], rng));
+    adapter.lora_a.set_requires_grad(true);
+    adapter.lora_b.set_requires_grad(true);
+    let mut x_tracked = x.clone();
+    x_tracked.set_requires_grad(true);
+    let linear = LoraLinear::new(weight.clone()).with_adapter(adapter.clone());
+    linear.forward_for_backward(&x_tracked).mul(&grad_out).sum().backward();
+    let grad = linear.backward(&grad_out.data.to_owned());
+    let tape = [x_tracked.grad(), adapter.lora_a.grad(), adapter.lora_b.grad()].map(|g| g.unwrap().mapv(f64::from));
+    for (actual, expected) in [&grad.input, &grad.lora_a, &grad.lora_b].into_iter().zip(&tape) {
+        assert_all_close(actual, expected, 1e-4);
+    }
+
+    // The base weight may be stored in a half type; eval turns dropout off.
+    let mut half = LoraLinear::<bf16>::new(weight.to_dtype::<bf16>()).with_rank(2);
+    assert_eq!((half.in_features(), half.out_features()), (6, 4));
+    half.eval();
+    assert_eq!(half.forward(&x).data, x.matmul(&weight.to_dtype::<bf16>()).data);
+}
+
+#[test]
+fn test_lora_merge_keeps_the_forward() {
+    use unsloth_rs::core::{bf16, manual_seed, with_rng, Init, QuantType, Weight};
+    use unsloth_rs::kernels::fast_lora::{LoraAdapter, LoraLinear};
+
+    manual_seed(9);
+    let init = Init::Normal { std: 0.5 };
+    let (weight, lora_b, x) =
+        with_rng(|rng| (init.tensor(&[6, 4], rng), init.tensor(&[2, 4], rng), init.tensor(&[3, 6], rng)));
+    let mut adapter = LoraAdapter::new(6, 4, 2).with_lora_alpha(4.0, false);
+    adapter.lora_b = lora_b;
+    let close = |actual: &Tensor, expected: &Tensor, tol: f64| {
+        assert_all_close(&actual.data.to_owned(), &expected.data.mapv(f64::from), tol)
+    };
+
+    let mut linear = LoraLinear::new(weight.clone()).with_adapter(adapter.clone());
+    let before = linear.forward(&x);
+    linear.merge(false);
+    assert!(linear.is_merged());
+    close(&linear.forward(&x), &before, 1e-5);
+    let err = linear.try_backward(&before.data.to_owned()).unwrap_err();
+    assert_eq!(err.to_string(), "lora_linear_backward: the adapters are merged into the base weights");
+    linear.unmerge();
+    assert!(!linear.is_merged());
+    close(&linear.weight.to_dense(), &weight, 1e-5);
+    close(&linear.forward(&x), &before, 1e-5);
+
+    // A half base is merged in its own dtype.
+    let mut half = LoraLinear::<bf16>::new(weight.to_dtype::<bf16>()).with_adapter(adapter.clone());
+    let before = half.forward(&x);
+    half.merge(false);
+    close(&half.forward(&x), &before, 5e-2);
+
+    // A quantized base is dequantized, then optionally quantized again, and
+    // restored exactly by `unmerge`.
+    let quantized = QuantizedTensor::quantize(&weight, QuantType::Nf4);
+    let mut qlora = LoraLinear::<f32>::new(quantized.clone()).with_adapter(adapter.clone());
+    let before = qlora.forward(&x);
+    qlora.merge(false);
+    assert!(!qlora.weight.is_quantized());
+    close(&qlora.forward(&x), &before, 1e-4);
+    qlora.unmerge();
+    let Weight::Quantized(restored) = &qlora.weight else { panic!("unmerge did not restore the quantized weight") };
+    assert_eq!(restored.to_bytes(), quantized.to_bytes());
+    qlora.merge(true);
+    assert!(qlora.weight.is_quantized());
+    close(&qlora.forward(&x), &before, 0.5);
+
+    // The fused MLP and QKV projections merge their three adapters.
+    let (hidden, inter, rank) = (6, 8, 2);
+    let mut tensors = with_rng(|rng| {
+        [
+            [hidden, inter],
+            [hidden, inter],
+            [inter, hidden],
+            [hidden, rank],
+            [rank, inter],
+            [hidden, rank],
+            [rank, inter],
+            [inter, rank],
+            [rank, hidden],
+        ]
+        .map(|shape| init.tensor(&shape, rng))
+    })
+    .into_iter();
+    let mut next = || tensors.next().unwrap();
+    let mut mlp = LoraMlp::new(next(), next(), next(), next(), next(), next(), next(), next(), next())
+        .with_lora_alpha(4.0, true);
+    let before = mlp.forward(&x);
+    mlp.merge(false);
+    close(&mlp.forward(&x), &before, 1e-4);
+    assert!(mlp.try_backward(&before.data.to_owned()).is_err());
+    mlp.unmerge();
+    close(&mlp.forward_for_backward(&x), &before, 1e-4);
+    assert!(mlp.try_backward(&before.data.to_owned()).is_ok());
+
+    let mut qkv = LoraQkv::with_rank(weight.clone(), weight.clone(), weight.clone(), 2).with_scaling(0.5);
+    let (q, _, v) = qkv.forward(&x);
+    qkv.merge(true);
+    assert!(qkv.is_merged());
+    let (merged_q, _, merged_v) = qkv.forward(&x);
+    close(&merged_q, &q, 1e-5);
+    close(&merged_v, &v, 1e-5);
+}
+
+#[test]
+fn test_dora_backward_matches_finite_differences() {
+    use unsloth_rs::core::{manual_seed, with_rng, Init, Weight};
+    use unsloth_rs::kernels::fast_lora::{LoraAdapter, LoraLinear};
+    use unsloth_rs::save::Model;
+
+    manual_seed(6);
+    let init = Init::Normal { std: 0.5 };
+    let (weight, x, grad_out) =
+        with_rng(|rng| (init.tensor(&[6, 4], rng), init.tensor(&[2, 3, 6], rng), init.tensor(&[2, 3, 4], rng)));
+
+    // Each column's magnitude starts at its norm, so DoRA starts as the base.
+    let fresh = LoraAdapter::new(6, 4, 2).with_lora_alpha(4.0, false).with_dora(&Weight::from(weight.clone()));
+    assert!(fresh.is_dora());
+    let base = LoraLinear::new(weight.clone()).with_adapter(fresh.clone()).forward(&x);
+    assert_all_close(&base.data.to_owned(), &x.matmul(&weight).data.mapv(f64::from), 1e-5);
+
+    let lora_b = with_rng(|rng| init.tensor(&[2, 4], rng)).data.to_owned();
+    let magnitude = Array::from_vec(vec![0.5f32, 1.0, 1.5, 2.0]).into_dyn();
+    let params = [fresh.lora_a.data.to_owned(), lora_b, magnitude];
+    let make = |params: &[ArrayD<f32>]| {
+        let mut adapter = fresh.clone();
+        adapter.lora_a = Tensor::new(params[0].clone());
+        adapter.lora_b = Tensor::new(params[1].clone());
+        adapter.magnitude = Some(Tensor::new(params[2].clone()));
+        LoraLinear::new(weight.clone()).with_adapter(adapter)
+    };
+    let loss = |i: usize, value: &ArrayD<f32>| {
+        let mut params = params.clone();
+        params[i] = value.clone();
+        let out = make(&params).forward(&x);
+        out.data.iter().zip(&grad_out.data).map(|(&o, &g)| o as f64 * g as f64).sum::<f64>()
+    };
+    let linear = make(&params);
+    linear.forward_for_backward(&x);
+    let grad = linear.backward(&grad_out.data.to_owned());
+    let actual = [&grad.lora_a, &grad.lora_b, grad.magnitude.as_ref().unwrap()];
+    for (i, actual) in actual.into_iter().enumerate() {
+        assert_all_close(actual, &numeric_grad_f32(&params[i], |v| loss(i, v)), 2e-3);
+    }
+
+    // The tape agrees, dropout included.
+    let mut adapter = make(&params).adapter.unwrap().with_dropout(0.25);
+    adapter.lora_a.set_requires_grad(true);
+    adapter.lora_b.set_requires_grad(true);
+    adapter.magnitude.as_mut().unwrap().set_requires_grad(true);
+    let mut x_tracked = x.clone();
+    x_tracked.set_requires_grad(true);
+    let linear = LoraLinear::new(weight.clone()).with_adapter(adapter.clone());
+    linear.forward_for_backward(&x_tracked).mul(&grad_out).sum().backward();
+    let grad = linear.backward(&grad_out.data.to_owned());
+    let tape = [&x_tracked, &adapter.lora_a, &adapter.lora_b, adapter.magnitude.as_ref().unwrap()]
+        .map(|tensor| tensor.grad().unwrap().mapv(f64::from));
+    let actual = [&grad.input, &grad.lora_a, &grad.lora_b, grad.magnitude.as_ref().unwrap()];
+    for (actual, expected) in actual.into_iter().zip(&tape) {
+        assert_all_close(actual, expected, 1e-4);
+    }
+
+    // Merging folds the magnitude into the weight too.
+    let mut linear = make(&params);
+    let before = linear.forward(&x);
+    linear.merge(false);
+    assert_all_close(&linear.forward(&x).data.to_owned(), &before.data.mapv(f64::from), 1e-4);
+    linear.unmerge();
+    assert_all_close(&linear.weight.to_dense().data.to_owned(), &weight.data.mapv(f64::from), 1e-5);
+
+    // The magnitude is saved with the adapter.
+    let mut model = Model::new();
+    linear.adapter.as_ref().unwrap().save_into(&mut model, "proj");
+    assert_eq!(model.names(), ["proj.lora_a", "proj.lora_b", "proj.magnitude"]);
+    let mut restored = LoraAdapter::new(6, 4, 2);
+    restored.load_from(&model, "proj").unwrap();
+    assert_eq!(restored.scaling, 2.0);
+    let restored = LoraLinear::new(weight.clone()).with_adapter(restored);
+    assert_eq!(restored.forward(&x).data, before.data);
+}
+
+#[test]
+fn test_quantized_dora_backward_matches_the_tape() {
+    use unsloth_rs::core::{manual_seed, with_rng, Init, QuantType, QuantizedTensor, Weight};
+    use unsloth_rs::kernels::fast_lora::{LoraAdapter, LoraLinear};
+
+    // Enough input features for several dequantized row blocks, none of which
+    // the backward needs all at once.
+    manual_seed(7);
+    let init = Init::Normal { std: 0.5 };
+    let (weight, x, grad_out) =
+        with_rng(|rng| (init.tensor(&[150, 4], rng), init.tensor(&[2, 3, 150], rng), init.tensor(&[2, 3, 4], rng)));
+    let weight: Weight = QuantizedTensor::quantize(&weight, QuantType::Nf4).into();
+    let mut adapter = LoraAdapter::new(150, 4, 2).with_lora_alpha(4.0, false).with_dora(&weight).with_dropout(0.25);
+    adapter.lora_b = with_rng(|rng| init.tensor(&[2, 4], rng));
+    adapter.lora_a.set_requires_grad(true);
+    adapter.lora_b.set_requires_grad(true);
+    adapter.magnitude.as_mut().unwrap().set_requires_grad(true);
+    let mut x_tracked = x.clone();
+    x_tracked.set_requires_grad(true);
+
+    let linear = LoraLinear::new(weight).with_adapter(adapter.clone());
+    linear.forward_for_backward(&x_tracked).mul(&grad_out).sum().backward();
+    let grad = linear.backward(&grad_out.data.to_owned());
+    let tape = [&x_tracked, &adapter.lora_a, &adapter.lora_b, adapter.magnitude.as_ref().unwrap()]
+        .map(|tensor| tensor.grad().unwrap().mapv(f64::from));
+    let actual = [&grad.input, &grad.lora_a, &grad.lora_b, grad.magnitude.as_ref().unwrap()];
+    for (actual, expected) in actual.into_iter().zip(&tape) {
+        assert_all_close(actual, expected, 1e-4);
+    }
+}
+
+#[test]
+fn test_pissa_and_loftq_init() {
+    use unsloth_rs::core::{manual_seed, with_rng, Init, QuantType, Weight};
+    use unsloth_rs::kernels::fast_lora::{loftq_init, pissa_init};
+
+    manual_seed(8);
+    let init = Init::Normal { std: 0.5 };
+    let (hidden, inter) = (8, 12);
+    let (gate_w, up_w, down_w, x) = with_rng(|rng| {
+        (
+            init.tensor(&[hidden, inter], rng),
+            init.tensor(&[hidden, inter], rng),
+            init.tensor(&[inter, hidden], rng),
+            init.tensor(&[3, hidden], rng),
+        )
+    });
+    let dense = Weight::from(gate_w.clone());
+    let plain_error = |qtype: QuantType| {
+        let q = QuantizedTensor::quantize(&gate_w, qtype).dequantize();
+        let norm = |t: &ArrayD<f32>| t.iter().map(|v| v * v).sum::<f32>().sqrt();
+        norm(&(&gate_w.data - &q.data)) / norm(&gate_w.data.to_owned())
+    };
+
+    // PiSSA moves the top singular directions into the adapter, exactly.
+    let pissa = pissa_init(&dense, 4, 2.0);
+    assert!(pissa.error < 1e-5);
+    assert_eq!((pissa.lora_a.data.shape(), pissa.lora_b.data.shape()), (&[hidden, 4][..], &[4, inter][..]));
+    let mlp = LoraMlp::new(
+        pissa.base.clone(),
+        up_w.clone(),
+        down_w.clone(),
+        pissa.lora_a.clone(),
+        pissa.lora_b.clone(),
+        Tensor::new(ArrayD::zeros(vec![hidden, 4])),
+        Tensor::new(ArrayD::zeros(vec![4, inter])),
+        Tensor::new(ArrayD::zeros(vec![inter, 4])),
+        Tensor::new(ArrayD::zeros(vec![4, hidden])),
+    )
+    .with_scaling(2.0);
+    let base = x.matmul(&gate_w).silu().mul(&x.matmul(&up_w)).matmul(&down_w);
+    assert!((&mlp.forward(&x).data - &base.data).iter().all(|d| d.abs() < 1e-4));
+
+    // Quantizing the residual instead of the weight loses less.
+    let quantized = Weight::<f32>::from(QuantizedTensor::quantize(&gate_w, QuantType::Nf4));
+    let qpissa = pissa_init(&quantized, 4, 2.0);
+    assert!(qpissa.base.is_quantized());
+    assert!(qpissa.error < plain_error(QuantType::Nf4));
+
+    // LoftQ fits the adapter to the quantization error; more rounds never hurt.
+    let once = loftq_init(&dense, 4, 2.0, QuantType::Nf4, 1);
+    let more = loftq_init(&dense, 4, 2.0, QuantType::Nf4, 5);
+    assert!(once.base.is_quantized() && more.base.is_quantized());
+    assert!(once.error < plain_error(QuantType::Nf4));
+    assert!(more.error <= once.error);
+    let int8 = loftq_init(&dense, 4, 2.0, QuantType::Int8, 2);
+    assert!(int8.error < plain_error(QuantType::Int8));
+}
+
+#[test]
+fn test_lora_linear_named_adapters() {
+    use unsloth_rs::core::{manual_seed, with_rng, Init, Weight};
+    use unsloth_rs::kernels::fast_lora::{LoraAdapter, LoraLinear};
+
+    manual_seed(10);
+    let init = Init::Normal { std: 0.5 };
+    let (weight, x, b_first, b_second) = with_rng(|rng| {
+        (
+            init.tensor(&[6, 4], rng),
+            init.tensor(&[4, 6], rng),
+            init.tensor(&[2, 4], rng),
+            init.tensor(&[3, 4], rng),
+        )
+    });
+    let mut first = LoraAdapter::new(6, 4, 2).with_lora_alpha(4.0, false);
+    first.lora_b = b_first;
+    let mut second = LoraAdapter::new(6, 4, 3).with_dora(&Weight::from(weight.clone()));
+    second.lora_b = b_second;
+    let alone = |adapter: &LoraAdapter| LoraLinear::new(weight.clone()).with_adapter(adapter.clone()).forward(&x);
+    let (base, y_first, y_second) = (x.matmul(&weight), alone(&first), alone(&second));
+    let close = |actual: &Tensor, expected: &ArrayD<f32>| {
+        assert_all_close(&actual.data.to_owned(), &expected.mapv(f64::from), 1e-5)
+    };
+
+    // Named adapters are inactive until selected; the default one is unset.
+    let mut linear = LoraLinear::new(weight.clone());
+    linear.insert_adapter("first", first.clone());
+    linear.insert_adapter("second", second.clone());
+    let names: Vec<&str> = linear.adapters().into_iter().map(|(name, _)| name).collect();
+    assert_eq!(names, ["first", "second"]);
+    assert_eq!(linear.forward(&x).data, base.data);
+    linear.set_active_adapters(&[("second", 1.0)]);
+    assert_eq!(linear.forward_for_backward(&x).data, y_second.data);
+    assert!(linear.backward(&base.data.to_owned()).magnitude.is_some());
+
+    // A weighted blend adds up each adapter's weighted change.
+    linear.set_active_adapters(&[("first", 0.5), ("second", 2.0), ("missing", 1.0)]);
+    let blend = &base.data + &((&y_first.data - &base.data) * 0.5) + (&y_second.data - &base.data) * 2.0;
+    close(&linear.forward(&x), &blend);
+    let err = linear.try_backward(&base.data.to_owned()).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "lora_linear_backward: only a single active adapter at weight 1 can be differentiated"
+    );
+
+    // Each row can take its own adapter, active or not.
+    linear.set_active_adapters(&[]);
+    let out = linear.forward_routed(&x, &[Some("first"), None, Some("second"), Some("missing")]);
+    let row = |y: &Tensor, i: usize| y.data.index_axis(ndarray::Axis(0), i).to_owned();
+    let rows = [row(&y_first, 0), row(&base, 1), row(&y_second, 2), row(&base, 3)];
+    let views: Vec<_> = rows.iter().map(|row| row.view()).collect();
+    close(&out, &ndarray::stack(ndarray::Axis(0), &views).unwrap());
+    let err = linear.try_forward_routed(&x, &[None]).unwrap_err();
+    assert_eq!(err.to_string(), "lora_linear_forward_routed: expected shape [4], got [1]");
+
+    // A blend merges like any single adapter.
+    linear.set_active_adapters(&[("first", 0.5), ("second", 2.0)]);
+    linear.merge(false);
+    close(&linear.forward(&x), &blend);
+    assert!(linear.try_forward_routed(&x, &[None; 4]).is_err());
+    linear.unmerge();
+    close(&linear.weight.to_dense(), &weight.data.to_owned());
+    assert_eq!(linear.remove_adapter("first").unwrap().rank(), 2);
+    assert!(linear.get_adapter("first").is_none());
+}
+
+#[test]
+fn test_lora_linear_forward_fused() {
+    use unsloth_rs::core::{manual_seed, with_rng, Init, QuantType};
+    use unsloth_rs::kernels::fast_lora::{LoraAdapter, LoraLinear};
+
+    manual_seed(11);
+    let init = Init::Normal { std: 0.5 };
+    let draw = |shape: &[usize]| with_rng(|rng| init.tensor(shape, rng));
+    let (mut x, grads) = (draw(&[5, 6]), [draw(&[5, 6]), draw(&[5, 4]), draw(&[5, 4])]);
+    x.set_requires_grad(true);
+    let adapter = |out: usize, rank: usize| {
+        let mut adapter = LoraAdapter::new(6, out, rank).with_lora_alpha(4.0, false);
+        adapter.lora_b = draw(&[rank, out]);
+        adapter.lora_a.set_requires_grad(true);
+        adapter.lora_b.set_requires_grad(true);
+        adapter
+    };
+    // Grouped-query shapes, a quantized K without an adapter and adapters of
+    // different ranks.
+    let q = LoraLinear::new(draw(&[6, 6])).with_adapter(adapter(6, 2));
+    let mut k = LoraLinear::new(draw(&[6, 4]));
+    k.weight.quantize(QuantType::Int8);
+    let mut v = LoraLinear::new(draw(&[6, 4])).with_adapter(adapter(4, 3).with_dropout(0.5));
+    v.eval();
+    let leaves: Vec<Tensor> = [&q, &v]
+        .iter()
+        .flat_map(|linear| {
+            let adapter = linear.adapter.as_ref().unwrap();
+            [adapter.lora_a.clone(), adapter.lora_b.clone()]
+        })
+        .chain([x.clone()])
+        .collect();
+    let run = |outputs: Vec<Tensor>| {
+        let loss = outputs.iter().zip(&grads).fold(Tensor::new(ndarray::arr0(0.0).into_dyn()), |loss, (out, g)| {
+            loss.add(&out.mul(g).sum())
+        });
+        loss.backward();
+        let grads: Vec<ArrayD<f32>> = leaves.iter().map(|leaf| leaf.grad().unwrap()).collect();
+        leaves.iter().for_each(Tensor::zero_grad);
+        (outputs, grads)
+    };
+
+    let (fused, fused_grads) = run(LoraLinear::forward_fused(&[&q, &k, &v], &x));
+    let (separate, separate_grads) = run([&q, &k, &v].iter().map(|linear| linear.forward(&x)).collect());
+    for (actual, expected) in fused.iter().map(|out| &out.data).zip(separate.iter().map(|out| &out.data)) {
+        assert_all_close(&actual.to_owned(), &expected.mapv(f64::from), 1e-5);
+    }
+    for (actual, expected) in fused_grads.iter().zip(&separate_grads) {
+        assert_all_close(actual, &expected.mapv(f64::from), 1e-4);
+    }
+    // Only `forward_for_backward` keeps the input for the projection's own
+    // backward; the tape path drops what an earlier call kept.
+    q.forward_for_backward(&x);
+    LoraLinear::forward_fused(&[&q, &k, &v], &x);
+    let err = q.try_backward(&grads[0].data.to_owned()).unwrap_err();
+    assert_eq!(err.to_string(), "lora_linear_backward: backward called before forward_for_backward");
+    q.forward_for_backward(&x);
+    let grad = q.backward(&grads[0].data.to_owned());
+    assert_all_close(&grad.lora_b, &separate_grads[1].mapv(f64::from), 1e-4);
+
+    // Dropout in training falls back to separate projections.
+    v.train();
+    manual_seed(12);
+    let fused = LoraLinear::forward_fused(&[&q, &k, &v], &x);
+    manual_seed(12);
+    let separate: Vec<Tensor> = [&q, &k, &v].iter().map(|linear| linear.forward(&x)).collect();
+    for (fused, separate) in fused.iter().zip(&separate) {
+        assert_eq!(fused.data, separate.data);
+    }
+}
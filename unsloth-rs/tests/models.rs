@@ -2,11 +2,16 @@ use unsloth_rs::models::llama::LlamaModel;
 
 use unsloth_rs::core::Tensor;
 use unsloth_rs::models::llama::LlamaAttention;
-use ndarray::{Array, IxDyn};
+use ndarray::{s, Array, Array2, ArrayD, ArrayView2, ArrayViewD, ArrayViewMut2, ArrayViewMut3, IxDyn};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use unsloth_rs::core::{Backend, CpuBackend, RopeLayout};
 
 #[test]
 fn test_create_llama_model() {
-    let _llama_model = unsloth_rs::models::llama::LlamaModel::new(8, 4, 128, 32000, 32);
+    let llama_model = LlamaModel::new(4, 2, 16, 128, 2);
+    let logits = llama_model.forward(&[1, 2, 3]);
+    assert_eq!(logits.data.shape(), &[3, 128]);
+    assert!(logits.data.iter().all(|x| x.is_finite()));
 }
 
 #[test]
@@ -18,13 +23,771 @@ fn test_llama_attention() {
     let hidden_dim = n_heads * head_dim;
 
     let mut attention = LlamaAttention::new(n_heads, n_kv_heads, head_dim);
-    attention.wq = Tensor::new(Array::zeros(IxDyn(&[hidden_dim, n_heads * head_dim])));
-    attention.wk = Tensor::new(Array::zeros(IxDyn(&[hidden_dim, n_kv_heads * head_dim])));
-    attention.wv = Tensor::new(Array::zeros(IxDyn(&[hidden_dim, n_kv_heads * head_dim])));
-    attention.wo = Tensor::new(Array::zeros(IxDyn(&[n_heads * head_dim, hidden_dim])));
+    attention.wq = Tensor::new(Array::zeros(IxDyn(&[hidden_dim, n_heads * head_dim]))).into();
+    attention.wk = Tensor::new(Array::zeros(IxDyn(&[hidden_dim, n_kv_heads * head_dim]))).into();
+    attention.wv = Tensor::new(Array::zeros(IxDyn(&[hidden_dim, n_kv_heads * head_dim]))).into();
+    attention.wo = Tensor::new(Array::zeros(IxDyn(&[n_heads * head_dim, hidden_dim]))).into();
 
     let input = Tensor::new(Array::zeros(IxDyn(&[seq_len, hidden_dim])));
     let output = attention.forward(&input);
 
     assert_eq!(output.data.shape(), &[seq_len, hidden_dim]);
 }
+
+#[test]
+fn test_llama_state_dict_round_trip_with_dtype() {
+    use unsloth_rs::core::{bf16, DType};
+    use unsloth_rs::save::Model;
+
+    let mut model = LlamaModel::new(2, 1, 4, 16, 2);
+    for (i, (_, weight)) in model.named_weights_mut().into_iter().enumerate() {
+        *weight = Tensor::new(Array::from_elem(IxDyn(weight.shape()), i as f32 * 0.5)).into();
+    }
+
+    let path = std::env::temp_dir().join(format!("unsloth_rs_llama_{}.safetensors", std::process::id()));
+    let path = path.to_str().unwrap();
+    model.state_dict().save(path).unwrap();
+    let loaded = Model::load(path).unwrap();
+    std::fs::remove_file(path).unwrap();
+    assert_eq!(loaded.dtype("layers.1.self_attn.wq"), Some(DType::F32));
+
+    let mut half_model = LlamaModel::<bf16>::new_with_dtype(2, 1, 4, 16, 2);
+    half_model.load_state_dict(&loaded).unwrap();
+    for ((name, original), (half_name, half)) in model.named_weights().into_iter().zip(half_model.named_weights()) {
+        assert_eq!(name, half_name);
+        let half = half.to_dense();
+        assert_eq!(half.dtype(), DType::BF16);
+        assert_eq!(half.to_f32().data, original.to_dense().data);
+    }
+    assert_eq!(half_model.state_dict().dtype("embedding"), Some(DType::BF16));
+}
+
+#[test]
+fn test_llama_quantized_state_dict_round_trip() {
+    use unsloth_rs::core::{DType, QuantType};
+    use unsloth_rs::save::Model;
+
+    let mut model = LlamaModel::new(2, 1, 4, 16, 1);
+    for (i, (_, weight)) in model.named_weights_mut().into_iter().enumerate() {
+        let shape = weight.shape().to_vec();
+        *weight = Tensor::new(Array::from_shape_fn(IxDyn(&shape), |idx| (idx[0] + i) as f32 * 0.25)).into();
+    }
+    model.quantize(QuantType::Nf4);
+    for (name, weight) in model.named_weights() {
+        assert_eq!(weight.is_quantized(), name.starts_with("layers.0.self_attn.") || name.starts_with("layers.0.w"));
+    }
+
+    let path = std::env::temp_dir().join(format!("unsloth_rs_qllama_{}.safetensors", std::process::id()));
+    let path = path.to_str().unwrap();
+    model.state_dict().save(path).unwrap();
+    let loaded = Model::load(path).unwrap();
+    std::fs::remove_file(path).unwrap();
+    assert_eq!(loaded.qtype("layers.0.w1"), Some(QuantType::Nf4));
+    assert_eq!(loaded.dtype("layers.0.w1"), None);
+    assert_eq!(loaded.dtype("embedding"), Some(DType::F32));
+
+    let mut reloaded = LlamaModel::new(2, 1, 4, 16, 1);
+    reloaded.load_state_dict(&loaded).unwrap();
+    for ((_, original), (_, weight)) in model.named_weights().into_iter().zip(reloaded.named_weights()) {
+        assert_eq!(weight.is_quantized(), original.is_quantized());
+        assert_eq!(weight.to_dense().data, original.to_dense().data);
+    }
+}
+
+#[test]
+fn test_llama_attention_uses_token_positions() {
+    let (n_heads, n_kv_heads, head_dim, seq_len) = (4, 2, 8, 5);
+    let hidden_dim = n_heads * head_dim;
+    let values = |shape: &[usize], seed: usize| {
+        let n: usize = shape.iter().product();
+        let data = (0..n).map(|i| ((i * 7919 + seed * 104_729) % 1000) as f32 / 1000.0 - 0.5).collect();
+        Tensor::new(Array::from_shape_vec(IxDyn(shape), data).unwrap())
+    };
+
+    let mut attention = LlamaAttention::new(n_heads, n_kv_heads, head_dim);
+    attention.wq = values(&[hidden_dim, n_heads * head_dim], 1).into();
+    attention.wk = values(&[hidden_dim, n_kv_heads * head_dim], 2).into();
+    attention.wv = values(&[hidden_dim, n_kv_heads * head_dim], 3).into();
+    attention.wo = values(&[n_heads * head_dim, hidden_dim], 4).into();
+    let x = values(&[seq_len, hidden_dim], 5);
+    let output = attention.forward(&x);
+
+    // Attention scores depend only on relative positions, so shifting every
+    // position id leaves the output unchanged.
+    let shifted = attention.forward_with_positions(&x, &[7, 8, 9, 10, 11]);
+    let diff = (&shifted.data - &output.data).mapv(f32::abs);
+    assert!(diff.iter().all(|&d| d < 1e-4));
+
+    // Without positions, attention would be equivariant to reordering the tokens.
+    let reversed = Tensor::new(x.data.slice(ndarray::s![..;-1, ..]).to_owned().into_dyn());
+    let output_reversed = attention.forward(&reversed);
+    let diff = (&output_reversed.data.slice(ndarray::s![..;-1, ..]) - &output.data).mapv(f32::abs);
+    assert!(diff.iter().any(|&d| d > 1e-3));
+}
+
+// Attention computed the slow way, one query, head and key at a time: query
+// `i` attends to keys `j <= i` that `keep[j]` allows, and to nothing if none is.
+fn naive_attention(attention: &LlamaAttention, x: &Tensor, positions: &[usize], keep: &[bool]) -> Tensor {
+    let (seq_len, n_heads, head_dim) = (x.data.shape()[0], attention.n_heads, attention.head_dim);
+    let n_rep = n_heads / attention.n_kv_heads;
+    let rotate = |w, heads| x.matmul(w).reshape(&[seq_len, heads, head_dim]).rope_at(positions, &attention.rope);
+    let q = rotate(&attention.wq.weight, n_heads);
+    let k = rotate(&attention.wk.weight, attention.n_kv_heads);
+    let v = x.matmul(&attention.wv.weight).reshape(&[seq_len, attention.n_kv_heads, head_dim]);
+    let mut out = ArrayD::zeros(IxDyn(&[seq_len, n_heads * head_dim]));
+    for h in 0..n_heads {
+        for i in 0..seq_len {
+            let keys: Vec<usize> = (0..=i).filter(|&j| keep[j]).collect();
+            let scores: Vec<f32> = keys
+                .iter()
+                .map(|&j| (0..head_dim).map(|d| q.data[[i, h, d]] * k.data[[j, h / n_rep, d]]).sum::<f32>())
+                .map(|score| score / (head_dim as f32).sqrt())
+                .collect();
+            let max = scores.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+            let total: f32 = scores.iter().map(|s| (s - max).exp()).sum();
+            for (&j, score) in keys.iter().zip(&scores) {
+                let p = (score - max).exp() / total;
+                for d in 0..head_dim {
+                    out[[i, h * head_dim + d]] += p * v.data[[j, h / n_rep, d]];
+                }
+            }
+        }
+    }
+    Tensor::new(out).matmul(&attention.wo.weight)
+}
+
+#[test]
+fn test_llama_attention_is_causal_and_masks_padding() {
+    use unsloth_rs::core::{manual_seed, with_rng, Init, SoftmaxMask};
+
+    let (n_heads, n_kv_heads, head_dim, seq_len) = (4, 2, 8, 5);
+    manual_seed(4);
+    let attention = LlamaAttention::from_config(
+        &unsloth_rs::models::llama::LlamaConfig::new(n_heads, n_kv_heads, head_dim, 0, 0).with_initializer_range(0.3),
+    );
+    let x = with_rng(|rng| Init::Normal { std: 1.0 }.tensor(&[seq_len, n_heads * head_dim], rng));
+    let positions: Vec<usize> = (0..seq_len).collect();
+    let close = |actual: &Tensor, expected: &Tensor| {
+        let diff = (&actual.data - &expected.data).mapv(f32::abs);
+        assert!(diff.iter().all(|&d| d < 1e-5), "{diff}");
+    };
+    close(&attention.forward(&x), &naive_attention(&attention, &x, &positions, &[true; 5]));
+
+    // Later tokens do not change the output of earlier ones.
+    let mut changed = x.data.to_owned();
+    changed.index_axis_mut(ndarray::Axis(0), 4).fill(3.0);
+    let prefix = ndarray::s![..4, ..];
+    let (before, after) = (attention.forward(&x), attention.forward(&Tensor::new(changed)));
+    assert_eq!(before.data.slice(prefix), after.data.slice(prefix));
+
+    // Left padding: the padded rows see no keys at all and come out as zeros.
+    let keep = [false, false, true, true, true];
+    let shifted = [0, 0, 0, 1, 2];
+    let padded = attention.forward_masked(&x, &shifted, &ndarray::arr1(&keep).into());
+    close(&padded, &naive_attention(&attention, &x, &shifted, &keep));
+    assert!(padded.data.slice(ndarray::s![..2, ..]).iter().all(|&v| v == 0.0));
+    // An additive mask with -inf at the padding does the same.
+    let bias = ndarray::arr1(&keep).mapv(|keep| if keep { 0.0 } else { f32::NEG_INFINITY });
+    close(&attention.forward_masked(&x, &shifted, &SoftmaxMask::from(bias)), &padded);
+}
+
+#[test]
+fn test_llama_model_attention_mask() {
+    use unsloth_rs::core::{SoftmaxMask, TensorError};
+
+    let model = small_model();
+    let rows = |logits: &Tensor, range: std::ops::Range<usize>| {
+        logits.data.slice(ndarray::s![range, ..]).to_owned().into_dyn()
+    };
+    let close = |actual: ndarray::ArrayD<f32>, expected: &Tensor| {
+        assert!((&actual - &expected.data).iter().all(|d| d.abs() < 1e-5));
+    };
+
+    // Padded tokens on either side leave the real ones as they were alone.
+    let alone = model.forward(&[3, 1, 4]);
+    let keep = |keep: [bool; 5]| SoftmaxMask::from(ndarray::arr1(&keep));
+    let right = model.forward_masked(&[3, 1, 4, 0, 0], &[0, 1, 2, 3, 4], &keep([true, true, true, false, false]));
+    close(rows(&right, 0..3), &alone);
+    let left = model.forward_masked(&[0, 0, 3, 1, 4], &[0, 0, 0, 1, 2], &keep([false, false, true, true, true]));
+    close(rows(&left, 2..5), &alone);
+
+    // A block-diagonal mask keeps packed sequences apart.
+    let document = [0, 0, 0, 1, 1];
+    let packed: SoftmaxMask = Array2::from_shape_fn((5, 5), |(i, j)| document[i] == document[j]).into();
+    let logits = model.forward_masked(&[3, 1, 4, 1, 5], &[0, 1, 2, 0, 1], &packed);
+    close(rows(&logits, 0..3), &alone);
+    close(rows(&logits, 3..5), &model.forward(&[1, 5]));
+
+    let err = model.try_forward_masked(&[3, 1, 4], &[0, 1, 2], &ndarray::arr1(&[true; 4]).into()).unwrap_err();
+    assert_eq!(err, TensorError::IncompatibleShapes { op: "combine", lhs: vec![3, 3], rhs: vec![4] });
+    let err = model.try_forward_masked(&[3, 1, 4], &[0, 1], &keep([true; 5])).unwrap_err();
+    assert_eq!(err.to_string(), "forward_masked: expected shape [3], got [2]");
+}
+
+#[test]
+#[should_panic(expected = "n_heads must be a multiple of n_kv_heads, got 3 and 2")]
+fn test_llama_attention_rejects_ungrouped_kv_heads() {
+    LlamaAttention::new(3, 2, 4);
+}
+
+// A 2-layer model with real weight shapes: hidden = 2 heads * 4 = 8, one K/V
+// head, an MLP width of 12 and 16 tokens.
+fn small_model() -> LlamaModel {
+    small_model_with_dropout(0.0)
+}
+
+fn small_model_with_dropout(attention_dropout: f32) -> LlamaModel {
+    use unsloth_rs::models::llama::LlamaConfig;
+
+    let config = LlamaConfig::new(2, 1, 4, 16, 2)
+        .with_intermediate_size(12)
+        .with_attention_dropout(attention_dropout);
+    let mut model = LlamaModel::from_config(&config);
+    for (i, (_, weight)) in model.named_weights_mut().into_iter().enumerate() {
+        let shape = weight.shape().to_vec();
+        let n: usize = shape.iter().product();
+        let data = (0..n).map(|j| ((i * 31 + j * 17) % 23) as f32 / 23.0 - 0.5).collect();
+        *weight = Tensor::new(Array::from_shape_vec(IxDyn(&shape), data).unwrap()).into();
+    }
+    model
+}
+
+#[test]
+fn test_llama_model_rope_scaling() {
+    use unsloth_rs::core::{RopeConfig, RopeScaling};
+
+    let mut model = small_model();
+    let tokens = [3, 1, 4, 1, 5];
+    let plain = model.forward(&tokens);
+
+    // Linear scaling by 2 is the same as doubling the context: positions
+    // 0, 2, 4, .. then land where 0, 1, 2, .. were.
+    model.set_rope(RopeConfig::new(4, 10000.0).with_scaling(RopeScaling::Linear { factor: 2.0 }));
+    assert_ne!(model.forward(&tokens).data, plain.data);
+    let stretched = model.forward_with_positions(&tokens, &[0, 2, 4, 6, 8]);
+    let diff = (&stretched.data - &plain.data).mapv(f32::abs);
+    assert!(diff.iter().all(|&d| d < 1e-4));
+}
+
+#[test]
+fn test_llama_model_loss_matches_logits() {
+    use unsloth_rs::kernels::cross_entropy::{FusedCrossEntropy, IGNORE_INDEX};
+
+    let model = small_model();
+    let tokens = [3, 1, 4, 1, 5];
+    let labels = [1, 4, IGNORE_INDEX, 5, 9];
+    let loss = model.loss(&tokens, &labels, &FusedCrossEntropy::new().with_chunk_size(5));
+
+    let logits = model.forward(&tokens);
+    let mut expected = 0.0;
+    for (row, &label) in logits.data.outer_iter().zip(&labels) {
+        if label != IGNORE_INDEX {
+            let lse = row.mapv(f32::exp).sum().ln();
+            expected += (lse - row[label]) / 4.0;
+        }
+    }
+    assert!((loss.data[[]] - expected).abs() < 1e-5);
+}
+
+// Delegates to the reference backend, counting the GEMMs it is asked for.
+#[derive(Debug, Default)]
+struct CountingBackend {
+    gemms: AtomicUsize,
+}
+
+impl Backend for CountingBackend {
+    fn name(&self) -> &'static str {
+        "counting"
+    }
+    fn gemm_into(&self, a: &ArrayView2<f32>, b: &ArrayView2<f32>, c: ArrayViewMut2<f32>) {
+        self.gemms.fetch_add(1, Ordering::Relaxed);
+        CpuBackend.gemm_into(a, b, c)
+    }
+    fn batched_gemm_into(&self, pairs: &[(ArrayView2<f32>, ArrayView2<f32>)], out: ArrayViewMut3<f32>) {
+        self.gemms.fetch_add(pairs.len(), Ordering::Relaxed);
+        CpuBackend.batched_gemm_into(pairs, out)
+    }
+    fn add(&self, a: &ArrayViewD<f32>, b: &ArrayViewD<f32>) -> ArrayD<f32> {
+        CpuBackend.add(a, b)
+    }
+    fn mul(&self, a: &ArrayViewD<f32>, b: &ArrayViewD<f32>) -> ArrayD<f32> {
+        CpuBackend.mul(a, b)
+    }
+    fn scale(&self, x: &ArrayViewD<f32>, factor: f32) -> ArrayD<f32> {
+        CpuBackend.scale(x, factor)
+    }
+    fn silu(&self, x: &ArrayViewD<f32>) -> ArrayD<f32> {
+        CpuBackend.silu(x)
+    }
+    fn silu_backward(&self, x: &ArrayViewD<f32>, grad: &ArrayViewD<f32>) -> ArrayD<f32> {
+        CpuBackend.silu_backward(x, grad)
+    }
+    fn sum(&self, x: &ArrayViewD<f32>) -> f32 {
+        CpuBackend.sum(x)
+    }
+    fn rms_normalize(&self, x: &ArrayViewD<f32>, epsilon: f32) -> (ArrayD<f32>, ArrayD<f32>) {
+        CpuBackend.rms_normalize(x, epsilon)
+    }
+    fn logsumexp(&self, x: &ArrayViewD<f32>, axis: usize) -> ArrayD<f32> {
+        CpuBackend.logsumexp(x, axis)
+    }
+    fn rope(&self, x: &mut ArrayD<f32>, cos: &Array2<f32>, sin: &Array2<f32>, layout: RopeLayout) {
+        CpuBackend.rope(x, cos, sin, layout)
+    }
+    fn softmax(&self, x: &mut ArrayD<f32>, axis: usize) {
+        CpuBackend.softmax(x, axis)
+    }
+    fn log_softmax(&self, x: &mut ArrayD<f32>, axis: usize) {
+        CpuBackend.log_softmax(x, axis)
+    }
+    fn softmax_backward(&self, grad: &ArrayD<f32>, y: &ArrayD<f32>, axis: usize) -> ArrayD<f32> {
+        CpuBackend.softmax_backward(grad, y, axis)
+    }
+    fn log_softmax_backward(&self, grad: &ArrayD<f32>, y: &ArrayD<f32>, axis: usize) -> ArrayD<f32> {
+        CpuBackend.log_softmax_backward(grad, y, axis)
+    }
+}
+
+#[test]
+fn test_llama_model_runs_on_any_backend() {
+    use std::sync::Arc;
+    use unsloth_rs::core::with_backend;
+
+    let model = small_model();
+    let tokens = [3, 1, 4, 1, 5];
+    let expected = model.forward(&tokens);
+
+    let backend = Arc::new(CountingBackend::default());
+    let logits = with_backend(backend.clone(), || model.forward(&tokens));
+    let max_diff = (&logits.data - &expected.data).mapv(f32::abs).fold(0.0f32, |m, &d| m.max(d));
+    assert!(max_diff < 1e-5);
+    assert!(backend.gemms.load(Ordering::Relaxed) > 0);
+}
+
+#[test]
+fn test_llama_gradient_checkpointing_matches_full_tape() {
+    use unsloth_rs::core::{with_scratch_dir, CheckpointMode, Weight};
+    use unsloth_rs::kernels::cross_entropy::FusedCrossEntropy;
+
+    // Offloaded activations go to a directory of this test's own.
+    let dir = std::env::temp_dir().join(format!("unsloth-rs-test-offload-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let scratch_files = || std::fs::read_dir(&dir).unwrap().count();
+    let tokens = [3, 1, 4, 1, 5];
+    let labels = [1, 4, 1, 5, 9];
+    let run = |mode: CheckpointMode| {
+        let mut model = small_model();
+        model.set_checkpointing(mode);
+        for (_, weight) in model.named_weights_mut() {
+            if let Weight::Dense(tensor) = weight {
+                tensor.set_requires_grad(true);
+            }
+        }
+        let loss = with_scratch_dir(&dir, || model.loss(&tokens, &labels, &FusedCrossEntropy::new()));
+        if mode == CheckpointMode::Offload {
+            // One saved input per decoder layer.
+            assert_eq!(scratch_files(), 2);
+        }
+        loss.backward();
+        let grads: Vec<_> = model
+            .named_weights()
+            .into_iter()
+            .map(|(name, weight)| (name, weight.as_dense().unwrap().grad()))
+            .collect();
+        (loss.data[[]], grads)
+    };
+
+    let (expected_loss, expected_grads) = run(CheckpointMode::None);
+    for mode in [CheckpointMode::Full, CheckpointMode::Offload] {
+        let (loss, grads) = run(mode);
+        assert!((loss - expected_loss).abs() < 1e-6);
+        for ((name, grad), (_, expected)) in grads.iter().zip(&expected_grads) {
+            match (grad, expected) {
+                (Some(grad), Some(expected)) => {
+                    let max_diff = (grad - expected).mapv(f32::abs).fold(0.0f32, |m, &d| m.max(d));
+                    assert!(max_diff < 1e-5, "{mode}: gradient of {name} differs by {max_diff}");
+                }
+                (None, None) => {}
+                _ => panic!("{mode}: gradient of {name} is missing"),
+            }
+        }
+    }
+    // Dropping the tape removes the scratch files.
+    assert_eq!(scratch_files(), 0);
+    std::fs::remove_dir(&dir).unwrap();
+}
+
+#[test]
+fn test_llama_from_config_trains_from_scratch() {
+    use unsloth_rs::core::{manual_seed, Weight};
+    use unsloth_rs::kernels::cross_entropy::FusedCrossEntropy;
+    use unsloth_rs::models::llama::LlamaConfig;
+
+    let config = LlamaConfig::new(2, 1, 8, 32, 2).with_intermediate_size(32);
+    manual_seed(0);
+    let mut model = LlamaModel::from_config(&config);
+    manual_seed(0);
+    let same = LlamaModel::from_config(&config);
+    for ((name, weight), (_, other)) in model.named_weights().into_iter().zip(same.named_weights()) {
+        assert_eq!(weight.to_dense().data, other.to_dense().data, "{name}");
+        let expected: &[usize] = match name.as_str() {
+            "embedding" => &[32, 16],
+            "output" => &[16, 32],
+            "layers.0.w1" => &[16, 32],
+            "layers.0.self_attn.wk" => &[16, 8],
+            _ => continue,
+        };
+        assert_eq!(weight.shape(), expected);
+    }
+
+    // Plain SGD on a fixed next-token task. The embedding lookup is not on
+    // the tape, so it stays at its initial values.
+    let tokens = [1, 5, 9, 13, 17, 21];
+    let labels = [5, 9, 13, 17, 21, 25];
+    let mut losses = Vec::new();
+    for _ in 0..30 {
+        for (_, weight) in model.named_weights_mut() {
+            if let Weight::Dense(tensor) = weight {
+                tensor.set_requires_grad(true);
+            }
+        }
+        let loss = model.loss(&tokens, &labels, &FusedCrossEntropy::new());
+        loss.backward();
+        losses.push(loss.data[[]]);
+        for (_, weight) in model.named_weights_mut() {
+            if let Weight::Dense(tensor) = weight {
+                if let Some(grad) = tensor.grad() {
+                    *tensor = Tensor::new(&tensor.data - &(grad * 0.1));
+                }
+            }
+        }
+    }
+    assert!((losses[0] - 32f32.ln()).abs() < 0.1);
+    assert!(losses[29] < 0.5, "{losses:?}");
+}
+
+#[test]
+fn test_llama_attention_dropout_follows_train_and_eval() {
+    use unsloth_rs::core::{manual_seed, CheckpointMode, Weight};
+    use unsloth_rs::kernels::cross_entropy::FusedCrossEntropy;
+
+    let tokens = [3, 1, 4, 1, 5];
+    let labels = [1, 4, 1, 5, 9];
+    let plain = small_model().forward(&tokens);
+    let mut model = small_model_with_dropout(0.5);
+    assert!(model.is_training());
+    assert_ne!(model.forward(&tokens).data, plain.data);
+
+    // Dropout is the identity in eval mode.
+    model.eval();
+    assert!(!model.is_training());
+    assert_eq!(model.forward(&tokens).data, plain.data);
+    model.train();
+    assert_ne!(model.forward(&tokens).data, plain.data);
+
+    // A checkpointed layer replays the masks of its forward pass when it is
+    // recomputed, so the gradients match the full tape.
+    let grads = |mode: CheckpointMode| {
+        let mut model = small_model_with_dropout(0.5);
+        model.set_checkpointing(mode);
+        for (_, weight) in model.named_weights_mut() {
+            if let Weight::Dense(tensor) = weight {
+                tensor.set_requires_grad(true);
+            }
+        }
+        manual_seed(11);
+        model.loss(&tokens, &labels, &FusedCrossEntropy::new()).backward();
+        model
+            .named_weights()
+            .into_iter()
+            .filter_map(|(_, weight)| weight.as_dense().unwrap().grad())
+            .collect::<Vec<_>>()
+    };
+    let expected = grads(CheckpointMode::None);
+    let actual = grads(CheckpointMode::Full);
+    assert_eq!(actual.len(), expected.len());
+    for (grad, expected) in actual.iter().zip(&expected) {
+        assert!((grad - expected).mapv(f32::abs).iter().all(|&d| d < 1e-5));
+    }
+}
+
+#[test]
+fn test_get_peft_model_wraps_target_modules() {
+    use unsloth_rs::core::{manual_seed, CheckpointMode};
+    use unsloth_rs::kernels::cross_entropy::FusedCrossEntropy;
+    use unsloth_rs::peft::{get_peft_model, LoraConfig};
+
+    let tokens = [3, 1, 4, 1, 5];
+    let labels = [1, 4, 1, 5, 9];
+    let base = small_model().forward(&tokens);
+    let config = LoraConfig::new(2, 4.0)
+        .with_target_modules(&["q_proj", "v_proj", "down_proj"])
+        .with_rank_for("down_proj", 4)
+        .with_alpha_for("down_proj", 2.0)
+        .with_dropout_for("v_proj", 0.1)
+        .with_rslora(true);
+    manual_seed(0);
+    let model = get_peft_model(small_model(), &config).unwrap();
+    let adapters = model.named_adapters();
+    let names: Vec<&str> = adapters.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(
+        names,
+        [
+            "layers.0.q_proj",
+            "layers.0.v_proj",
+            "layers.0.down_proj",
+            "layers.1.q_proj",
+            "layers.1.v_proj",
+            "layers.1.down_proj"
+        ]
+    );
+    let (_, v_proj) = &adapters[1];
+    assert_eq!((v_proj.rank(), v_proj.scaling, v_proj.dropout), (2, 4.0 / 2f32.sqrt(), 0.1));
+    assert_eq!(v_proj.lora_a.data.shape(), [8, 2]);
+    assert_eq!(v_proj.lora_b.data.shape(), [2, 4]);
+    let (_, down_proj) = &adapters[2];
+    assert_eq!((down_proj.rank(), down_proj.scaling, down_proj.dropout), (4, 1.0, 0.0));
+    assert_eq!(down_proj.lora_a.data.shape(), [12, 4]);
+    // The weights and their names are untouched, and B starts at zero.
+    assert_eq!(model.named_weights().len(), small_model().named_weights().len());
+    assert_eq!(model.forward(&tokens).data, base.data);
+
+    // Only the adapters receive gradients, with or without checkpointing.
+    let grads = |mode: CheckpointMode| {
+        manual_seed(0);
+        let mut model = get_peft_model(small_model(), &config.clone().with_dropout_for("v_proj", 0.0)).unwrap();
+        model.set_checkpointing(mode);
+        model.loss(&tokens, &labels, &FusedCrossEntropy::new()).backward();
+        assert!(model.named_weights().iter().all(|(_, weight)| weight.as_dense().unwrap().grad().is_none()));
+        model
+            .named_adapters()
+            .into_iter()
+            .flat_map(|(_, adapter)| [adapter.lora_a.grad().unwrap(), adapter.lora_b.grad().unwrap()])
+            .collect::<Vec<_>>()
+    };
+    let expected = grads(CheckpointMode::None);
+    assert!(expected.iter().skip(1).step_by(2).all(|grad_b| grad_b.iter().any(|&g| g != 0.0)));
+    for (grad, expected) in grads(CheckpointMode::Full).iter().zip(&expected) {
+        assert!((grad - expected).mapv(f32::abs).iter().all(|&d| d < 1e-5));
+    }
+
+    let err = get_peft_model(small_model(), &LoraConfig::default().with_target_modules(&["qkv_proj"]))
+        .err()
+        .unwrap();
+    assert!(err.to_string().starts_with("add_lora: unknown target module \"qkv_proj\""));
+}
+
+#[test]
+fn test_merge_adapters_keeps_the_forward() {
+    use unsloth_rs::core::{bf16, manual_seed, with_rng, Init, QuantType};
+    use unsloth_rs::peft::{get_peft_model, LoraConfig};
+
+    let tokens = [3, 1, 4, 1, 5];
+    manual_seed(0);
+    let mut model = get_peft_model(small_model(), &LoraConfig::new(2, 4.0)).unwrap();
+    model.eval();
+    // B starts at zero; give the adapters something to merge.
+    for (_, adapter) in model.named_adapters_mut() {
+        let shape = adapter.lora_b.data.shape().to_vec();
+        adapter.lora_b = with_rng(|rng| Init::Normal { std: 0.5 }.tensor(&shape, rng));
+    }
+    let before = model.forward(&tokens);
+    assert_ne!(before.data, small_model().forward(&tokens).data);
+    let close = |actual: &Tensor, tol: f32| (&actual.data - &before.data).iter().all(|d| d.abs() < tol);
+
+    model.merge_adapters(false);
+    assert!(close(&model.forward(&tokens), 1e-4));
+    // The merged weights export as a plain model, here in bf16.
+    let mut exported = LlamaModel::<bf16>::new_with_dtype(2, 1, 4, 16, 2);
+    exported.load_state_dict(&model.state_dict()).unwrap();
+    assert!(close(&exported.forward(&tokens), 0.1));
+
+    model.unmerge_adapters();
+    assert!(close(&model.forward(&tokens), 1e-4));
+
+    // A QLoRA model merges into dequantized weights.
+    model.quantize(QuantType::Int8);
+    let before = model.forward(&tokens);
+    model.merge_adapters(false);
+    assert!(model.named_weights().iter().all(|(_, weight)| !weight.is_quantized()));
+    assert!((&model.forward(&tokens).data - &before.data).iter().all(|d| d.abs() < 1e-4));
+    model.unmerge_adapters();
+    assert_eq!(model.forward(&tokens).data, before.data);
+}
+
+#[test]
+fn test_get_peft_model_with_dora() {
+    use unsloth_rs::core::{manual_seed, with_rng, CheckpointMode, Init};
+    use unsloth_rs::kernels::cross_entropy::FusedCrossEntropy;
+    use unsloth_rs::peft::{get_peft_model, LoraConfig};
+
+    let tokens = [3, 1, 4, 1, 5];
+    let labels = [1, 4, 1, 5, 9];
+    let config = LoraConfig::new(2, 4.0).with_target_modules(&["q_proj", "up_proj"]).with_dora(true);
+    manual_seed(0);
+    let mut model = get_peft_model(small_model(), &config).unwrap();
+    let base = small_model().forward(&tokens);
+    assert!((&model.forward(&tokens).data - &base.data).iter().all(|d| d.abs() < 1e-5));
+
+    // The magnitudes are trained with the adapters, checkpointed or not.
+    model.set_checkpointing(CheckpointMode::Full);
+    model.loss(&tokens, &labels, &FusedCrossEntropy::new()).backward();
+    let adapters = model.named_adapters();
+    assert_eq!(adapters.len(), 4);
+    assert!(adapters.iter().all(|(_, adapter)| adapter.magnitude.as_ref().unwrap().grad().is_some()));
+
+    for (_, adapter) in model.named_adapters_mut() {
+        let shape = adapter.lora_b.data.shape().to_vec();
+        adapter.lora_b = with_rng(|rng| Init::Normal { std: 0.5 }.tensor(&shape, rng));
+    }
+    model.eval();
+    let trained = model.forward(&tokens);
+    let state = model.adapter_state_dict();
+    assert_eq!(state.metadata("layers.1.q_proj.scaling"), Some("2"));
+    assert!(state.get::<f32>("layers.0.up_proj.magnitude").is_some());
+
+    manual_seed(1);
+    let mut restored = get_peft_model(small_model(), &config).unwrap();
+    restored.eval();
+    restored.load_adapter_state_dict(&state).unwrap();
+    assert_eq!(restored.forward(&tokens).data, trained.data);
+    restored.merge_adapters(false);
+    assert!((&restored.forward(&tokens).data - &trained.data).iter().all(|d| d.abs() < 1e-4));
+}
+
+#[test]
+fn test_named_adapters_hot_swap() {
+    use unsloth_rs::core::{manual_seed, with_rng, Init};
+    use unsloth_rs::peft::{get_peft_model, InitLoraWeights, LoraConfig};
+
+    let tokens = [3, 1, 4, 1, 5];
+    let base = small_model().forward(&tokens);
+    // Two adapters fine-tuned separately on the same base model.
+    manual_seed(0);
+    let tuned = |config: &LoraConfig| {
+        let mut model = get_peft_model(small_model(), config).unwrap();
+        for (_, adapter) in model.named_adapters_mut() {
+            let shape = adapter.lora_b.data.shape().to_vec();
+            adapter.lora_b = with_rng(|rng| Init::Normal { std: 0.5 }.tensor(&shape, rng));
+        }
+        model
+    };
+    let first = tuned(&LoraConfig::new(2, 4.0));
+    let second = tuned(&LoraConfig::new(4, 4.0).with_target_modules(&["v_proj", "up_proj"]).with_dora(true));
+    let (y_first, y_second) = (first.forward(&tokens), second.forward(&tokens));
+
+    let mut model = small_model();
+    model.load_adapter("first", &first.adapter_state_dict()).unwrap();
+    model.load_adapter("second", &second.adapter_state_dict()).unwrap();
+    assert!(model.load_adapter("third", &small_model().state_dict()).is_err());
+    assert_eq!(model.adapter_names(), ["first", "second"]);
+    assert_eq!(model.forward(&tokens).data, base.data);
+
+    let close = |actual: &Tensor, expected: &Tensor| (&actual.data - &expected.data).iter().all(|d| d.abs() < 1e-5);
+    model.set_adapter("first").unwrap();
+    assert!(close(&model.forward(&tokens), &y_first));
+    model.set_adapter("second").unwrap();
+    assert!(close(&model.forward(&tokens), &y_second));
+    model.set_weighted_adapters(&[("first", 0.5), ("second", 0.5)]).unwrap();
+    let blend = model.forward(&tokens);
+    assert!(!close(&blend, &y_first) && !close(&blend, &y_second));
+    let err = model.set_adapter("third").unwrap_err();
+    assert_eq!(err.to_string(), "set_adapter: unknown adapter \"third\" (expected one of [\"first\", \"second\"])");
+    model.disable_adapters();
+    assert_eq!(model.forward(&tokens).data, base.data);
+
+    // Routing ignores the active set and picks the adapter per token.
+    model.set_adapter("first").unwrap();
+    assert!(close(&model.forward_routed(&tokens, &[5], &[Some("second"); 5]), &y_second));
+    assert!(close(&model.forward_routed(&tokens, &[5], &[None; 5]), &base));
+    assert!(model.try_forward_routed(&tokens, &[5], &[None; 4]).is_err());
+    let err = model.try_forward_routed(&tokens, &[2, 2], &[None; 5]).unwrap_err();
+    assert_eq!(err.to_string(), "forward_routed: expected shape [5], got [4]");
+    assert!(model.try_forward_routed(&tokens, &[5], &[Some("third"); 5]).is_err());
+
+    // A batch of requests for different adapters gives what each request
+    // gives on its own.
+    let requests: [(&[usize], Option<&str>); 3] =
+        [(&[2, 7, 1], Some("second")), (&[3, 1], None), (&[4, 4], Some("first"))];
+    let batch: Vec<usize> = requests.iter().flat_map(|(tokens, _)| tokens.iter().copied()).collect();
+    let lens: Vec<usize> = requests.iter().map(|(tokens, _)| tokens.len()).collect();
+    let routes: Vec<Option<&str>> =
+        requests.iter().flat_map(|&(tokens, route)| std::iter::repeat_n(route, tokens.len())).collect();
+    let out = model.forward_routed(&batch, &lens, &routes);
+    let mut start = 0;
+    for (tokens, route) in requests {
+        let alone = model.forward_routed(tokens, &[tokens.len()], &vec![route; tokens.len()]);
+        let rows = out.data.slice(s![start..start + tokens.len(), ..]);
+        assert!((&rows - &alone.data).iter().all(|d| d.abs() < 1e-5));
+        start += tokens.len();
+    }
+
+    // Only a single adapter can be fitted from the base weights.
+    let pissa = LoraConfig::new(2, 4.0).with_init_lora_weights(InitLoraWeights::Pissa);
+    assert!(model.add_adapter("third", &pissa).is_err());
+    model.remove_adapter("first");
+    assert_eq!(model.adapter_names(), ["second"]);
+}
+
+#[test]
+fn test_get_peft_model_with_pissa_and_loftq() {
+    use unsloth_rs::core::{manual_seed, QuantType};
+    use unsloth_rs::peft::{get_peft_model, InitLoraWeights, LoraConfig};
+
+    let tokens = [3, 1, 4, 1, 5];
+    let base = small_model().forward(&tokens);
+    let config = LoraConfig::new(2, 4.0).with_target_modules(&["q_proj", "down_proj"]);
+    manual_seed(0);
+
+    // PiSSA only moves part of each targeted weight into its adapter.
+    let model = get_peft_model(small_model(), &config.clone().with_init_lora_weights(InitLoraWeights::Pissa)).unwrap();
+    assert!((&model.forward(&tokens).data - &base.data).iter().all(|d| d.abs() < 1e-4));
+    assert!(model.named_adapters().iter().all(|(_, adapter)| adapter.lora_b.data.iter().any(|&b| b != 0.0)));
+
+    // LoftQ quantizes the targeted weights only.
+    let loftq = InitLoraWeights::Loftq {
+        qtype: QuantType::Int8,
+        iterations: 2,
+    };
+    let model = get_peft_model(small_model(), &config.with_init_lora_weights(loftq)).unwrap();
+    let quantized: Vec<String> = model
+        .named_weights()
+        .into_iter()
+        .filter(|(_, weight)| weight.is_quantized())
+        .map(|(name, _)| name)
+        .collect();
+    assert_eq!(quantized, ["layers.0.self_attn.wq", "layers.0.w2", "layers.1.self_attn.wq", "layers.1.w2"]);
+    assert!((&model.forward(&tokens).data - &base.data).iter().all(|d| d.abs() < 0.05));
+}
+
+#[test]
+fn test_kv_cache_forward_step_matches_full_forward() {
+    use unsloth_rs::core::TensorError;
+    use unsloth_rs::models::llama::KvCache;
+
+    let mut model = small_model();
+    model.eval();
+    let tokens = [3, 1, 4, 1, 5, 9, 2];
+    let full = model.forward(&tokens);
+    let close = |step: &Tensor, start: usize| {
+        let expected = full.data.slice(s![start..start + step.data.shape()[0], ..]);
+        assert!((&step.data - &expected).iter().all(|d| d.abs() < 1e-5), "step at {start}");
+    };
+
+    // The prompt in one step, then one token at a time at the next position.
+    let mut cache = model.new_kv_cache();
+    assert!(cache.iter().all(KvCache::is_empty));
+    let prompt = model.forward_step(&tokens[..3], &mut cache);
+    assert!(!prompt.requires_grad());
+    close(&prompt, 0);
+    for i in 3..tokens.len() {
+        close(&model.forward_step(&tokens[i..=i], &mut cache), i);
+    }
+    assert!(cache.iter().all(|layer| layer.len() == tokens.len()));
+
+    let err = model.try_forward_step(&[1], &mut cache[..1]).unwrap_err();
+    assert_eq!(err, TensorError::ShapeMismatch { op: "forward_step", expected: vec![2], actual: vec![1] });
+    cache[0].clear();
+    let err = model.try_forward_step(&[1], &mut cache).unwrap_err();
+    assert_eq!(err.to_string(), "forward_step: the layer caches hold different numbers of positions: [0, 7]");
+}
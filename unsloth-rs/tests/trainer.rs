@@ -3,7 +3,22 @@ use unsloth_rs::trainer::Trainer;
 
 #[test]
 fn test_create_trainer() {
-    let llama_model = LlamaModel::new(8, 4, 128, 32000, 32);
+    let llama_model = LlamaModel::new(4, 2, 16, 128, 2);
     let trainer = Trainer::new(llama_model);
     trainer.train();
 }
+
+#[test]
+fn test_trainer_config_sets_gradient_checkpointing() {
+    use unsloth_rs::core::CheckpointMode;
+    use unsloth_rs::trainer::TrainerConfig;
+
+    let trainer = Trainer::new(LlamaModel::new(2, 1, 4, 16, 2));
+    assert_eq!(trainer.model().checkpointing(), CheckpointMode::None);
+
+    let config = TrainerConfig::new().with_gradient_checkpointing("offload".parse().unwrap());
+    let trainer = Trainer::with_config(LlamaModel::new(2, 1, 4, 16, 2), config);
+    assert_eq!(trainer.config().gradient_checkpointing, CheckpointMode::Offload);
+    assert_eq!(trainer.model().checkpointing(), CheckpointMode::Offload);
+    assert!("everything".parse::<CheckpointMode>().is_err());
+}